@@ -0,0 +1,84 @@
+// How long it takes to draw a 15-row sprite: in the emulator itself, and with the two ways of
+// doing it in isolation, a pixel at a time over a byte per pixel (as the emulator used to) and a
+// row at a time over bit-packed rows (as it does now).
+//
+// Run with `cargo bench --bench sprites`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 32;
+
+// the "0" glyph and then some, 15 rows of it
+const SPRITE: [u8; 15] = [
+    0xf0, 0x90, 0x90, 0x90, 0xf0, 0x3c, 0x42, 0x81, 0x81, 0x42, 0x3c, 0xff, 0x00, 0xff, 0xaa,
+];
+
+fn per_pixel(pixels: &mut [u8; WIDTH * HEIGHT], sprite: &[u8], x_start: usize, y_start: usize)
+    -> bool
+{
+    let mut collision = false;
+    for (i, row) in sprite.iter().enumerate() {
+        let y = y_start + i;
+        if y >= HEIGHT {
+            continue;
+        }
+        for j in 0..8 {
+            let x = x_start + j;
+            if x >= WIDTH || row & (0x80 >> j) == 0 {
+                continue;
+            }
+            let pixel = &mut pixels[y * WIDTH + x];
+            collision |= *pixel != 0;
+            *pixel ^= 1;
+        }
+    }
+    collision
+}
+
+fn bit_packed(rows: &mut [u64; HEIGHT], sprite: &[u8], x_start: usize, y_start: usize) -> bool {
+    let mut collision = false;
+    for (i, row) in sprite.iter().enumerate() {
+        let y = y_start + i;
+        if y >= HEIGHT {
+            continue;
+        }
+        // bit 63 is the leftmost pixel
+        let mask = ((*row as u64) << 56) >> x_start;
+        collision |= rows[y] & mask != 0;
+        rows[y] ^= mask;
+    }
+    collision
+}
+
+fn draw_sprite(c: &mut Criterion) {
+    let mut group = c.benchmark_group("draw 15-row sprite");
+
+    let mut pixels = [0; WIDTH * HEIGHT];
+    group.bench_function("per pixel", |b| {
+        b.iter(|| per_pixel(&mut pixels, black_box(&SPRITE), black_box(10), black_box(5)))
+    });
+
+    let mut rows = [0; HEIGHT];
+    group.bench_function("bit-packed", |b| {
+        b.iter(|| bit_packed(&mut rows, black_box(&SPRITE), black_box(10), black_box(5)))
+    });
+
+    // LD I, 0x206; DRW V0, V1, 15; JP 0x202; then the sprite
+    let mut rom = vec![0xa2, 0x06, 0xd0, 0x1f, 0x12, 0x02];
+    rom.extend_from_slice(&SPRITE);
+    let mut chip8 = chip8::Chip8Builder::new().rom(rom).build().unwrap();
+    chip8.step(0, 0).unwrap();
+    group.bench_function("emulator", |b| {
+        b.iter(|| {
+            // the DRW, and the jump back to it
+            chip8.step(0, 0).unwrap();
+            chip8.step(0, 0).unwrap();
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, draw_sprite);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+// A driver for SSD1306 OLED displays on SPI, which are 128x64 with a bit per pixel: just right
+// for SUPER-CHIP's high resolution mode, and CHIP-8's 64x32 with every pixel doubled. The
+// display's memory is 8 pages of 128 bytes, each byte a column of 8 pixels with the top one in
+// bit 0.
+
+use core::convert::Infallible;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+
+const WIDTH: usize = 128;
+const HEIGHT: usize = 64;
+const PAGES: usize = HEIGHT / 8;
+
+const INIT: [u8; 25] = [
+    0xae,        // display off
+    0xd5, 0x80,  // clock divider
+    0xa8, 0x3f,  // 64 rows
+    0xd3, 0x00,  // no vertical offset
+    0x40,        // start at row 0
+    0x8d, 0x14,  // charge pump on
+    0x20, 0x00,  // horizontal addressing, so a whole frame can be sent in one go
+    0xa1,        // column 127 on the left, so it isn't mirrored
+    0xc8,        // scan rows from the bottom, so it isn't upside down
+    0xda, 0x12,  // COM pin layout for 64 rows
+    0x81, 0xcf,  // contrast
+    0xd9, 0xf1,  // precharge
+    0xdb, 0x40,  // VCOMH level
+    0xa4,        // show what's in memory
+    0xa6,        // white on black
+    0xaf,        // display on
+];
+
+pub struct Ssd1306<SPI, DC> {
+    spi: SPI,
+
+    // low for commands, high for pixel data
+    dc: DC,
+
+    buffer: [u8; WIDTH * PAGES],
+}
+
+impl<SPI: SpiBus, DC: OutputPin<Error = Infallible>> Ssd1306<SPI, DC> {
+    pub fn new(spi: SPI, dc: DC) -> Result<Ssd1306<SPI, DC>, SPI::Error> {
+        let mut display = Ssd1306 {
+            spi,
+            dc,
+            buffer: [0; WIDTH * PAGES],
+        };
+        display.command(&INIT)?;
+        Ok(display)
+    }
+
+    // Show a CHIP-8 display (as from Chip8::step, a byte per pixel) scaled up to fill the screen.
+    // Anything smaller than 128x64 is taken to be the 64x32 low resolution mode.
+    pub fn draw(&mut self, pixels: &[u8]) -> Result<(), SPI::Error> {
+        let width = if pixels.len() == WIDTH * HEIGHT {WIDTH} else {WIDTH / 2};
+        let scale = WIDTH / width;
+        for (n, byte) in self.buffer.iter_mut().enumerate() {
+            let (page, x) = (n / WIDTH, n % WIDTH);
+            *byte = (0..8)
+                .filter(|bit| pixels[(page * 8 + bit) / scale * width + x / scale] != 0)
+                .fold(0, |byte, bit| byte | 1 << bit);
+        }
+
+        // the whole of memory, starting from the top left
+        self.command(&[0x21, 0, WIDTH as u8 - 1, 0x22, 0, PAGES as u8 - 1])?;
+        self.dc.set_high().ok();
+        self.spi.write(&self.buffer)?;
+        self.spi.flush()
+    }
+
+    fn command(&mut self, bytes: &[u8]) -> Result<(), SPI::Error> {
+        // DC mustn't change until the last byte has gone out
+        self.spi.flush()?;
+        self.dc.set_low().ok();
+        self.spi.write(bytes)?;
+        self.spi.flush()
+    }
+}
@@ -0,0 +1,111 @@
+// The emulator core running on a microcontroller: an STM32F411 with an SSD1306 display on SPI1
+// (SCK on PA5, MOSI on PA7 and D/C on PA4, with CS tied low). SysTick counts 60ths of a second,
+// which pace the program and drive its timers, and random numbers come from a seeded linear
+// congruential generator, since there's no operating system to ask for entropy.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+mod display;
+
+use alloc::boxed::Box;
+use chip8::{Chip8Builder, RandomSource};
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m_rt::{entry, exception};
+use display::Ssd1306;
+use embedded_alloc::LlffHeap as Heap;
+use embedded_hal::spi::MODE_0;
+use panic_halt as _;
+use stm32f4xx_hal::gpio::NoPin;
+use stm32f4xx_hal::pac;
+use stm32f4xx_hal::prelude::*;
+
+// enough for the Chip8's 4k of RAM, with room to spare
+const HEAP_SIZE: usize = 16 * 1024;
+
+// instructions per 60th of a second, for about 600 a second
+const CYCLES_PER_TICK: u32 = 10;
+
+const ROM: &[u8] = include_bytes!("../../tests/roms/font.ch8");
+
+#[global_allocator]
+static HEAP: Heap = Heap::empty();
+
+// 60ths of a second since SysTick was started
+static TICKS: AtomicU32 = AtomicU32::new(0);
+
+#[exception]
+fn SysTick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+// A linear congruential generator (with the constants from Numerical Recipes), taking the top
+// byte since the low bits of an LCG repeat quickly
+#[derive(Clone)]
+struct Lcg(u32);
+
+impl RandomSource for Lcg {
+    fn gen_byte(&mut self) -> u8 {
+        self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (self.0 >> 24) as u8
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    {
+        static mut HEAP_MEMORY: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+        // this is the only use of HEAP_MEMORY, and it happens once, before anything allocates
+        unsafe { HEAP.init(core::ptr::addr_of_mut!(HEAP_MEMORY) as usize, HEAP_SIZE) }
+    }
+
+    let dp = pac::Peripherals::take().unwrap();
+    let mut cp = cortex_m::Peripherals::take().unwrap();
+    let clocks = dp.RCC.constrain().cfgr.sysclk(84.MHz()).freeze();
+
+    cp.SYST.set_clock_source(SystClkSource::Core);
+    cp.SYST.set_reload(clocks.sysclk().raw() / 60 - 1);
+    cp.SYST.clear_current();
+    cp.SYST.enable_counter();
+    cp.SYST.enable_interrupt();
+
+    let gpioa = dp.GPIOA.split();
+    let spi = dp.SPI1.spi((gpioa.pa5, NoPin::new(), gpioa.pa7), MODE_0, 8.MHz(), &clocks);
+    let dc = gpioa.pa4.into_push_pull_output();
+    let mut display = Ssd1306::new(spi, dc).unwrap();
+
+    // any seed will do, though one from a noisy ADC pin would differ from one boot to the next
+    let mut chip8 = Chip8Builder::new()
+        .rom(ROM.to_vec())
+        .random_source(Box::new(Lcg(0x2545_f491)))
+        .build()
+        .unwrap();
+
+    let mut last_tick = 0;
+    loop {
+        let now = TICKS.load(Ordering::Relaxed);
+        if now == last_tick {
+            // there's nothing to do until the next tick
+            cortex_m::asm::wfi();
+            continue;
+        }
+        let elapsed = now.wrapping_sub(last_tick).min(u8::MAX as u32) as u8;
+        last_tick = now;
+
+        // with a keypad, this is where it would be scanned: bit N set for key N down
+        let keys = 0;
+
+        for n in 0..CYCLES_PER_TICK {
+            // the timers only need to hear about the elapsed ticks once
+            let ticks = if n == 0 {elapsed} else {0};
+            let pixels = chip8.step(keys, ticks).unwrap();
+            if n == CYCLES_PER_TICK - 1 {
+                display.draw(pixels).unwrap();
+            }
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// Runs arbitrary ROMs for up to MAX_CYCLES instructions. The first byte picks the machine, so
+// every instruction set gets covered; the rest is the ROM. Unknown opcodes are skipped, and any
+// other error just ends the run, so the failures are panics (indexing out of bounds, arithmetic
+// overflow and so on), and the machine getting into a state check_invariants rejects, which
+// emulate_cycle catches since cargo-fuzz builds with debug assertions.
+
+#![no_main]
+
+use chip8::{Chip8Builder, Chip8Error, Machine};
+use libfuzzer_sys::fuzz_target;
+
+const MAX_CYCLES: usize = 10_000;
+
+// as at the default speed, a timer tick every eight or so instructions
+const CYCLES_PER_TICK: usize = 8;
+
+const MACHINES: [Machine; 5] = [
+    Machine::Chip8,
+    Machine::Chip48,
+    Machine::SuperChip10,
+    Machine::SuperChip11,
+    Machine::XoChip,
+];
+
+fuzz_target!(|data: &[u8]| {
+    let (machine, rom) = match data.split_first() {
+        Some((first, rom)) => (MACHINES[*first as usize % MACHINES.len()], rom),
+        None => return,
+    };
+    let mut chip8 = match Chip8Builder::new()
+        .rom(rom.to_vec())
+        .machine(machine)
+        .random_seed(0)
+        .lenient(true)
+        .build()
+    {
+        Ok(chip8) => chip8,
+        // too big for the machine's memory
+        Err(_) => return,
+    };
+
+    for cycle in 1..=MAX_CYCLES {
+        match chip8.emulate_cycle() {
+            Err(err @ Chip8Error::InvariantViolated { .. }) => panic!("{}", err),
+            Err(_) => break,
+            Ok(()) => {},
+        }
+        if chip8.is_halted() {
+            break;
+        }
+        if cycle % CYCLES_PER_TICK == 0 {
+            chip8.tick_timers(1);
+            chip8.end_frame();
+            chip8.clear_dirty();
+        }
+    }
+});
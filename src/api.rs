@@ -0,0 +1,285 @@
+// An HTTP API for controlling the emulator from other programs, enabled with --api-port. With the
+// "api" feature enabled we serve it with axum, from a thread of its own; without it, ApiServer can
+// never be started.
+//
+//   GET  /             a page that shows the display and sends the keyboard's keys
+//   GET  /state        the registers, PC, stack depth, timers and display (base64) as JSON
+//   GET  /display      the display as a PNG
+//   GET  /ws/display   a WebSocket that sends the display whenever it changes, as a binary
+//                      message with a bit for each pixel, row by row, LSB first (256 bytes at
+//                      64x32), at most once a frame
+//   POST /key          press or release a key, with a body like {"key":5,"down":true}
+//   POST /reset        start the program over
+//   POST /load         replace the program with the ROM in the body
+//
+// The handlers read a copy of the Chip8 that the run loop puts in a Mutex at the end of each
+// frame. Anything that changes the Chip8 goes to the run loop as an ApiRequest, and is done
+// between frames, like a key press from the frontend.
+
+// only the server makes these, so without it they're never made
+#[cfg_attr(not(feature = "api"), allow(dead_code))]
+pub enum ApiRequest {
+    Key { key: u8, down: bool },
+    Reset,
+    Load(Vec<u8>),
+}
+
+#[cfg(feature = "api")]
+mod imp {
+    use super::ApiRequest;
+    use crate::frontend::Palette;
+    use crate::{screenshot, Chip8, Chip8Error};
+    use axum::body::Bytes;
+    use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+    use axum::extract::State;
+    use axum::http::{header, StatusCode};
+    use axum::response::{Html, IntoResponse, Response};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use base64::Engine;
+    use image::ImageFormat;
+    use serde::{Deserialize, Serialize};
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use tokio::sync::{oneshot, watch};
+
+    const INDEX: &str = include_str!("api/index.html");
+
+    // how the run loop says whether a request worked
+    type Reply = oneshot::Sender<Result<(), Chip8Error>>;
+
+    pub struct ApiServer {
+        shared: Arc<Shared>,
+        requests: Receiver<(ApiRequest, Reply)>,
+    }
+
+    // what the handlers get at
+    struct Shared {
+        chip8: Mutex<Chip8>,
+        requests: Sender<(ApiRequest, Reply)>,
+
+        // the display, packed as for /ws/display, each time it changes
+        frames: watch::Sender<Vec<u8>>,
+        palette: Palette,
+        scale: u32,
+    }
+
+    #[derive(Serialize)]
+    struct StateResponse {
+        v: [u8; 16],
+        i: u16,
+        pc: u16,
+        stack_depth: u8,
+        delay_timer: u8,
+        sound_timer: u8,
+        cycles: u64,
+        halted: bool,
+        width: usize,
+        height: usize,
+
+        // a byte for each pixel, row by row, as from Chip8::display_buffer
+        display: String,
+    }
+
+    #[derive(Deserialize)]
+    struct KeyRequest {
+        key: u8,
+        down: bool,
+    }
+
+    impl ApiServer {
+        // Listen on port (on localhost only) and serve requests from now on. GET /display draws
+        // in the colors of palette, with each pixel scale pixels across, like a screenshot.
+        pub fn start(port: u16, chip8: &Chip8, palette: &Palette, scale: u32)
+            -> Result<ApiServer, String>
+        {
+            let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|err| err.to_string())?;
+            listener.set_nonblocking(true).map_err(|err| err.to_string())?;
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|err| err.to_string())?;
+
+            let (sender, requests) = mpsc::channel();
+            let shared = Arc::new(Shared {
+                chip8: Mutex::new(chip8.clone()),
+                requests: sender,
+                frames: watch::Sender::new(pack_display(chip8)),
+                palette: *palette,
+                scale,
+            });
+
+            let app = Router::new()
+                .route("/", get(index))
+                .route("/ws/display", get(ws_display))
+                .route("/state", get(state))
+                .route("/display", get(display))
+                .route("/key", post(key))
+                .route("/reset", post(reset))
+                .route("/load", post(load))
+                .with_state(Arc::clone(&shared));
+
+            // the server goes on until the program exits
+            thread::spawn(move || {
+                runtime.block_on(async {
+                    if let Ok(listener) = tokio::net::TcpListener::from_std(listener) {
+                        let _ = axum::serve(listener, app).await;
+                    }
+                });
+            });
+
+            Ok(ApiServer { shared, requests })
+        }
+
+        // Carry out the requests made since the last call, with handler, and tell the clients
+        // how it went
+        pub fn handle<F>(&self, mut handler: F)
+            where F: FnMut(ApiRequest) -> Result<(), Chip8Error>
+        {
+            for (request, reply) in self.requests.try_iter() {
+                // the client may have gone away, but it's done either way
+                let _ = reply.send(handler(request));
+            }
+        }
+
+        // Called at the end of each frame, before the dirty rows are cleared, so the handlers
+        // see what the program's done, and the display goes out if it's changed
+        pub fn publish(&self, chip8: &Chip8) {
+            if let Ok(mut copy) = self.shared.chip8.lock() {
+                *copy = chip8.clone();
+            }
+            // rows can be drawn over and end up as they were, which isn't worth sending
+            if chip8.dirty_rows() != 0 {
+                let packed = pack_display(chip8);
+                self.shared.frames.send_if_modified(|frame| {
+                    let changed = *frame != packed;
+                    *frame = packed;
+                    changed
+                });
+            }
+        }
+    }
+
+    // A bit for each pixel that's on (in any plane), the first pixel in the lowest bit
+    fn pack_display(chip8: &Chip8) -> Vec<u8> {
+        let pixels = chip8.display_buffer();
+        let mut packed = vec![0; pixels.len().div_ceil(8)];
+        for (n, pixel) in pixels.iter().enumerate() {
+            if *pixel != 0 {
+                packed[n / 8] |= 1 << (n % 8);
+            }
+        }
+        packed
+    }
+
+    async fn index() -> Html<&'static str> {
+        Html(INDEX)
+    }
+
+    async fn ws_display(State(shared): State<Arc<Shared>>, upgrade: WebSocketUpgrade) -> Response {
+        let frames = shared.frames.subscribe();
+        upgrade.on_upgrade(move |socket| stream_display(socket, frames))
+    }
+
+    // Send the display as it is now, and again each time it changes, until the client goes away.
+    // If it can't keep up it misses frames rather than falling behind.
+    async fn stream_display(mut socket: WebSocket, mut frames: watch::Receiver<Vec<u8>>) {
+        loop {
+            let frame = frames.borrow_and_update().clone();
+            if socket.send(Message::Binary(frame.into())).await.is_err() {
+                return;
+            }
+            if frames.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn state(State(shared): State<Arc<Shared>>) -> Response {
+        let chip8 = match shared.chip8.lock() {
+            Ok(chip8) => chip8,
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let (width, height) = chip8.display_size();
+        let response = StateResponse {
+            v: chip8.v,
+            i: chip8.i,
+            pc: chip8.pc,
+            stack_depth: chip8.sp,
+            delay_timer: chip8.delay_timer.get_value(),
+            sound_timer: chip8.sound_timer.get_value(),
+            cycles: chip8.cycles,
+            halted: chip8.is_halted(),
+            width,
+            height,
+            display: base64::engine::general_purpose::STANDARD.encode(chip8.display_buffer()),
+        };
+        Json(response).into_response()
+    }
+
+    async fn display(State(shared): State<Arc<Shared>>) -> Response {
+        let image = match shared.chip8.lock() {
+            Ok(chip8) => screenshot::render(&chip8, &shared.palette, shared.scale),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let mut png = Vec::new();
+        match image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png) {
+            Ok(()) => ([(header::CONTENT_TYPE, "image/png")], png).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        }
+    }
+
+    async fn key(State(shared): State<Arc<Shared>>, Json(body): Json<KeyRequest>) -> Response {
+        send(&shared, ApiRequest::Key { key: body.key, down: body.down }).await
+    }
+
+    async fn reset(State(shared): State<Arc<Shared>>) -> Response {
+        send(&shared, ApiRequest::Reset).await
+    }
+
+    async fn load(State(shared): State<Arc<Shared>>, rom: Bytes) -> Response {
+        send(&shared, ApiRequest::Load(rom.to_vec())).await
+    }
+
+    // Hand request to the run loop, and wait for it to be done
+    async fn send(shared: &Shared, request: ApiRequest) -> Response {
+        let (reply, result) = oneshot::channel();
+        if shared.requests.send((request, reply)).is_err() {
+            return (StatusCode::SERVICE_UNAVAILABLE, "the emulator has stopped").into_response();
+        }
+        match result.await {
+            Ok(Ok(())) => StatusCode::NO_CONTENT.into_response(),
+            Ok(Err(err)) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+            Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "the emulator has stopped").into_response(),
+        }
+    }
+}
+
+#[cfg(not(feature = "api"))]
+mod imp {
+    use super::ApiRequest;
+    use crate::frontend::Palette;
+    use crate::{Chip8, Chip8Error};
+
+    pub struct ApiServer;
+
+    impl ApiServer {
+        pub fn start(_port: u16, _chip8: &Chip8, _palette: &Palette, _scale: u32)
+            -> Result<ApiServer, String>
+        {
+            Err("this build doesn't have the api feature".to_string())
+        }
+
+        pub fn handle<F>(&self, _handler: F)
+            where F: FnMut(ApiRequest) -> Result<(), Chip8Error>
+        {
+        }
+
+        pub fn publish(&self, _chip8: &Chip8) {}
+    }
+}
+
+pub use imp::ApiServer;
@@ -0,0 +1,303 @@
+// A two-pass assembler for the notation the disassembler uses (see disasm.rs), e.g.
+//
+//     start:  LD V0, K        ; wait for a key
+//             DRW V1, V2, 5
+//             JP start
+//
+// A label is a name followed by a colon at the start of a line, and can be used wherever an
+// address or number is expected. Numbers can be decimal, hex (0x1f) or binary (0b1010). DB and
+// DW put bytes and words (comma-separated, as many as you like) straight into the program. The
+// first pass works out where each label is, and the second turns each line into machine code.
+
+use crate::INSTRUCTIONS_START;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+const MNEMONICS: [&str; 29] = [
+    "ADD", "AND", "CALL", "CLS", "DB", "DRW", "DW", "EXIT", "HIGH", "JP", "LD", "LOW", "OR",
+    "PLANE", "RET", "RND", "SCD", "SCL", "SCR", "SE", "SHL", "SHR", "SKNP", "SKP", "SNE", "SUB",
+    "SUBN", "SYS", "XOR",
+];
+
+// operands with special meanings, which can't be used as labels
+const NAMES: [&str; 12] = ["I", "[I]", "DT", "ST", "K", "F", "HF", "B", "R", "LONG", "AUDIO",
+                           "PITCH"];
+
+#[derive(Debug,PartialEq)]
+pub struct AssembleError {
+    // counting from 1, and where in the line the problem is, as byte offsets
+    pub line: usize,
+    pub columns: Range<usize>,
+
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+// A label definition, and where it is in the source
+#[derive(Debug,PartialEq)]
+pub struct Label {
+    pub name: String,
+    pub addr: usize,
+    pub line: usize,
+    pub columns: Range<usize>,
+}
+
+// A line's instruction or data, without its label or comment
+struct Statement<'a> {
+    line: usize,
+    text: &'a str,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+enum Operand<'a> {
+    Register(u16),
+    Name(&'static str),
+    Value(&'a str),
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    let (_, program, errors) = run(source);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(program),
+    }
+}
+
+// Every problem with source, in order, and the labels it defines (for editors)
+pub fn check(source: &str) -> (Vec<Label>, Vec<AssembleError>) {
+    let (labels, _, errors) = run(source);
+    (labels, errors)
+}
+
+// Both passes, carrying on past any errors to find the rest
+fn run(source: &str) -> (Vec<Label>, Vec<u8>, Vec<AssembleError>) {
+    let mut labels: Vec<Label> = Vec::new();
+    let mut statements = Vec::new();
+    let mut errors = Vec::new();
+    let mut addr = INSTRUCTIONS_START as usize;
+    for (n, line_text) in source.lines().enumerate() {
+        let line = n + 1;
+
+        let mut text = line_text.split(';').next().unwrap_or_default().trim();
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            let columns = span(line_text, label);
+            if !is_label(label) {
+                let message = format!("invalid label {}", label);
+                errors.push(AssembleError { line, columns, message });
+            } else if labels.iter().any(|defined| defined.name == label) {
+                let message = format!("label {} is defined twice", label);
+                errors.push(AssembleError { line, columns, message });
+            } else {
+                labels.push(Label { name: label.to_string(), addr, line, columns });
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operands = match rest.trim() {
+            "" => Vec::new(),
+            rest => rest.split(',').map(str::trim).collect(),
+        };
+        let statement = Statement { line, text: line_text, mnemonic, operands };
+        addr += statement.size();
+        statements.push(statement);
+    }
+
+    let addrs: HashMap<&str, usize> = labels.iter()
+        .map(|label| (label.name.as_str(), label.addr))
+        .collect();
+    let mut program = Vec::new();
+    for statement in statements.iter() {
+        if let Err(error) = statement.encode(&addrs, &mut program) {
+            errors.push(error);
+        }
+    }
+    errors.sort_by_key(|error| error.line);
+    (labels, program, errors)
+}
+
+impl<'a> Statement<'a> {
+    fn size(&self) -> usize {
+        match self.mnemonic.to_ascii_uppercase().as_str() {
+            "DB" => self.operands.len(),
+            "DW" => 2 * self.operands.len(),
+            _ => 2,
+        }
+    }
+
+    fn encode(&self, labels: &HashMap<&str, usize>, out: &mut Vec<u8>)
+        -> Result<(), AssembleError>
+    {
+        match self.mnemonic.to_ascii_uppercase().as_str() {
+            "DB" => {
+                for operand in self.operands.iter() {
+                    out.push(self.value(operand, labels, 0xff)? as u8);
+                }
+            },
+            "DW" => {
+                for operand in self.operands.iter() {
+                    out.extend_from_slice(&self.value(operand, labels, 0xffff)?.to_be_bytes());
+                }
+            },
+            _ => out.extend_from_slice(&self.instruction(labels)?.to_be_bytes()),
+        }
+        Ok(())
+    }
+
+    fn instruction(&self, labels: &HashMap<&str, usize>) -> Result<u16, AssembleError> {
+        use Operand::{Name, Register, Value};
+
+        let operands: Vec<Operand> = self.operands.iter()
+            .map(|text| Operand::parse(text))
+            .collect();
+        let addr = |text: &str| self.value(text, labels, 0xfff);
+        let byte = |text: &str| self.value(text, labels, 0xff);
+        let nibble = |text: &str| self.value(text, labels, 0xf);
+        let xy = |x: u16, y: u16| x << 8 | y << 4;
+
+        let mnemonic = self.mnemonic.to_ascii_uppercase();
+        let instr = match (mnemonic.as_str(), operands.as_slice()) {
+            ("CLS", []) => 0x00e0,
+            ("RET", []) => 0x00ee,
+            ("SCD", [Value(n)]) => 0x00c0 | nibble(n)?,
+            ("SCR", []) => 0x00fb,
+            ("SCL", []) => 0x00fc,
+            ("EXIT", []) => 0x00fd,
+            ("LOW", []) => 0x00fe,
+            ("HIGH", []) => 0x00ff,
+            ("SYS", [Value(a)]) => addr(a)?,
+            ("JP", [Value(a)]) => 0x1000 | addr(a)?,
+            ("JP", [Register(0), Value(a)]) => 0xb000 | addr(a)?,
+            ("CALL", [Value(a)]) => 0x2000 | addr(a)?,
+            ("SE", [Register(x), Value(k)]) => 0x3000 | x << 8 | byte(k)?,
+            ("SE", [Register(x), Register(y)]) => 0x5000 | xy(*x, *y),
+            ("SNE", [Register(x), Value(k)]) => 0x4000 | x << 8 | byte(k)?,
+            ("SNE", [Register(x), Register(y)]) => 0x9000 | xy(*x, *y),
+            ("LD", [Register(x), Value(k)]) => 0x6000 | x << 8 | byte(k)?,
+            ("LD", [Register(x), Register(y)]) => 0x8000 | xy(*x, *y),
+            ("LD", [Name("I"), Name("LONG")]) => 0xf000,
+            ("LD", [Name("I"), Value(a)]) => 0xa000 | addr(a)?,
+            ("LD", [Name("AUDIO"), Name("[I]")]) => 0xf002,
+            ("LD", [Register(x), Name("DT")]) => 0xf007 | x << 8,
+            ("LD", [Register(x), Name("K")]) => 0xf00a | x << 8,
+            ("LD", [Name("DT"), Register(x)]) => 0xf015 | x << 8,
+            ("LD", [Name("ST"), Register(x)]) => 0xf018 | x << 8,
+            ("LD", [Name("F"), Register(x)]) => 0xf029 | x << 8,
+            ("LD", [Name("HF"), Register(x)]) => 0xf030 | x << 8,
+            ("LD", [Name("B"), Register(x)]) => 0xf033 | x << 8,
+            ("LD", [Name("PITCH"), Register(x)]) => 0xf03a | x << 8,
+            ("LD", [Name("[I]"), Register(x)]) => 0xf055 | x << 8,
+            ("LD", [Register(x), Name("[I]")]) => 0xf065 | x << 8,
+            ("LD", [Name("R"), Register(x)]) => 0xf075 | x << 8,
+            ("LD", [Register(x), Name("R")]) => 0xf085 | x << 8,
+            ("ADD", [Register(x), Value(k)]) => 0x7000 | x << 8 | byte(k)?,
+            ("ADD", [Register(x), Register(y)]) => 0x8004 | xy(*x, *y),
+            ("ADD", [Name("I"), Register(x)]) => 0xf01e | x << 8,
+            ("OR", [Register(x), Register(y)]) => 0x8001 | xy(*x, *y),
+            ("AND", [Register(x), Register(y)]) => 0x8002 | xy(*x, *y),
+            ("XOR", [Register(x), Register(y)]) => 0x8003 | xy(*x, *y),
+            ("SUB", [Register(x), Register(y)]) => 0x8005 | xy(*x, *y),
+            // the shifts' second register is optional, since it's ignored with the shift quirk
+            ("SHR", [Register(x)]) => 0x8006 | xy(*x, *x),
+            ("SHR", [Register(x), Register(y)]) => 0x8006 | xy(*x, *y),
+            ("SUBN", [Register(x), Register(y)]) => 0x8007 | xy(*x, *y),
+            ("SHL", [Register(x)]) => 0x800e | xy(*x, *x),
+            ("SHL", [Register(x), Register(y)]) => 0x800e | xy(*x, *y),
+            ("RND", [Register(x), Value(k)]) => 0xc000 | x << 8 | byte(k)?,
+            ("DRW", [Register(x), Register(y), Value(n)]) => 0xd000 | xy(*x, *y) | nibble(n)?,
+            ("SKP", [Register(x)]) => 0xe09e | x << 8,
+            ("SKNP", [Register(x)]) => 0xe0a1 | x << 8,
+            ("PLANE", [Value(n)]) => 0xf001 | nibble(n)? << 8,
+            (mnemonic, _) if MNEMONICS.contains(&mnemonic) => {
+                let operands = self.operands.join(", ");
+                let message = format!("invalid operands for {}: {}", mnemonic, operands);
+                let columns = match (self.operands.first(), self.operands.last()) {
+                    (Some(first), Some(last)) => {
+                        span(self.text, first).start..span(self.text, last).end
+                    },
+                    _ => span(self.text, self.mnemonic),
+                };
+                return Err(AssembleError { line: self.line, columns, message });
+            },
+            (mnemonic, _) => {
+                let message = format!("unknown instruction {}", mnemonic);
+                return Err(self.error(self.mnemonic, message));
+            },
+        };
+        Ok(instr)
+    }
+
+    // The number text stands for, or the address of the label it names, as long as it's at most
+    // max. Errors point at text, which must be part of this line.
+    fn value(&self, text: &str, labels: &HashMap<&str, usize>, max: usize)
+        -> Result<u16, AssembleError>
+    {
+        let number = if let Some(digits) = text.strip_prefix("0x") {
+            usize::from_str_radix(digits, 16).ok()
+        } else if let Some(digits) = text.strip_prefix("0b") {
+            usize::from_str_radix(digits, 2).ok()
+        } else {
+            text.parse().ok()
+        };
+
+        let value = match number {
+            Some(number) => number,
+            None if is_label(text) => match labels.get(text) {
+                Some(addr) => *addr,
+                None => return Err(self.error(text, format!("undefined label {}", text))),
+            },
+            None => return Err(self.error(text, format!("invalid number {}", text))),
+        };
+        if value > max {
+            let message = format!("{} is too large (at most {:#x})", text, max);
+            return Err(self.error(text, message));
+        }
+        Ok(value as u16)
+    }
+
+    fn error(&self, part: &str, message: String) -> AssembleError {
+        AssembleError { line: self.line, columns: span(self.text, part), message }
+    }
+}
+
+impl<'a> Operand<'a> {
+    fn parse(text: &'a str) -> Operand<'a> {
+        let upper = text.to_ascii_uppercase();
+        if let Some(name) = NAMES.iter().find(|name| **name == upper) {
+            return Operand::Name(name);
+        }
+        match upper.strip_prefix('V') {
+            Some(digit) if digit.len() == 1 => match u16::from_str_radix(digit, 16) {
+                Ok(reg) => Operand::Register(reg),
+                Err(_) => Operand::Value(text),
+            },
+            _ => Operand::Value(text),
+        }
+    }
+}
+
+// Whether text can be used as a label
+pub fn is_label(text: &str) -> bool {
+    let mut chars = text.chars();
+    let starts_well = chars.next().is_some_and(|ch| ch.is_ascii_alphabetic() || ch == '_');
+    starts_well
+        && chars.all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+        && matches!(Operand::parse(text), Operand::Value(_))
+}
+
+// Where part, which must be a slice of line, is within it
+fn span(line: &str, part: &str) -> Range<usize> {
+    let start = part.as_ptr() as usize - line.as_ptr() as usize;
+    start..start + part.len()
+}
@@ -0,0 +1,102 @@
+// A run loop for programs that already have a tokio runtime going (web servers, GUIs and the
+// like), with the "tokio" feature. It does without a frontend: keys come in as a stream of
+// InputEvents, and the display goes out as a stream of DisplayFrames, from display_frames.
+
+use crate::{Chip8, Chip8Error, Key, FRAME_RATE};
+use futures::stream::{self, Stream, StreamExt};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{self, MissedTickBehavior};
+
+// how many frames can wait to be taken from display_frames' stream before we start dropping
+// them, so a slow reader doesn't hold up the program
+const FRAME_BUFFER: usize = 4;
+
+pub(crate) type FrameSender = mpsc::Sender<DisplayFrame>;
+
+pub enum InputEvent {
+    // key is 0 to 0xf; others are ignored
+    Key { key: u8, down: bool },
+    Quit,
+}
+
+// The display at the end of a frame, as from Chip8::display_buffer
+#[derive(Clone,Debug,PartialEq)]
+pub struct DisplayFrame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Chip8 {
+    // The display, each time run_async finishes a frame that changed it. Only the latest call's
+    // stream gets frames.
+    pub fn display_frames(&mut self) -> impl Stream<Item = DisplayFrame> + Unpin {
+        let (sender, mut receiver) = mpsc::channel(FRAME_BUFFER);
+        self.frame_sender = Some(sender);
+        stream::poll_fn(move |context| receiver.poll_recv(context))
+    }
+
+    fn send_frame(&mut self) {
+        let (width, height) = self.display_size();
+        let frame = DisplayFrame {
+            width,
+            height,
+            pixels: self.display_buffer().to_vec(),
+        };
+        if let Some(sender) = &self.frame_sender {
+            if let Err(mpsc::error::TrySendError::Closed(_)) = sender.try_send(frame) {
+                self.frame_sender = None;
+            }
+        }
+    }
+}
+
+// Run chip8 at its speed, 60 frames a second, until the program exits, events sends Quit, or
+// there's an error. If events runs dry the program carries on without any more input.
+pub async fn run_async(chip8: &mut Chip8, mut events: impl Stream<Item = InputEvent> + Unpin)
+    -> Result<(), Chip8Error>
+{
+    let cycles_per_frame = (chip8.speed / FRAME_RATE).max(1);
+    let mut interval = time::interval(Duration::from_secs(1) / FRAME_RATE);
+    // after a hiccup, carry on at the usual pace rather than rushing to catch up
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut more_events = true;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            event = events.next(), if more_events => {
+                match event {
+                    Some(InputEvent::Key { key, down }) => {
+                        if let Some(state) = chip8.keys.get_mut(key as usize) {
+                            *state = if down {Key::Down} else {Key::Up};
+                        }
+                    },
+                    Some(InputEvent::Quit) => return Ok(()),
+                    None => more_events = false,
+                }
+                continue;
+            },
+        }
+
+        for _ in 0..cycles_per_frame {
+            chip8.emulate_cycle()?;
+            if chip8.is_halted() {
+                break;
+            }
+        }
+        if !chip8.is_cycle_accurate() {
+            chip8.tick_timers(1);
+        }
+
+        chip8.end_frame();
+        if chip8.dirty_rows() != 0 {
+            chip8.clear_dirty();
+            chip8.send_frame();
+        }
+        if chip8.is_halted() {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,143 @@
+// The buzzer sounds for as long as the sound timer is nonzero. With the "audio" feature enabled
+// we play a square wave through cpal (or XO-CHIP's audio pattern, if the program has loaded one);
+// without it, Buzzer is a silent stand-in with the same interface so the run loop doesn't need to
+// care. Without the "std" feature there is no Buzzer at all, only the constants.
+
+pub const DEFAULT_BUZZER_FREQUENCY: f32 = 440.;
+
+// XO-CHIP's audio pattern is 128 bits, played one after the other (a 1 for high, a 0 for low) and
+// then repeated
+pub const AUDIO_PATTERN_SIZE: usize = 16;
+
+// An audio pattern, and how many of its bits to play per second
+#[cfg(feature = "std")]
+pub type Waveform = ([u8; AUDIO_PATTERN_SIZE], f32);
+
+#[cfg(feature = "audio")]
+mod imp {
+    use super::{Waveform, AUDIO_PATTERN_SIZE};
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    const VOLUME: f32 = 0.2;
+    const PATTERN_BITS: f32 = (AUDIO_PATTERN_SIZE * 8) as f32;
+
+    // what the emulator tells the audio callback
+    struct Shared {
+        playing: AtomicBool,
+        waveform: Mutex<Option<Waveform>>,
+    }
+
+    pub struct Buzzer {
+        shared: Arc<Shared>,
+
+        // the stream stops as soon as it's dropped, so we need to hold on to it
+        _stream: cpal::Stream,
+    }
+
+    impl Buzzer {
+        pub fn new(frequency: f32) -> Option<Buzzer> {
+            let host = cpal::default_host();
+            let device = host.default_output_device()?;
+            let config = device.default_output_config().ok()?;
+
+            let shared = Arc::new(Shared {
+                playing: AtomicBool::new(false),
+                waveform: Mutex::new(None),
+            });
+
+            let format = config.sample_format();
+            let config = config.into();
+            let stream = match format {
+                cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, frequency, &shared),
+                cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, frequency, &shared),
+                cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, frequency, &shared),
+                _ => return None,
+            }?;
+            stream.play().ok()?;
+
+            Some(Buzzer {
+                shared,
+                _stream: stream,
+            })
+        }
+
+        pub fn set_playing(&self, playing: bool) {
+            self.shared.playing.store(playing, Ordering::Relaxed);
+        }
+
+        // Play waveform instead of the square wave, or go back to the square wave if it's None
+        pub fn set_waveform(&self, waveform: Option<Waveform>) {
+            if let Ok(mut current) = self.shared.waveform.lock() {
+                *current = waveform;
+            }
+        }
+    }
+
+    fn build_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, frequency: f32,
+                       shared: &Arc<Shared>) -> Option<cpal::Stream>
+        where T: cpal::SizedSample + cpal::FromSample<f32>
+    {
+        let sample_rate = config.sample_rate.0 as f32;
+        let channels = config.channels as usize;
+        let shared = Arc::clone(shared);
+
+        // the waveform as of the last time we could get at it; the callback mustn't wait for the
+        // lock, so if it's busy we carry on with this
+        let mut waveform = None;
+
+        // position within the current period of the wave, from 0 to 1
+        let mut phase = 0.;
+
+        let callback = move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            if let Ok(current) = shared.waveform.try_lock() {
+                waveform = *current;
+            }
+
+            let on = shared.playing.load(Ordering::Relaxed);
+            for frame in data.chunks_mut(channels) {
+                let (high, step) = match waveform {
+                    Some((pattern, rate)) => {
+                        let bit = (phase * PATTERN_BITS) as usize;
+                        let high = pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+                        (high, rate / PATTERN_BITS / sample_rate)
+                    },
+                    None => (phase < 0.5, frequency / sample_rate),
+                };
+                let value = if !on {
+                    0.
+                } else if high {
+                    VOLUME
+                } else {
+                    -VOLUME
+                };
+                phase = (phase + step) % 1.;
+
+                for sample in frame.iter_mut() {
+                    *sample = T::from_sample(value);
+                }
+            }
+        };
+
+        device.build_output_stream(config, callback, |_| {}, None).ok()
+    }
+}
+
+#[cfg(all(feature = "std", not(feature = "audio")))]
+mod imp {
+    pub struct Buzzer;
+
+    impl Buzzer {
+        pub fn new(_frequency: f32) -> Option<Buzzer> {
+            None
+        }
+
+        pub fn set_playing(&self, _playing: bool) {}
+
+        pub fn set_waveform(&self, _waveform: Option<super::Waveform>) {}
+    }
+}
+
+#[cfg(feature = "std")]
+pub use imp::Buzzer;
@@ -0,0 +1,34 @@
+use chip8::assembler;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() < 2 {
+        println!("Usage: chip8-asm <source> [<rom>]");
+        process::exit(1);
+    }
+
+    let source = fs::read_to_string(&args[1]).unwrap_or_else(|err| {
+        println!("Couldn't open file: {}", err);
+        process::exit(1);
+    });
+
+    let rom = assembler::assemble(&source).unwrap_or_else(|err| {
+        println!("{}: {}", args[1], err);
+        process::exit(1);
+    });
+
+    // pong.asm becomes pong.ch8, unless we're told otherwise
+    let rom_path = match args.get(2) {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => Path::new(&args[1]).with_extension("ch8"),
+    };
+    fs::write(&rom_path, rom).unwrap_or_else(|err| {
+        println!("Couldn't write {}: {}", rom_path.display(), err);
+        process::exit(1);
+    });
+}
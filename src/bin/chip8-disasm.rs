@@ -0,0 +1,43 @@
+use chip8::disasm::{self, Symbols};
+use std::env;
+use std::fs;
+use std::process;
+
+fn main() {
+    let mut symbols_path = None;
+    let mut rom_path = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--symbols" => symbols_path = Some(args.next().unwrap_or_else(|| usage())),
+            _ => rom_path = Some(arg),
+        }
+    }
+    let rom_path = rom_path.unwrap_or_else(|| usage());
+
+    let symbols = match symbols_path {
+        Some(path) => {
+            let text = fs::read_to_string(&path).unwrap_or_else(|err| {
+                println!("Couldn't open file: {}", err);
+                process::exit(1);
+            });
+            disasm::parse_symbols(&text).unwrap_or_else(|err| {
+                println!("{}: {}", path, err);
+                process::exit(1);
+            })
+        },
+        None => Symbols::new(),
+    };
+
+    let rom = fs::read(&rom_path).unwrap_or_else(|err| {
+        println!("Couldn't open file: {}", err);
+        process::exit(1);
+    });
+
+    disasm::print_listing(&rom, &symbols);
+}
+
+fn usage() -> ! {
+    println!("Usage: chip8-disasm [--symbols <file>] <rom>");
+    process::exit(1);
+}
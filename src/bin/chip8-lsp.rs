@@ -0,0 +1,10 @@
+use chip8::lsp;
+use std::process;
+
+fn main() {
+    // stdout is for talking to the editor
+    if let Err(err) = lsp::serve() {
+        eprintln!("chip8-lsp: {}", err);
+        process::exit(1);
+    }
+}
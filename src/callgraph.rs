@@ -0,0 +1,75 @@
+// Records which subroutines call which (with --callgraph), and writes the result out as a
+// Graphviz DOT file when the emulator exits, so that
+//
+//     dot -Tsvg callgraph.dot -o callgraph.svg
+//
+// draws a map of the program. Each node is a subroutine's entry address (plus the program's
+// start), labelled with how many times it was entered; each edge is labelled with how many
+// calls were made along it.
+
+use crate::{Chip8, Chip8Error, INSTRUCTIONS_START};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+#[derive(Default)]
+pub struct CallGraph {
+    // how many times each subroutine was entered, by its address
+    entries: BTreeMap<u16, u64>,
+
+    // how many calls each subroutine made to each other one, by (caller, callee)
+    calls: BTreeMap<(u16, u16), u64>,
+
+    // the entry addresses of the subroutines we're in, innermost last; empty when we're in the
+    // code at the start of the program
+    stack: Vec<u16>,
+}
+
+impl CallGraph {
+    pub fn new() -> CallGraph {
+        let mut graph = CallGraph::default();
+        graph.entries.insert(INSTRUCTIONS_START, 1);
+        graph
+    }
+
+    // Called just before the instruction at the current PC is executed
+    pub fn record(&mut self, chip8: &Chip8) {
+        // it isn't executed yet if it's waiting for the frame
+        let instr = match chip8.fetch() {
+            Some(instr) if !chip8.is_waiting_for_frame() => instr,
+            _ => return,
+        };
+        if instr & 0xf000 == 0x2000 {
+            let callee = instr & 0x0fff;
+            let caller = self.stack.last().copied().unwrap_or(INSTRUCTIONS_START);
+            *self.calls.entry((caller, callee)).or_insert(0) += 1;
+            *self.entries.entry(callee).or_insert(0) += 1;
+            self.stack.push(callee);
+        } else if instr == 0x00ee {
+            // a program that returns more often than it calls (or one started over partway
+            // through) just leaves us back at the top level
+            self.stack.pop();
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Chip8Error> {
+        let failed = |err: io::Error| {
+            Chip8Error::CallGraphFailed { path: path.to_string(), reason: err.to_string() }
+        };
+        let mut out = BufWriter::new(File::create(path).map_err(failed)?);
+        self.write_dot(&mut out).and_then(|_| out.flush()).map_err(failed)
+    }
+
+    pub fn write_dot<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "digraph calls {{")?;
+        for (addr, count) in self.entries.iter() {
+            let times = if *count == 1 {"time"} else {"times"};
+            writeln!(out, "    \"{:#06x}\" [label=\"{:#06x}\\nentered {} {}\"];",
+                     addr, addr, count, times)?;
+        }
+        for ((caller, callee), count) in self.calls.iter() {
+            writeln!(out, "    \"{:#06x}\" -> \"{:#06x}\" [label=\"{}\"];", caller, callee, count)?;
+        }
+        writeln!(out, "}}")
+    }
+}
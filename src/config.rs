@@ -0,0 +1,641 @@
+use crate::audio::DEFAULT_BUZZER_FREQUENCY;
+use crate::debugger::parse_addr;
+use crate::frontend::{Color, FrontendKind, DEFAULT_PALETTE, Palette};
+use crate::{Machine, QuirkFlags, QuirkPreset, DEFAULT_SPEED};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "Usage: chip8 [options] <rom>
+
+Options:
+  --frontend NAME    ncurses (the default), sdl2, crossterm or pixels
+  --mode NAME        chip8 (the default), chip48, superchip10, superchip (1.1) or xochip, for
+                     the instructions of CHIP-48, SUPER-CHIP or XO-CHIP
+  --scale N          draw each CHIP-8 pixel N times as large
+  --speed N          run N instructions per second (the default is 500)
+  --cycle-accurate   count the timers down every N/60 instructions, rather than 60 times a
+                     second of real time, so a slow host doesn't throw them off
+  --fg-color COLOR   color of pixels that are on, as hex RGB (#00ff00) or a name like green
+  --bg-color COLOR   color of pixels that are off (the defaults are white on black)
+  --palette COLORS   four comma-separated colors for XO-CHIP: off, on in the first plane, on
+                     in the second plane, and on in both
+  --fullscreen       start the sdl2 frontend fullscreen
+  --phosphor         fade pixels out over a few frames when they're turned off, like a CRT
+                     (ncurses only, on terminals with 256 colors)
+  --disasm           print a disassembly of the ROM instead of running it
+  --debug            start in the step debugger
+  --break ADDR       stop in the debugger before executing the instruction at ADDR (hex)
+  --watch            start the ROM over whenever the file changes (needs the watch feature)
+  --trace PATH       write each instruction executed, and the registers, to PATH
+  --trace-limit N    stop tracing after N instructions
+  --callgraph PATH   write a graph of which subroutines called which to PATH when the program
+                     exits, in Graphviz's DOT format
+  --record PATH      record the display to PATH as an animated GIF
+  --record-input PATH
+                     record the keys pressed to PATH
+  --replay-input PATH
+                     press the keys recorded in PATH instead of reading the keyboard; with
+                     --cycle-accurate, this repeats a recorded session exactly
+  --gamepad-index N  only read the Nth gamepad, counting from 0, rather than all of them (needs
+                     the gilrs feature)
+  --lenient          skip unknown instructions with a warning, rather than stopping
+  --warn-self-modify warn whenever the program writes over its own code
+  --max-cycles N     stop after N emulate cycles, counting cycles spent waiting for the frame
+                     with the draw-wait quirk as well as ones that execute an instruction
+  --verbose          print the display and registers when the program stops
+  --log-level LEVEL  log to stderr at LEVEL: error, warn (the default), info, debug (each
+                     instruction) or trace (what each instruction did)
+  --perf             show the actual instructions per second, frame rate and timer rate
+  --rewind-buffer N  keep the last N frames, so Backspace can go back through them (180 is
+                     three seconds)
+  --api-port N       serve an HTTP API on localhost port N, to watch and control the emulator
+                     from other programs, or from a browser at http://localhost:N/ (needs the
+                     api feature)
+  --quirk NAME       enable a compatibility quirk; NAME is one of:
+                     vf-reset, shift, load-store, wrap, i-overflow, draw-wait
+                     or the name of a platform, to use its quirks: chip8, chip48,
+                     superchip or xochip. Individual quirks are added to the platform's.
+  --config PATH      read settings from PATH rather than ./chip8.toml or
+                     ~/.config/chip8/config.toml
+
+Settings in the config file can be overridden for a particular ROM by a file next to it with the
+same name, ending in .toml (pong.toml for pong.ch8), and the options above override both.
+
+While running, Esc pauses and resumes, F5 starts the ROM over, F2 saves the state to
+state.chip8 and F3 loads it back, F12 saves a screenshot as screenshot_<time>.png, Backspace
+rewinds a frame with --rewind-buffer, and Ctrl-C (or closing the window) quits. In the sdl2
+frontend, F11 switches in and out of fullscreen, and in fullscreen Esc leaves it rather than
+pausing.";
+
+pub struct Config {
+    pub rom_path: String,
+    pub frontend: FrontendKind,
+    pub scale: u8,
+
+    // instructions per second
+    pub speed: u32,
+
+    // tick the timers by instruction count rather than by the clock
+    pub cycle_accurate: bool,
+
+    // colors for pixels that are off, and on in each combination of XO-CHIP's planes; CHIP-8
+    // and SUPER-CHIP only use the first two
+    pub palette: Palette,
+
+    // start fullscreen, for the frontends with a window of their own
+    pub fullscreen: bool,
+
+    // let pixels fade out rather than turning off at once
+    pub phosphor: bool,
+
+    // print a disassembly of the ROM instead of running it
+    pub disasm: bool,
+
+    // start in the step debugger
+    pub debug: bool,
+
+    // addresses to stop at in the debugger
+    pub breakpoints: Vec<u16>,
+
+    // reload the ROM whenever the file changes
+    pub watch: bool,
+
+    // where to write a line for each instruction executed, and how many lines at most
+    pub trace: Option<String>,
+    pub trace_limit: Option<u64>,
+
+    // where to write the call graph on exit
+    pub callgraph: Option<String>,
+
+    // where to record the display to
+    pub record: Option<String>,
+
+    // where to record key presses to, or play them back from
+    pub record_input: Option<String>,
+    pub replay_input: Option<String>,
+
+    // skip unknown opcodes rather than stopping, and warn about the program writing over itself
+    pub lenient: bool,
+    pub warn_self_modify: bool,
+
+    // stop after this many instructions
+    pub max_cycles: Option<u64>,
+
+    // print the registers on the way out
+    pub verbose: bool,
+
+    // the most detailed messages to log to stderr
+    pub log_level: tracing::Level,
+
+    // show performance figures while running
+    pub perf: bool,
+
+    // how many frames to keep snapshots of for rewinding, if any
+    pub rewind_buffer: Option<usize>,
+
+    // the port to serve the HTTP API on, if any
+    pub api_port: Option<u16>,
+
+    // behaviors that differ between CHIP-8 interpreters, all off by default
+    pub quirks: QuirkFlags,
+
+    pub audio: AudioConfig,
+
+    pub gamepad: GamepadConfig,
+}
+
+#[derive(Copy,Clone,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    pub enabled: bool,
+
+    // pitch of the buzzer, in Hz
+    pub frequency: f32,
+}
+
+impl Default for AudioConfig {
+    fn default() -> AudioConfig {
+        AudioConfig {
+            enabled: true,
+            frequency: DEFAULT_BUZZER_FREQUENCY,
+        }
+    }
+}
+
+// Which CHIP-8 key (0 to 0xf) each gamepad button presses, if any. The D-pad defaults to 5, 7, 8
+// and 9, since they're up, left, down and right in so many games, and the face buttons to 6, A,
+// B and C.
+#[derive(Copy,Clone,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GamepadConfig {
+    pub dpad_up: Option<u8>,
+    pub dpad_down: Option<u8>,
+    pub dpad_left: Option<u8>,
+    pub dpad_right: Option<u8>,
+    pub south: Option<u8>,
+    pub east: Option<u8>,
+    pub west: Option<u8>,
+    pub north: Option<u8>,
+    pub select: Option<u8>,
+    pub start: Option<u8>,
+    pub left_trigger: Option<u8>,
+    pub right_trigger: Option<u8>,
+
+    // the only gamepad to read, counting from 0, or None for all of them
+    pub index: Option<usize>,
+}
+
+impl Default for GamepadConfig {
+    fn default() -> GamepadConfig {
+        GamepadConfig {
+            dpad_up: Some(0x5),
+            dpad_down: Some(0x8),
+            dpad_left: Some(0x7),
+            dpad_right: Some(0x9),
+            south: Some(0x6),
+            east: Some(0xa),
+            west: Some(0xb),
+            north: Some(0xc),
+            select: None,
+            start: None,
+            left_trigger: None,
+            right_trigger: None,
+            index: None,
+        }
+    }
+}
+
+impl GamepadConfig {
+    fn keys(&self) -> [Option<u8>; 12] {
+        [self.dpad_up, self.dpad_down, self.dpad_left, self.dpad_right, self.south, self.east,
+         self.west, self.north, self.select, self.start, self.left_trigger, self.right_trigger]
+    }
+}
+
+// Settings from a config file, e.g.
+//
+//     speed = 700
+//     fg_color = "green"
+//
+//     [quirks]
+//     machine = "superchip"
+//     shift_quirk = true
+//
+// Anything left out keeps its default. A ROM's own config file looks just the same.
+#[derive(Default,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    frontend: Option<String>,
+    scale: Option<u8>,
+    speed: Option<u32>,
+    fg_color: Option<String>,
+    bg_color: Option<String>,
+    palette: Option<Vec<String>>,
+    quirks: QuirkFlags,
+    audio: AudioConfig,
+    gamepad: GamepadConfig,
+}
+
+impl FileConfig {
+    // Settings from the global config file (the one at path, if it's given), overridden by any in
+    // the ROM's own config file: one with the same name as the ROM but ending in .toml, like
+    // pong.toml for pong.ch8
+    fn load(path: Option<&str>, rom_path: &str) -> Result<FileConfig, String> {
+        let mut table = match path {
+            Some(path) => {
+                read_table(Path::new(path))?.ok_or(format!("Couldn't find config file {}", path))?
+            },
+            None => find_table()?.unwrap_or_default(),
+        };
+        if let Some(rom_table) = read_table(&Path::new(rom_path).with_extension("toml"))? {
+            merge_tables(&mut table, rom_table);
+        }
+        toml::Value::Table(table).try_into().map_err(|err| format!("Invalid config: {}", err))
+    }
+}
+
+// The contents of the config file at path, or None if there's no such file
+fn read_table(path: &Path) -> Result<Option<toml::Table>, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("Couldn't read config file {}: {}", path.display(), err)),
+    };
+
+    // check it now, so any mistakes are reported along with the file they're in
+    let invalid = |err: toml::de::Error| format!("Invalid config file {}: {}", path.display(), err);
+    toml::from_str::<FileConfig>(&text).map_err(invalid)?;
+    toml::from_str(&text).map(Some).map_err(invalid)
+}
+
+// The first config file there is of ./chip8.toml and ~/.config/chip8/config.toml (or the
+// equivalent under $XDG_CONFIG_HOME)
+fn find_table() -> Result<Option<toml::Table>, String> {
+    let mut paths = vec![PathBuf::from("chip8.toml")];
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    };
+    if let Some(dir) = config_dir {
+        paths.push(dir.join("chip8").join("config.toml"));
+    }
+
+    for path in paths {
+        if let Some(table) = read_table(&path)? {
+            return Ok(Some(table));
+        }
+    }
+    Ok(None)
+}
+
+// Replace anything in base that's also in overrides, going into sections like [quirks] rather than
+// replacing them wholesale
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => {
+                merge_tables(base, overrides);
+            },
+            (_, value) => {
+                base.insert(key, value);
+            },
+        }
+    }
+}
+
+// What we've worked out so far from the config files and the command line
+struct Settings {
+    rom_path: Option<String>,
+    config_path: Option<String>,
+    frontend: FrontendKind,
+    scale: Option<u8>,
+    speed: u32,
+    cycle_accurate: bool,
+    palette: Palette,
+    fullscreen: bool,
+    phosphor: bool,
+    disasm: bool,
+    debug: bool,
+    breakpoints: Vec<u16>,
+    watch: bool,
+    trace: Option<String>,
+    trace_limit: Option<u64>,
+    callgraph: Option<String>,
+    record: Option<String>,
+    record_input: Option<String>,
+    replay_input: Option<String>,
+    lenient: bool,
+    warn_self_modify: bool,
+    max_cycles: Option<u64>,
+    verbose: bool,
+    log_level: tracing::Level,
+    perf: bool,
+    rewind_buffer: Option<usize>,
+    api_port: Option<u16>,
+    gamepad: GamepadConfig,
+    quirks: QuirkFlags,
+}
+
+impl Settings {
+    fn from_file(file: &FileConfig) -> Result<Settings, String> {
+        let frontend = match &file.frontend {
+            Some(name) => parse_frontend(name)?,
+            None => FrontendKind::Ncurses,
+        };
+        let scale = match file.scale {
+            Some(0) => return Err("Invalid scale 0".to_string()),
+            scale => scale,
+        };
+        let speed = match file.speed {
+            Some(0) => return Err("Invalid speed 0".to_string()),
+            speed => speed.unwrap_or(DEFAULT_SPEED),
+        };
+        let mut palette = match &file.palette {
+            Some(colors) => parse_palette(&colors.join(","))?,
+            None => DEFAULT_PALETTE,
+        };
+        if let Some(name) = &file.bg_color {
+            palette[0] = Color::parse(name).ok_or(format!("Invalid color {}", name))?;
+        }
+        if let Some(name) = &file.fg_color {
+            palette[1] = Color::parse(name).ok_or(format!("Invalid color {}", name))?;
+        }
+
+        Ok(Settings {
+            rom_path: None,
+            config_path: None,
+            frontend,
+            scale,
+            speed,
+            cycle_accurate: false,
+            palette,
+            fullscreen: false,
+            phosphor: false,
+            disasm: false,
+            debug: false,
+            breakpoints: Vec::new(),
+            watch: false,
+            trace: None,
+            trace_limit: None,
+            callgraph: None,
+            record: None,
+            record_input: None,
+            replay_input: None,
+            lenient: false,
+            warn_self_modify: false,
+            max_cycles: None,
+            verbose: false,
+            log_level: tracing::Level::WARN,
+            perf: false,
+            rewind_buffer: None,
+            api_port: None,
+            gamepad: file.gamepad,
+            quirks: file.quirks,
+        })
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<(), String> {
+        // a quirk preset replaces all the quirks, so we save the rest until we know if there is one
+        let mut preset = None;
+        let mut machine = None;
+        let mut quirks = Vec::new();
+
+        let mut args = args.iter().cloned();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    self.config_path = Some(args.next().ok_or("--config needs a path")?);
+                },
+                "--frontend" => {
+                    let value = args.next().ok_or("--frontend needs a value")?;
+                    self.frontend = parse_frontend(&value)?;
+                },
+                "--mode" => {
+                    let value = args.next().ok_or("--mode needs a value")?;
+                    machine = Some(parse_machine(&value)?);
+                },
+                "--scale" => {
+                    let value = args.next().ok_or("--scale needs a value")?;
+                    self.scale = match value.parse() {
+                        Ok(0) | Err(_) => return Err(format!("Invalid scale {}", value)),
+                        Ok(n) => Some(n),
+                    };
+                },
+                "--speed" => {
+                    let value = args.next().ok_or("--speed needs a value")?;
+                    self.speed = match value.parse() {
+                        Ok(0) | Err(_) => return Err(format!("Invalid speed {}", value)),
+                        Ok(n) => n,
+                    };
+                },
+                "--cycle-accurate" => self.cycle_accurate = true,
+                "--fg-color" => {
+                    let value = args.next().ok_or("--fg-color needs a value")?;
+                    let color = Color::parse(&value).ok_or(format!("Invalid color {}", value))?;
+                    self.palette[1] = color;
+                },
+                "--bg-color" => {
+                    let value = args.next().ok_or("--bg-color needs a value")?;
+                    let color = Color::parse(&value).ok_or(format!("Invalid color {}", value))?;
+                    self.palette[0] = color;
+                },
+                "--palette" => {
+                    let value = args.next().ok_or("--palette needs four colors")?;
+                    self.palette = parse_palette(&value)?;
+                },
+                "--fullscreen" => self.fullscreen = true,
+                "--phosphor" => self.phosphor = true,
+                "--disasm" => self.disasm = true,
+                "--debug" => self.debug = true,
+                "--watch" if cfg!(feature = "watch") => self.watch = true,
+                "--trace" => self.trace = Some(args.next().ok_or("--trace needs a path")?),
+                "--trace-limit" => {
+                    let value = args.next().ok_or("--trace-limit needs a value")?;
+                    let limit = value.parse().map_err(|_| format!("Invalid limit {}", value))?;
+                    self.trace_limit = Some(limit);
+                },
+                "--callgraph" => {
+                    self.callgraph = Some(args.next().ok_or("--callgraph needs a path")?);
+                },
+                "--record" => self.record = Some(args.next().ok_or("--record needs a path")?),
+                "--record-input" => {
+                    self.record_input = Some(args.next().ok_or("--record-input needs a path")?);
+                },
+                "--replay-input" => {
+                    self.replay_input = Some(args.next().ok_or("--replay-input needs a path")?);
+                },
+                "--gamepad-index" if cfg!(feature = "gilrs") => {
+                    let value = args.next().ok_or("--gamepad-index needs a value")?;
+                    let index = value.parse().map_err(|_| format!("Invalid index {}", value))?;
+                    self.gamepad.index = Some(index);
+                },
+                "--lenient" => self.lenient = true,
+                "--warn-self-modify" => self.warn_self_modify = true,
+                "--max-cycles" => {
+                    let value = args.next().ok_or("--max-cycles needs a value")?;
+                    let max = value.parse().map_err(|_| format!("Invalid limit {}", value))?;
+                    self.max_cycles = Some(max);
+                },
+                "--verbose" => self.verbose = true,
+                "--log-level" => {
+                    let value = args.next().ok_or("--log-level needs a level")?;
+                    let level = value.parse().map_err(|_| format!("Invalid log level {}", value))?;
+                    self.log_level = level;
+                },
+                "--perf" => self.perf = true,
+                "--rewind-buffer" => {
+                    let value = args.next().ok_or("--rewind-buffer needs a value")?;
+                    self.rewind_buffer = match value.parse() {
+                        Ok(0) | Err(_) => return Err(format!("Invalid rewind buffer {}", value)),
+                        Ok(n) => Some(n),
+                    };
+                },
+                "--api-port" if cfg!(feature = "api") => {
+                    let value = args.next().ok_or("--api-port needs a value")?;
+                    let port = value.parse().map_err(|_| format!("Invalid port {}", value))?;
+                    self.api_port = Some(port);
+                },
+                "--break" => {
+                    let value = args.next().ok_or("--break needs an address")?;
+                    let addr = parse_addr(&value).ok_or(format!("Invalid address {}", value))?;
+                    self.breakpoints.push(addr as u16);
+                },
+                "--quirk" => {
+                    let value = args.next().ok_or("--quirk needs a name")?;
+                    match parse_quirk_preset(&value) {
+                        Some(value) => preset = Some(value),
+                        None => quirks.push(value),
+                    }
+                },
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => self.rom_path = Some(arg),
+            }
+        }
+
+        if let Some(preset) = preset {
+            self.quirks = preset.to_quirk_flags();
+        }
+        if let Some(machine) = machine {
+            self.quirks.machine = machine;
+        }
+        for name in quirks.iter() {
+            enable_quirk(&mut self.quirks, name)?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    // Build a Config from command line arguments (not including the program name), on top of
+    // whatever's in the config files
+    pub fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Config, String> {
+        let args: Vec<String> = args.collect();
+
+        // we need the ROM's path (and --config, if it's there) to find the config files, but the
+        // command line overrides them, so we go through it once before reading them and once after
+        let mut settings = Settings::from_file(&FileConfig::default())?;
+        settings.apply_args(&args)?;
+        let rom_path = settings.rom_path.ok_or("Please provide a filename")?;
+        let file = FileConfig::load(settings.config_path.as_deref(), &rom_path)?;
+
+        let mut settings = Settings::from_file(&file)?;
+        settings.apply_args(&args)?;
+        if let Some(key) = settings.gamepad.keys().iter().flatten().find(|key| **key > 0xf) {
+            return Err(format!("Invalid gamepad key {:#x}", key));
+        }
+
+        Ok(Config {
+            rom_path,
+            frontend: settings.frontend,
+            scale: settings.scale.unwrap_or_else(|| settings.frontend.default_scale()),
+            speed: settings.speed,
+            cycle_accurate: settings.cycle_accurate,
+            palette: settings.palette,
+            fullscreen: settings.fullscreen,
+            phosphor: settings.phosphor,
+            disasm: settings.disasm,
+            debug: settings.debug,
+            breakpoints: settings.breakpoints,
+            watch: settings.watch,
+            trace: settings.trace,
+            trace_limit: settings.trace_limit,
+            callgraph: settings.callgraph,
+            record: settings.record,
+            record_input: settings.record_input,
+            replay_input: settings.replay_input,
+            lenient: settings.lenient,
+            warn_self_modify: settings.warn_self_modify,
+            max_cycles: settings.max_cycles,
+            verbose: settings.verbose,
+            log_level: settings.log_level,
+            perf: settings.perf,
+            rewind_buffer: settings.rewind_buffer,
+            api_port: settings.api_port,
+            quirks: settings.quirks,
+            audio: file.audio,
+            gamepad: settings.gamepad,
+        })
+    }
+}
+
+fn parse_frontend(name: &str) -> Result<FrontendKind, String> {
+    match name {
+        "ncurses" => Ok(FrontendKind::Ncurses),
+        #[cfg(feature = "sdl2")]
+        "sdl2" => Ok(FrontendKind::Sdl2),
+        #[cfg(feature = "crossterm")]
+        "crossterm" => Ok(FrontendKind::Crossterm),
+        #[cfg(feature = "pixels-renderer")]
+        "pixels" => Ok(FrontendKind::Pixels),
+        _ => Err(format!("Unknown or unsupported frontend {}", name)),
+    }
+}
+
+fn parse_machine(name: &str) -> Result<Machine, String> {
+    match name {
+        "chip8" => Ok(Machine::Chip8),
+        "chip48" => Ok(Machine::Chip48),
+        "superchip10" => Ok(Machine::SuperChip10),
+        "superchip" | "superchip11" => Ok(Machine::SuperChip11),
+        "xochip" => Ok(Machine::XoChip),
+        _ => Err(format!("Unknown mode {}", name)),
+    }
+}
+
+fn parse_palette(text: &str) -> Result<Palette, String> {
+    let colors = text.split(',')
+        .map(|name| Color::parse(name.trim()).ok_or(format!("Invalid color {}", name)))
+        .collect::<Result<Vec<Color>, String>>()?;
+    let mut palette = DEFAULT_PALETTE;
+    if colors.len() != palette.len() {
+        return Err(format!("--palette needs four colors, not {}", colors.len()));
+    }
+    palette.copy_from_slice(&colors);
+    Ok(palette)
+}
+
+fn parse_quirk_preset(name: &str) -> Option<QuirkPreset> {
+    match name {
+        "chip8" => Some(QuirkPreset::Chip8),
+        "chip48" => Some(QuirkPreset::Chip48),
+        "superchip" => Some(QuirkPreset::SuperChip),
+        "xochip" => Some(QuirkPreset::XoChip),
+        _ => None,
+    }
+}
+
+fn enable_quirk(quirks: &mut QuirkFlags, name: &str) -> Result<(), String> {
+    match name {
+        "vf-reset" => quirks.vf_reset = true,
+        "shift" => quirks.shift_quirk = true,
+        "load-store" => quirks.load_store_quirk = true,
+        "wrap" => quirks.wrap_sprites = true,
+        "i-overflow" => quirks.i_overflow_vf = true,
+        "draw-wait" => quirks.draw_wait = true,
+        _ => return Err(format!("Unknown quirk {}", name)),
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+// Post-mortem dumps: when the program runs into an instruction we can't execute, the run loop
+// writes out what the machine looked like at the time, and the instructions leading up to it, to
+// crash_<time>_<pid>.txt.
+
+use crate::disasm::disassemble_instruction;
+use crate::{Chip8, Chip8Error};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// how many of the most recent instructions are kept for the dump
+const HISTORY_LENGTH: usize = 16;
+
+// how many bytes of memory around PC go in the dump
+const MEMORY_BYTES: usize = 32;
+
+#[derive(Default)]
+pub struct History {
+    // (address, instruction) for the most recent instructions, oldest first
+    instructions: VecDeque<(u16, u16)>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    // Called before each instruction is executed
+    pub fn record(&mut self, chip8: &Chip8) {
+        let instr = match chip8.fetch() {
+            // it isn't executed yet if it's waiting for the frame
+            Some(_) if chip8.is_waiting_for_frame() => return,
+            Some(instr) => instr,
+            // it can't be executed either, so that's what the dump will be about
+            None => return,
+        };
+        if self.instructions.len() == HISTORY_LENGTH {
+            self.instructions.pop_front();
+        }
+        self.instructions.push_back((chip8.pc, instr));
+    }
+}
+
+fn default_path() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    format!("crash_{}_{}.txt", seconds, process::id())
+}
+
+// Write a dump for err to a new file, and say where on stderr (so the frontend should have given
+// the terminal back by now). We're stopping anyway, so a failure is only reported.
+pub fn save(chip8: &Chip8, err: &Chip8Error, history: &History) {
+    let path = default_path();
+    match write(&path, chip8, err, history) {
+        Ok(()) => eprintln!("Wrote a post-mortem dump to {}", path),
+        Err(write_err) => eprintln!("Couldn't write a post-mortem dump to {}: {}", path, write_err),
+    }
+}
+
+fn write(path: &str, chip8: &Chip8, err: &Chip8Error, history: &History) -> io::Result<()> {
+    fs::write(path, report(chip8, err, history))
+}
+
+// The dump itself: what went wrong, the registers, the stack, the memory around PC and the
+// instructions that came before
+pub fn report(chip8: &Chip8, err: &Chip8Error, history: &History) -> String {
+    let mut report = format!("{}\n\n", err);
+
+    let registers: Vec<String> = chip8.v.iter()
+        .enumerate()
+        .map(|(n, val)| format!("V{:X}={:02x}", n, val))
+        .collect();
+    report += &format!("{}\nI={:#06x}\n", registers.join(" "), chip8.i);
+
+    let stack: Vec<String> = chip8.get_stack().iter().map(|addr| format!("{:#06x}", addr)).collect();
+    let stack = if stack.is_empty() {"empty".to_string()} else {stack.join(" ")};
+    report += &format!("stack (innermost last): {}\n\nmemory:\n", stack);
+
+    let start = (chip8.pc as usize).saturating_sub(MEMORY_BYTES / 2);
+    let end = (start + MEMORY_BYTES).min(chip8.ram.len());
+    for (n, line) in chip8.ram[start..end].chunks(16).enumerate() {
+        let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        report += &format!("{:#06x}: {}\n", start + 16 * n, bytes.join(" "));
+    }
+
+    report += "\nlast instructions (most recent last):\n";
+    for (addr, instr) in history.instructions.iter() {
+        report += &format!("{:#06x}  {:04x}  {}\n", addr, instr, disassemble_instruction(*instr));
+    }
+    report
+}
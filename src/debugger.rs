@@ -0,0 +1,165 @@
+// A simple line-based debugger, enabled with --debug. Pressing Enter executes one instruction;
+// the other commands are listed in HELP.
+
+use crate::disasm::disassemble_instruction;
+use crate::{Chip8, WatchHit, XO_CHIP_RAM_SIZE};
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "Commands:
+  <Enter>   execute one instruction
+  b <addr>  set a breakpoint at addr (hex)
+  c         continue running until a breakpoint
+  m <addr>  show 16 bytes of memory starting at addr (hex)
+  r         show registers
+  w <reg>   stop whenever register reg (V0-VF) is written
+  wm <addr> stop whenever memory at addr (hex) is written
+  q         quit";
+
+pub enum DebugAction {
+    Continue,
+    Quit,
+}
+
+pub struct Debugger {
+    stepping: bool,
+
+    // the most recently executed instruction and its address, so we can report on it
+    last_instr: Option<(u16, u16)>,
+}
+
+impl Debugger {
+    // If stepping is false, run until a breakpoint is reached
+    pub fn new(stepping: bool) -> Debugger {
+        Debugger {
+            stepping,
+            last_instr: None,
+        }
+    }
+
+    // Called before each instruction is fetched. While the instruction at PC is held up waiting
+    // for the frame, stepping or a breakpoint there waits too, rather than stopping every cycle.
+    pub fn should_pause(&self, chip8: &Chip8) -> bool {
+        let at_breakpoint = self.stepping || chip8.breakpoints.contains(&chip8.pc);
+        (at_breakpoint && !chip8.is_waiting_for_frame()) || !chip8.watch_hits.is_empty()
+    }
+
+    // Called just before the instruction at the current PC is executed
+    pub fn record(&mut self, chip8: &Chip8) {
+        if !chip8.is_waiting_for_frame() {
+            self.last_instr = chip8.fetch().map(|instr| (chip8.pc, instr));
+        }
+    }
+
+    // Read and handle commands until one of them resumes (or stops) execution
+    pub fn prompt(&mut self, chip8: &mut Chip8) -> DebugAction {
+        let hits: Vec<WatchHit> = chip8.watch_hits.drain(..).collect();
+        if !hits.is_empty() {
+            let (addr, instr) = self.last_instr.unwrap_or_default();
+            for hit in hits.iter() {
+                let (name, old, new) = match hit {
+                    WatchHit::Register { reg, old, new } => (format!("V{:X}", reg), old, new),
+                    WatchHit::Memory { addr, old, new } => (format!("{:#06x}", addr), old, new),
+                };
+                println!("{} changed from {:#04x} to {:#04x} by {} at {:#06x}; PC is now {:#06x}",
+                         name, old, new, disassemble_instruction(instr), addr, chip8.pc);
+            }
+            print_registers(chip8);
+        } else if !self.stepping {
+            println!("Breakpoint at {:#06x}", chip8.pc);
+            print_registers(chip8);
+        } else if let Some((_, instr)) = self.last_instr {
+            println!("{:#06x}  (executed {})", chip8.pc, disassemble_instruction(instr));
+            print_registers(chip8);
+        } else {
+            println!("{}", HELP);
+        }
+
+        let stdin = io::stdin();
+        loop {
+            print!("{:#06x}> ", chip8.pc);
+            let _ = io::stdout().flush();
+
+            let mut line = String::new();
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => return DebugAction::Quit,
+                Ok(_) => {},
+            }
+
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("b") => match words.next().and_then(parse_addr) {
+                    Some(addr) => {
+                        chip8.breakpoints.insert(addr as u16);
+                        println!("Breakpoint set at {:#06x}", addr);
+                    },
+                    None => println!("Usage: b <addr>"),
+                },
+                None => {
+                    self.stepping = true;
+                    return DebugAction::Continue;
+                },
+                Some("c") => {
+                    self.stepping = false;
+                    return DebugAction::Continue;
+                },
+                Some("m") => match words.next().and_then(parse_addr) {
+                    Some(addr) => dump_memory(chip8, addr),
+                    None => println!("Usage: m <addr>"),
+                },
+                Some("q") => return DebugAction::Quit,
+                Some("r") => print_registers(chip8),
+                Some("w") => match words.next().and_then(parse_register) {
+                    Some(reg) => {
+                        chip8.watched_registers |= 1 << reg;
+                        println!("Watching V{:X}", reg);
+                    },
+                    None => println!("Usage: w <reg>"),
+                },
+                Some("wm") => match words.next().and_then(parse_addr) {
+                    Some(addr) => {
+                        chip8.memory_watchpoints.insert(addr);
+                        println!("Watching {:#06x}", addr);
+                    },
+                    None => println!("Usage: wm <addr>"),
+                },
+                Some(_) => println!("{}", HELP),
+            }
+        }
+    }
+}
+
+// Addresses can be anywhere in XO-CHIP's memory, since we don't always know yet how much there is
+pub fn parse_addr(text: &str) -> Option<usize> {
+    let digits = text.trim_start_matches("0x");
+    usize::from_str_radix(digits, 16).ok().filter(|addr| *addr < XO_CHIP_RAM_SIZE)
+}
+
+fn parse_register(text: &str) -> Option<usize> {
+    let digit = text.strip_prefix('V').or_else(|| text.strip_prefix('v'))?;
+    usize::from_str_radix(digit, 16).ok().filter(|reg| *reg < 16)
+}
+
+fn dump_memory(chip8: &Chip8, addr: usize) {
+    if addr >= chip8.ram.len() {
+        println!("{:#06x} is past the end of memory", addr);
+        return;
+    }
+
+    let end = (addr + 16).min(chip8.ram.len());
+    let bytes: Vec<String> = chip8.ram[addr..end].iter().map(|b| format!("{:02x}", b)).collect();
+    println!("{:#06x}: {}", addr, bytes.join(" "));
+}
+
+pub fn print_registers(chip8: &Chip8) {
+    let registers: Vec<String> = chip8.v.iter()
+        .enumerate()
+        .map(|(n, val)| format!("V{:X}={:02x}", n, val))
+        .collect();
+    println!("{}", registers.join(" "));
+
+    let stack_top = match chip8.stack().last() {
+        Some(addr) => format!("{:#06x}", addr),
+        None => "empty".to_string(),
+    };
+    println!("I={:#06x} PC={:#06x} stack top={}", chip8.i, chip8.pc, stack_top);
+}
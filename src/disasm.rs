@@ -0,0 +1,225 @@
+// Turns CHIP-8 machine code into mnemonics, using the usual notation (as in Cowgod's reference):
+// "LD V3, 0x42", "DRW V1, V2, 5", "JP 0x228" and so on. SUPER-CHIP and XO-CHIP
+// instructions are always recognized, since there's no telling which machine a ROM was written for.
+//
+// Listings label the places jumped to and called, so they can be fed back to the assembler:
+// subroutines are SUB_0x220 and so on, the targets of jumps backwards are LOOP_1, LOOP_2..., and
+// other jump targets LABEL_0x300. Names can also be given for addresses, in a symbols file with
+// lines like "0x220 draw_paddle".
+
+use crate::assembler::is_label;
+use crate::INSTRUCTIONS_START;
+use std::collections::HashMap;
+
+// names for addresses, chosen by the user
+pub type Symbols = HashMap<u16, String>;
+
+// Print a listing of a ROM loaded at the usual address
+pub fn print_listing(rom: &[u8], symbols: &Symbols) {
+    print!("{}", listing(rom, INSTRUCTIONS_START, symbols));
+}
+
+// A listing of rom, loaded at load_addr, that the assembler will turn back into the same ROM: a
+// line for each label, and one for each two-byte word with its address in a comment
+pub fn listing(rom: &[u8], load_addr: u16, symbols: &Symbols) -> String {
+    let mut listing = String::new();
+    for (addr, label, mnemonic) in disassemble(rom, load_addr, symbols) {
+        if let Some(label) = label {
+            listing += &format!("{}:\n", label);
+        }
+        listing += &format!("    {:<20}  ; {:#06x}\n", mnemonic, addr);
+    }
+    listing
+}
+
+// Disassemble every two-byte word of rom, which is loaded at load_addr, along with the label for
+// each word's address if it has one. Where an instruction refers to a labelled address, it uses
+// the label.
+pub fn disassemble(rom: &[u8], load_addr: u16, symbols: &Symbols)
+    -> Vec<(u16, Option<String>, String)>
+{
+    let words: Vec<(u16, Option<u16>)> = rom.chunks(2)
+        .enumerate()
+        .map(|(n, word)| {
+            let addr = load_addr.wrapping_add(2 * n as u16);
+            match *word {
+                [first, second] => (addr, Some((first as u16) << 8 | second as u16)),
+                // a ROM with an odd number of bytes
+                _ => (addr, None),
+            }
+        })
+        .collect();
+    let labels = find_labels(&words, symbols);
+
+    words.iter()
+        .map(|(addr, instr)| {
+            let mnemonic = match instr {
+                Some(instr) => match labels.get(&(instr & 0x0fff)) {
+                    Some(label) => with_label(*instr, label),
+                    None => None,
+                }.unwrap_or_else(|| disassemble_instruction(*instr)),
+                None => format!("DB {:#04x}", rom[rom.len() - 1]),
+            };
+            (*addr, labels.get(addr).cloned(), mnemonic)
+        })
+        .collect()
+}
+
+// Read a symbols file: an address (in hex) and a name on each line, with comments after semicolons
+pub fn parse_symbols(text: &str) -> Result<Symbols, String> {
+    let mut symbols = Symbols::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+        let (addr, name) = match (words.next(), words.next(), words.next()) {
+            (None, _, _) => continue,
+            (Some(addr), Some(name), None) => (addr, name),
+            _ => return Err(format!("line {}: expected an address and a name", n + 1)),
+        };
+        let digits = addr.trim_start_matches("0x");
+        let addr = u16::from_str_radix(digits, 16)
+            .map_err(|_| format!("line {}: invalid address {}", n + 1, addr))?;
+        if !is_label(name) {
+            return Err(format!("line {}: invalid name {}", n + 1, name));
+        }
+        symbols.insert(addr, name.to_string());
+    }
+    Ok(symbols)
+}
+
+// Labels for the addresses in symbols, and those that jumps and calls go to, as long as they're
+// the start of a word in the ROM
+fn find_labels(words: &[(u16, Option<u16>)], symbols: &Symbols) -> HashMap<u16, String> {
+    let in_rom = |target: u16| words.iter().any(|(addr, _)| *addr == target);
+
+    let mut calls = Vec::new();
+    let mut jumps = Vec::new();
+    for (addr, instr) in words.iter() {
+        let target = instr.map_or(0, |instr| instr & 0x0fff);
+        match instr.map(|instr| instr >> 12) {
+            Some(0x1) => jumps.push((*addr, target)),
+            Some(0x2) => calls.push(target),
+            _ => {},
+        }
+    }
+
+    let mut labels: HashMap<u16, String> = symbols.iter()
+        .filter(|(addr, _)| in_rom(**addr))
+        .map(|(addr, name)| (*addr, name.clone()))
+        .collect();
+    for target in calls {
+        if in_rom(target) {
+            labels.entry(target).or_insert_with(|| format!("SUB_{:#05x}", target));
+        }
+    }
+
+    let mut targets: Vec<u16> = jumps.iter().map(|(_, target)| *target).collect();
+    targets.sort_unstable();
+    targets.dedup();
+    let mut loops = 0;
+    for target in targets {
+        if !in_rom(target) || labels.contains_key(&target) {
+            continue;
+        }
+        let backwards = jumps.iter().any(|(addr, to)| *to == target && *addr >= target);
+        let label = if backwards {
+            loops += 1;
+            format!("LOOP_{}", loops)
+        } else {
+            format!("LABEL_{:#05x}", target)
+        };
+        labels.insert(target, label);
+    }
+    labels
+}
+
+// instr with its address replaced by label, if it has an address
+fn with_label(instr: u16, label: &str) -> Option<String> {
+    match instr >> 12 {
+        0x1 => Some(format!("JP {}", label)),
+        0x2 => Some(format!("CALL {}", label)),
+        0xa => Some(format!("LD I, {}", label)),
+        0xb => Some(format!("JP V0, {}", label)),
+        _ => None,
+    }
+}
+
+pub fn disassemble_instruction(instr: u16) -> String {
+    let x = (instr & 0x0f00) >> 8;
+    let y = (instr & 0x00f0) >> 4;
+    let n = instr & 0x000f;
+    let nn = instr & 0x00ff;
+    let nnn = instr & 0x0fff;
+
+    match (instr & 0xf000) >> 12 {
+        0x0 => match nnn {
+            0x0c0..=0x0cf => format!("SCD {}", n),
+            0x0e0 => "CLS".to_string(),
+            0x0ee => "RET".to_string(),
+            0x0fb => "SCR".to_string(),
+            0x0fc => "SCL".to_string(),
+            0x0fd => "EXIT".to_string(),
+            0x0fe => "LOW".to_string(),
+            0x0ff => "HIGH".to_string(),
+            _ => format!("SYS {:#05x}", nnn),
+        },
+        0x1 => format!("JP {:#05x}", nnn),
+        0x2 => format!("CALL {:#05x}", nnn),
+        0x3 => format!("SE V{:X}, {:#04x}", x, nn),
+        0x4 => format!("SNE V{:X}, {:#04x}", x, nn),
+        0x5 if n == 0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6 => format!("LD V{:X}, {:#04x}", x, nn),
+        0x7 => format!("ADD V{:X}, {:#04x}", x, nn),
+        0x8 => {
+            let op = match n {
+                0x0 => "LD",
+                0x1 => "OR",
+                0x2 => "AND",
+                0x3 => "XOR",
+                0x4 => "ADD",
+                0x5 => "SUB",
+                0x6 => "SHR",
+                0x7 => "SUBN",
+                0xe => "SHL",
+                _ => return unknown(instr),
+            };
+            format!("{} V{:X}, V{:X}", op, x, y)
+        },
+        0x9 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xa => format!("LD I, {:#05x}", nnn),
+        0xb => format!("JP V0, {:#05x}", nnn),
+        0xc => format!("RND V{:X}, {:#04x}", x, nn),
+        0xd => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xe => match nn {
+            0x9e => format!("SKP V{:X}", x),
+            0xa1 => format!("SKNP V{:X}", x),
+            _ => unknown(instr),
+        },
+        0xf => match nn {
+            // the address follows in the next word
+            0x00 if x == 0 => "LD I, LONG".to_string(),
+            0x01 => format!("PLANE {}", x),
+            0x02 if x == 0 => "LD AUDIO, [I]".to_string(),
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0a => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1e => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x30 => format!("LD HF, V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x3a => format!("LD PITCH, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            0x75 => format!("LD R, V{:X}", x),
+            0x85 => format!("LD V{:X}, R", x),
+            _ => unknown(instr),
+        },
+        _ => unknown(instr),
+    }
+}
+
+// Words that aren't instructions are usually sprite data
+fn unknown(instr: u16) -> String {
+    format!("DW {:#06x}", instr)
+}
@@ -0,0 +1,214 @@
+use super::{cell_size, dirty_pixels, key_index, Color, Command, Frontend, HeldKeys, Palette};
+use crate::{Key, HI_RES_PIXELS, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers,
+                       KeyboardEnhancementFlags};
+use crossterm::style::{self, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::{cursor, execute, queue, terminal};
+use std::io::{self, Stdout, Write};
+use std::time::Duration;
+
+const ON_CHAR: char = '\u{2588}';
+const OFF_CHAR: char = ' ';
+
+pub struct CrosstermFrontend {
+    stdout: Stdout,
+    palette: [style::Color; 4],
+
+    // our own copy of the display, row by row, as palette indexes, so we can redraw everything
+    // when the terminal is resized
+    pixels: [u8; HI_RES_PIXELS],
+    width: usize,
+    height: usize,
+
+    scale: u16,
+
+    // how many (columns, rows) of characters each CHIP-8 pixel takes up
+    cell: (u16, u16),
+
+    // where the top left corner of the display is within the terminal
+    origin: (u16, u16),
+
+    // whether the terminal will tell us about key releases, and if not, when to make them up
+    key_releases: bool,
+    held_keys: HeldKeys,
+}
+
+impl CrosstermFrontend {
+    pub fn new(scale: u16, palette: Palette) -> io::Result<CrosstermFrontend> {
+        let mut stdout = io::stdout();
+
+        terminal::enable_raw_mode()?;
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+
+        let key_releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if key_releases {
+            let flags = KeyboardEnhancementFlags::REPORT_EVENT_TYPES;
+            execute!(stdout, event::PushKeyboardEnhancementFlags(flags))?;
+        }
+
+        let mut frontend = CrosstermFrontend {
+            stdout,
+            palette: palette.map(terminal_color),
+            pixels: [0; HI_RES_PIXELS],
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+            scale,
+            cell: cell_size(scale, SCREEN_WIDTH),
+            origin: (0, 0),
+            key_releases,
+            held_keys: HeldKeys::default(),
+        };
+        let (columns, rows) = terminal::size()?;
+        frontend.center(columns, rows);
+        Ok(frontend)
+    }
+
+    // Put the display in the middle of a terminal this size
+    fn center(&mut self, columns: u16, rows: u16) {
+        let (cell_columns, cell_rows) = self.cell;
+        self.origin = (
+            columns.saturating_sub(self.width as u16 * cell_columns) / 2,
+            rows.saturating_sub(self.height as u16 * cell_rows) / 2,
+        );
+    }
+
+    fn draw_pixel(&mut self, x: usize, y: usize) -> io::Result<()> {
+        let color = self.pixels[y * self.width + x] as usize;
+        let ch = if color != 0 {ON_CHAR} else {OFF_CHAR};
+        let (width, height) = self.cell;
+        let line = ch.to_string().repeat(width as usize);
+
+        let (left, top) = self.origin;
+        let (left, top) = (left + x as u16 * width, top + y as u16 * height);
+        queue!(self.stdout, SetForegroundColor(self.palette[color]))?;
+        for row in 0..height {
+            queue!(self.stdout, cursor::MoveTo(left, top + row), Print(&line))?;
+        }
+        Ok(())
+    }
+
+    fn redraw(&mut self) -> io::Result<()> {
+        queue!(
+            self.stdout,
+            SetBackgroundColor(self.palette[0]),
+            terminal::Clear(terminal::ClearType::All),
+        )?;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                self.draw_pixel(x, y)?;
+            }
+        }
+        self.stdout.flush()
+    }
+}
+
+impl Drop for CrosstermFrontend {
+    fn drop(&mut self) {
+        // nothing useful to do if restoring the terminal fails
+        if self.key_releases {
+            let _ = execute!(self.stdout, event::PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.stdout, ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+impl Frontend for CrosstermFrontend {
+    fn render(&mut self, display: &[u8], dirty_rows: u64) {
+        if dirty_rows == 0 {
+            return;
+        }
+
+        let mut result = queue!(self.stdout, SetBackgroundColor(self.palette[0]));
+        for (x, y, color) in dirty_pixels(display, self.width, dirty_rows) {
+            self.pixels[y * self.width + x] = color;
+            result = result.and_then(|_| self.draw_pixel(x, y));
+        }
+        // a failed write leaves the terminal stale until the pixels are drawn again
+        let _ = result.and_then(|_| self.stdout.flush());
+    }
+
+    fn poll_events(&mut self, keys: &mut [Key; 16]) -> Option<Command> {
+        self.held_keys.release_expired(keys);
+        while let Ok(true) = event::poll(Duration::from_secs(0)) {
+            match event::read() {
+                Ok(Event::Key(key_event)) => {
+                    let pressed = key_event.kind == KeyEventKind::Press;
+                    match key_event.code {
+                        KeyCode::Char('c') if key_event.modifiers == KeyModifiers::CONTROL => {
+                            return Some(Command::Quit);
+                        },
+                        KeyCode::Esc if pressed => return Some(Command::Pause),
+                        KeyCode::F(2) if pressed => return Some(Command::SaveState),
+                        KeyCode::F(3) if pressed => return Some(Command::LoadState),
+                        KeyCode::F(5) if pressed => return Some(Command::Reset),
+                        KeyCode::F(12) if pressed => return Some(Command::Screenshot),
+                        // repeats too, so holding it down goes further back
+                        KeyCode::Backspace if key_event.kind != KeyEventKind::Release => {
+                            return Some(Command::Rewind);
+                        },
+                        _ => {},
+                    }
+                    if let KeyCode::Char(ch) = key_event.code {
+                        if let Some(key) = key_index(ch.to_ascii_lowercase()) {
+                            match key_event.kind {
+                                KeyEventKind::Release => keys[key] = Key::Up,
+                                _ if self.key_releases => keys[key] = Key::Down,
+                                _ => self.held_keys.press(key, keys),
+                            }
+                        }
+                    }
+                },
+                Ok(Event::Resize(columns, rows)) => {
+                    self.center(columns, rows);
+                    let _ = self.redraw();
+                },
+                _ => {},
+            }
+        }
+        None
+    }
+
+    fn clear(&mut self) {
+        self.pixels = [0; HI_RES_PIXELS];
+        let _ = self.redraw();
+    }
+
+    fn set_resolution(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.cell = cell_size(self.scale, width);
+        if let Ok((columns, rows)) = terminal::size() {
+            self.center(columns, rows);
+        }
+        self.clear();
+    }
+
+    fn show_status(&mut self, text: &str) {
+        // the line just below the display
+        let (left, top) = self.origin;
+        let _ = execute!(
+            self.stdout,
+            cursor::MoveTo(left, top + self.height as u16 * self.cell.1),
+            ResetColor,
+            Print(text),
+            terminal::Clear(terminal::ClearType::UntilNewLine),
+        );
+    }
+
+    fn suspend(&mut self) {
+        let _ = execute!(self.stdout, ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+
+    fn resume(&mut self) {
+        let _ = terminal::enable_raw_mode();
+        let _ = execute!(self.stdout, terminal::EnterAlternateScreen, cursor::Hide);
+        let _ = self.redraw();
+    }
+}
+
+
+fn terminal_color(color: Color) -> style::Color {
+    style::Color::Rgb { r: color.r, g: color.g, b: color.b }
+}
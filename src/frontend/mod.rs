@@ -0,0 +1,224 @@
+use crate::config::Config;
+use crate::{Key, KEYBOARD_MAP, SCREEN_WIDTH};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "crossterm")]
+mod crossterm;
+mod ncurses;
+#[cfg(feature = "pixels-renderer")]
+mod pixels;
+#[cfg(feature = "sdl2")]
+mod sdl2;
+
+#[cfg(feature = "crossterm")]
+pub use self::crossterm::CrosstermFrontend;
+pub use self::ncurses::NcursesFrontend;
+#[cfg(feature = "pixels-renderer")]
+pub use self::pixels::PixelsFrontend;
+#[cfg(feature = "sdl2")]
+pub use self::sdl2::Sdl2Frontend;
+
+// Something the user asked for that isn't a CHIP-8 key press
+pub enum Command {
+    Quit,
+
+    // pause the emulator if it's running, or carry on if it's paused
+    Pause,
+
+    // start the ROM again from the beginning
+    Reset,
+
+    // save everything about the running program to a file, or go back to what was saved
+    SaveState,
+    LoadState,
+
+    // save a picture of the display
+    Screenshot,
+
+    // go back to the state at the end of the last frame kept for --rewind-buffer
+    Rewind,
+
+    // the window went to the background, so the releases of any keys held now won't arrive
+    FocusLost,
+}
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+// The eight basic terminal colors, in the order curses numbers them (COLOR_BLACK is 0, and so on)
+pub const NAMED_COLORS: [(&str, Color); 8] = [
+    ("black", Color { r: 0, g: 0, b: 0 }),
+    ("red", Color { r: 0xff, g: 0, b: 0 }),
+    ("green", Color { r: 0, g: 0xff, b: 0 }),
+    ("yellow", Color { r: 0xff, g: 0xff, b: 0 }),
+    ("blue", Color { r: 0, g: 0, b: 0xff }),
+    ("magenta", Color { r: 0xff, g: 0, b: 0xff }),
+    ("cyan", Color { r: 0, g: 0xff, b: 0xff }),
+    ("white", Color { r: 0xff, g: 0xff, b: 0xff }),
+];
+
+impl Color {
+    pub const BLACK: Color = NAMED_COLORS[0].1;
+    pub const WHITE: Color = NAMED_COLORS[7].1;
+
+    // Accepts hex RGB like "#00ff00" or one of the NAMED_COLORS
+    pub fn parse(text: &str) -> Option<Color> {
+        let text = text.to_ascii_lowercase();
+        if let Some(hex) = text.strip_prefix('#') {
+            if hex.len() != 6 || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+                return None;
+            }
+            let component = |n: usize| u8::from_str_radix(&hex[2 * n..2 * n + 2], 16).ok();
+            return Some(Color { r: component(0)?, g: component(1)?, b: component(2)? });
+        }
+        NAMED_COLORS.iter().find(|(name, _)| *name == text).map(|(_, color)| *color)
+    }
+}
+
+// The colors pixels are drawn in, by which planes they're on in: off, the first plane only, the
+// second plane only, and both. Only XO-CHIP uses the second plane.
+pub type Palette = [Color; 4];
+
+pub const DEFAULT_PALETTE: Palette = [
+    Color::BLACK,
+    Color::WHITE,
+    Color { r: 0xaa, g: 0xaa, b: 0xaa },
+    Color { r: 0x55, g: 0x55, b: 0x55 },
+];
+
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum FrontendKind {
+    Ncurses,
+    #[cfg(feature = "sdl2")]
+    Sdl2,
+    #[cfg(feature = "crossterm")]
+    Crossterm,
+    #[cfg(feature = "pixels-renderer")]
+    Pixels,
+}
+
+impl FrontendKind {
+    // How many screen pixels (or rows of terminal characters) a CHIP-8 pixel takes up when
+    // --scale isn't given
+    pub fn default_scale(&self) -> u8 {
+        match self {
+            FrontendKind::Ncurses => 1,
+            #[cfg(feature = "sdl2")]
+            FrontendKind::Sdl2 => 10,
+            #[cfg(feature = "crossterm")]
+            FrontendKind::Crossterm => 1,
+            #[cfg(feature = "pixels-renderer")]
+            FrontendKind::Pixels => 10,
+        }
+    }
+}
+
+pub trait Frontend {
+    // Draw the rows of display (Chip8::display_buffer) that changed since the last call, which
+    // are the set bits of dirty_rows. Each pixel is an index into the palette.
+    fn render(&mut self, display: &[u8], dirty_rows: u64);
+
+    // Update keys from any pending input events.
+    fn poll_events(&mut self, keys: &mut [Key; 16]) -> Option<Command>;
+
+    // Turn every pixel off.
+    fn clear(&mut self);
+
+    // Start drawing a display of width x height CHIP-8 pixels (when SUPER-CHIP switches between
+    // its low and high resolution modes), turning every pixel off.
+    fn set_resolution(&mut self, width: usize, height: usize);
+
+    // Show a line of status information (for --perf). Terminal frontends put it below the
+    // display; the others write it to stderr.
+    fn show_status(&mut self, text: &str) {
+        eprintln!("{}", text);
+    }
+
+    // Give the terminal back (e.g. for the debugger prompt), and take it over again. Frontends
+    // that draw in their own window don't need to do anything.
+    fn suspend(&mut self) {}
+    fn resume(&mut self) {}
+}
+
+pub fn create(config: &Config) -> Result<Box<dyn Frontend>, String> {
+    let frontend: Box<dyn Frontend> = match config.frontend {
+        FrontendKind::Ncurses => {
+            Box::new(NcursesFrontend::new(config.scale as u32, config.palette, config.phosphor))
+        },
+        #[cfg(feature = "sdl2")]
+        FrontendKind::Sdl2 => {
+            Box::new(Sdl2Frontend::new(config.scale as u32, config.palette, config.fullscreen)?)
+        },
+        #[cfg(feature = "crossterm")]
+        FrontendKind::Crossterm => {
+            let frontend = CrosstermFrontend::new(config.scale as u16, config.palette);
+            Box::new(frontend.map_err(|err| err.to_string())?)
+        },
+        #[cfg(feature = "pixels-renderer")]
+        FrontendKind::Pixels => {
+            Box::new(PixelsFrontend::new(config.scale as u32, config.palette)?)
+        },
+    };
+    Ok(frontend)
+}
+
+// How many (columns, rows) of characters the terminal frontends draw each CHIP-8 pixel with, for
+// a display screen_width pixels wide. Character cells are about twice as tall as they are wide,
+// so pixels are twice as many characters wide as they are tall; in high resolution they're half
+// the size, though never less than a character.
+fn cell_size(scale: u16, screen_width: usize) -> (u16, u16) {
+    if screen_width > SCREEN_WIDTH {
+        (scale, (scale / 2).max(1))
+    } else {
+        (2 * scale, scale)
+    }
+}
+
+// The pixels in the rows of display that are set in dirty_rows, as (x, y, color), for a display
+// screen_width pixels wide
+fn dirty_pixels(display: &[u8], screen_width: usize, dirty_rows: u64)
+    -> impl Iterator<Item = (usize, usize, u8)> + '_
+{
+    display.chunks(screen_width)
+        .enumerate()
+        .filter(move |(y, _)| dirty_rows & (1 << y) != 0)
+        .flat_map(|(y, row)| row.iter().enumerate().map(move |(x, color)| (x, y, *color)))
+}
+
+fn key_index(ch: char) -> Option<usize> {
+    KEYBOARD_MAP.iter().find(|(key_ch, _)| *key_ch == ch).map(|(_, index)| *index)
+}
+
+// How long a key stays down after a press we can't see the release of. While the key is held,
+// the terminal's key repeat keeps pressing it again, but the first repeat only comes after the
+// repeat delay, which is usually about half a second. Any shorter than that and a held key gets
+// released and pressed again before repeating starts, which FX0A takes as two presses and games
+// see as the key flickering. The price is that a quick tap holds the key down for as long.
+const KEY_HOLD_TIME: Duration = Duration::from_millis(500);
+
+// Makes up key releases for terminals that only tell us about presses
+#[derive(Default)]
+struct HeldKeys {
+    pressed_at: [Option<Instant>; 16],
+}
+
+impl HeldKeys {
+    fn press(&mut self, key: usize, keys: &mut [Key; 16]) {
+        self.pressed_at[key] = Some(Instant::now());
+        keys[key] = Key::Down;
+    }
+
+    // Release any keys that haven't been pressed again for a while
+    fn release_expired(&mut self, keys: &mut [Key; 16]) {
+        for (key, pressed_at) in self.pressed_at.iter_mut().enumerate() {
+            if pressed_at.is_some_and(|time| time.elapsed() >= KEY_HOLD_TIME) {
+                *pressed_at = None;
+                keys[key] = Key::Up;
+            }
+        }
+    }
+}
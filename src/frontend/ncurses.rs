@@ -0,0 +1,255 @@
+use super::{cell_size, dirty_pixels, key_index, Color, Command, Frontend, HeldKeys, Palette,
+            NAMED_COLORS};
+use crate::{Key, HI_RES_PIXELS, SCREEN_HEIGHT, SCREEN_WIDTH};
+use std::char;
+
+const CTRL_C: i32 = 3;
+const ESC: i32 = 27;
+// what most terminals send for Backspace, rather than KEY_BACKSPACE
+const DEL: i32 = 127;
+
+// where we define custom colors, past the 16 the terminal usually starts with: one for each
+// palette entry
+const FIRST_COLOR_SLOT: i16 = 16;
+
+// With --phosphor, how many frames a pixel takes to fade out once it's turned off, like the
+// phosphor of a CRT. It goes through the shades of gray that 256-color terminals have (from 232,
+// nearly black, to 255, nearly white), getting darker each frame.
+const PHOSPHOR_FRAMES: u8 = 8;
+const FIRST_GRAY: i16 = 232;
+const GRAY_COUNT: i16 = 24;
+
+// the color pairs for the shades of gray, after the ones for the palette
+const FIRST_FADE_PAIR: i16 = 5;
+
+pub struct NcursesFrontend {
+    scale: u16,
+
+    // how many (columns, rows) of characters each CHIP-8 pixel takes up
+    cell: (i32, i32),
+
+    // how many CHIP-8 pixels wide the display is, and how high, so we know where the status line
+    // goes
+    width: usize,
+    height: i32,
+
+    // what to draw for each color in the palette: a block in that color on the background color
+    // (or a space, for the background itself) if the terminal supports colors
+    characters: [ncurses::chtype; 4],
+
+    // ncurses only tells us about key presses
+    held_keys: HeldKeys,
+
+    // the color of each pixel as of the last render, row by row
+    pixels: Vec<u8>,
+
+    // with --phosphor, what to draw for each pixel that's fading out (see PHOSPHOR_FRAMES), by
+    // how many frames it has left, 0 being the background; and for each pixel, how many frames
+    // it has left. This is empty without --phosphor, or if the terminal hasn't got the colors.
+    fade_characters: Vec<ncurses::chtype>,
+    fade_buffer: Vec<u8>,
+}
+
+impl NcursesFrontend {
+    pub fn new(scale: u32, palette: Palette, phosphor: bool) -> NcursesFrontend {
+        ncurses::initscr();
+        ncurses::raw();
+        ncurses::curs_set(ncurses::CURSOR_VISIBILITY::CURSOR_INVISIBLE);
+        ncurses::nodelay(ncurses::stdscr(), true);
+        ncurses::noecho();
+
+        // deliver function keys as single KEY_* codes, without making a lone ESC wait long to see
+        // if it starts an escape sequence
+        ncurses::keypad(ncurses::stdscr(), true);
+        ncurses::set_escdelay(25);
+
+        let block = ncurses::ACS_BLOCK();
+        let mut characters = [' ' as ncurses::chtype, block, block, block];
+        let mut fade_characters = Vec::new();
+        if ncurses::has_colors() {
+            ncurses::start_color();
+            let numbers: Vec<i16> = palette.iter()
+                .enumerate()
+                .map(|(n, color)| color_number(*color, FIRST_COLOR_SLOT + n as i16))
+                .collect();
+            // color pair 0 can't be changed, so palette entry n uses pair n + 1
+            for (n, character) in characters.iter_mut().enumerate() {
+                let pair = n as i16 + 1;
+                ncurses::init_pair(pair, numbers[n], numbers[0]);
+                *character |= ncurses::COLOR_PAIR(pair);
+            }
+            ncurses::bkgd(characters[0]);
+
+            if phosphor && ncurses::COLORS() >= (FIRST_GRAY + GRAY_COUNT) as i32 {
+                fade_characters.push(characters[0]);
+                for frames in 1..PHOSPHOR_FRAMES as i16 {
+                    let pair = FIRST_FADE_PAIR + frames - 1;
+                    let gray = FIRST_GRAY + (GRAY_COUNT - 1) * frames / PHOSPHOR_FRAMES as i16;
+                    ncurses::init_pair(pair, gray, numbers[0]);
+                    fade_characters.push(block | ncurses::COLOR_PAIR(pair));
+                }
+            }
+        }
+
+        let mut frontend = NcursesFrontend {
+            scale: scale as u16,
+            cell: (0, 0),
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT as i32,
+            characters,
+            held_keys: HeldKeys::default(),
+            pixels: vec![0; HI_RES_PIXELS],
+            fade_buffer: if fade_characters.is_empty() {
+                Vec::new()
+            } else {
+                vec![0; HI_RES_PIXELS]
+            },
+            fade_characters,
+        };
+        frontend.set_cell_size(SCREEN_WIDTH);
+        frontend
+    }
+
+    fn set_cell_size(&mut self, screen_width: usize) {
+        let (columns, rows) = cell_size(self.scale, screen_width);
+        self.cell = (columns as i32, rows as i32);
+    }
+
+    fn draw_pixel(&self, x: usize, y: usize, ch: ncurses::chtype) {
+        let (width, height) = self.cell;
+        for row in 0..height {
+            for column in 0..width {
+                ncurses::mvaddch(y as i32 * height + row, x as i32 * width + column, ch);
+            }
+        }
+    }
+}
+
+impl Drop for NcursesFrontend {
+    fn drop(&mut self) {
+        ncurses::endwin();
+    }
+}
+
+impl Frontend for NcursesFrontend {
+    fn render(&mut self, display: &[u8], dirty_rows: u64) {
+        let fading = !self.fade_buffer.is_empty();
+        for (x, y, color) in dirty_pixels(display, self.width, dirty_rows) {
+            let n = y * self.width + x;
+            // a dirty row is mostly pixels that are as they were, and one that's still off may
+            // be partway through fading out, which redrawing it would cut short
+            if color == self.pixels[n] {
+                continue;
+            }
+            let turned_off = color == 0;
+            self.pixels[n] = color;
+            if fading && turned_off {
+                // it's drawn with the others that are fading out, below
+                self.fade_buffer[n] = PHOSPHOR_FRAMES;
+            } else {
+                if fading {
+                    self.fade_buffer[n] = 0;
+                }
+                self.draw_pixel(x, y, self.characters[color as usize]);
+            }
+        }
+
+        let mut changed = dirty_rows != 0;
+        for n in 0..self.fade_buffer.len() {
+            let frames = self.fade_buffer[n];
+            if frames > 0 {
+                self.fade_buffer[n] = frames - 1;
+                let ch = self.fade_characters[frames as usize - 1];
+                self.draw_pixel(n % self.width, n / self.width, ch);
+                changed = true;
+            }
+        }
+
+        // refreshing costs ncurses a look through the whole screen for changes, which is a waste
+        // of time on the many frames that have none
+        if changed {
+            ncurses::refresh();
+        }
+    }
+
+    fn poll_events(&mut self, keys: &mut [Key; 16]) -> Option<Command> {
+        self.held_keys.release_expired(keys);
+        loop {
+            let ch = ncurses::getch();
+            match ch {
+                ncurses::ERR => return None,
+                CTRL_C => return Some(Command::Quit),
+                ESC => return Some(Command::Pause),
+                ncurses::KEY_F2 => return Some(Command::SaveState),
+                ncurses::KEY_F3 => return Some(Command::LoadState),
+                ncurses::KEY_F5 => return Some(Command::Reset),
+                ncurses::KEY_F12 => return Some(Command::Screenshot),
+                ncurses::KEY_BACKSPACE | DEL => return Some(Command::Rewind),
+                _ => {},
+            }
+
+            let character = char::from_u32(ch as u32);
+            if let Some(key) = character.and_then(key_index) {
+                self.held_keys.press(key, keys);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.pixels.fill(0);
+        self.fade_buffer.fill(0);
+        ncurses::clear();
+        ncurses::refresh();
+    }
+
+    fn set_resolution(&mut self, width: usize, height: usize) {
+        self.set_cell_size(width);
+        self.width = width;
+        self.height = height as i32;
+        self.clear();
+    }
+
+    fn show_status(&mut self, text: &str) {
+        // the line just below the display, in the first foreground color (the background color
+        // would be invisible)
+        ncurses::attrset(self.characters[1] & ncurses::A_COLOR());
+        ncurses::mvaddstr(self.height * self.cell.1, 0, text);
+        ncurses::clrtoeol();
+        ncurses::attrset(ncurses::A_NORMAL());
+        ncurses::refresh();
+    }
+
+    fn suspend(&mut self) {
+        ncurses::def_prog_mode();
+        ncurses::endwin();
+    }
+
+    fn resume(&mut self) {
+        ncurses::reset_prog_mode();
+        ncurses::refresh();
+    }
+}
+
+// Find a curses color number for color, defining a new color in slot if the terminal allows it
+// and falling back on the closest basic color if not
+fn color_number(color: Color, slot: i16) -> i16 {
+    if let Some(n) = NAMED_COLORS.iter().position(|(_, named)| *named == color) {
+        return n as i16;
+    }
+
+    if ncurses::can_change_color() && (slot as i32) < ncurses::COLORS() {
+        // curses color components go from 0 to 1000
+        let component = |value: u8| (value as i32 * 1000 / 0xff) as i16;
+        ncurses::init_color(slot, component(color.r), component(color.g), component(color.b));
+        return slot;
+    }
+
+    let distance = |other: &Color| {
+        let square = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+        square(color.r, other.r) + square(color.g, other.g) + square(color.b, other.b)
+    };
+    NAMED_COLORS.iter()
+        .enumerate()
+        .min_by_key(|(_, (_, named))| distance(named))
+        .map_or(0, |(n, _)| n as i16)
+}
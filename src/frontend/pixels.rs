@@ -0,0 +1,151 @@
+use super::{dirty_pixels, key_index, Command, Frontend, Palette};
+use crate::{Key, SCREEN_HEIGHT, SCREEN_WIDTH};
+use pixels::{Pixels, SurfaceTexture};
+use winit::dpi::LogicalSize;
+use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::event_loop::{ControlFlow, EventLoop};
+use winit::platform::run_return::EventLoopExtRunReturn;
+use winit::window::{Window, WindowBuilder};
+
+pub struct PixelsFrontend {
+    // pixels has to be dropped before the window it draws to
+    pixels: Pixels,
+    window: Window,
+    event_loop: EventLoop<()>,
+
+    // how many CHIP-8 pixels wide the display is
+    width: usize,
+
+    // RGBA
+    palette: [[u8; 4]; 4],
+}
+
+impl PixelsFrontend {
+    pub fn new(scale: u32, palette: Palette) -> Result<PixelsFrontend, String> {
+        let event_loop = EventLoop::new();
+
+        let size = LogicalSize::new(SCREEN_WIDTH as u32 * scale, SCREEN_HEIGHT as u32 * scale);
+        let window = WindowBuilder::new()
+            .with_title("CHIP-8")
+            .with_inner_size(size)
+            .with_min_inner_size(LogicalSize::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32))
+            .build(&event_loop)
+            .map_err(|err| err.to_string())?;
+
+        let window_size = window.inner_size();
+        let surface = SurfaceTexture::new(window_size.width, window_size.height, &window);
+        let pixels = Pixels::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32, surface)
+            .map_err(|err| err.to_string())?;
+
+        Ok(PixelsFrontend {
+            pixels,
+            window,
+            event_loop,
+            width: SCREEN_WIDTH,
+            palette: palette.map(|color| [color.r, color.g, color.b, 0xff]),
+        })
+    }
+}
+
+impl Frontend for PixelsFrontend {
+    fn render(&mut self, display: &[u8], dirty_rows: u64) {
+        if dirty_rows == 0 {
+            return;
+        }
+
+        let frame = self.pixels.frame_mut();
+        for (x, y, color) in dirty_pixels(display, self.width, dirty_rows) {
+            let offset = (y * self.width + x) * 4;
+            frame[offset..offset + 4].copy_from_slice(&self.palette[color as usize]);
+        }
+        self.window.request_redraw();
+    }
+
+    fn poll_events(&mut self, keys: &mut [Key; 16]) -> Option<Command> {
+        let pixels = &mut self.pixels;
+        let mut command = None;
+
+        // Handle whatever events are waiting, then hand control back to the emulator
+        self.event_loop.run_return(|event, _, control_flow| {
+            *control_flow = ControlFlow::Poll;
+            match event {
+                Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => {
+                    command = Some(Command::Quit);
+                },
+                Event::WindowEvent { event: WindowEvent::Focused(false), .. } => {
+                    command = Some(Command::FocusLost);
+                },
+                Event::WindowEvent { event: WindowEvent::Resized(size), .. } => {
+                    let _ = pixels.resize_surface(size.width, size.height);
+                },
+                Event::WindowEvent { event: WindowEvent::KeyboardInput { input, .. }, .. } => {
+                    let KeyboardInput { virtual_keycode, state, .. } = input;
+                    let pressed = state == ElementState::Pressed;
+                    match virtual_keycode {
+                        Some(VirtualKeyCode::Escape) if pressed => command = Some(Command::Pause),
+                        Some(VirtualKeyCode::F2) if pressed => command = Some(Command::SaveState),
+                        Some(VirtualKeyCode::F3) if pressed => command = Some(Command::LoadState),
+                        Some(VirtualKeyCode::F5) if pressed => command = Some(Command::Reset),
+                        Some(VirtualKeyCode::F12) if pressed => {
+                            command = Some(Command::Screenshot);
+                        },
+                        // holding it down goes further back
+                        Some(VirtualKeyCode::Back) if pressed => command = Some(Command::Rewind),
+                        Some(code) => {
+                            if let Some(key) = virtual_key_index(code) {
+                                keys[key] = if pressed {Key::Down} else {Key::Up};
+                            }
+                        },
+                        None => {},
+                    }
+                },
+                Event::RedrawRequested(_) => {
+                    // a failed render just leaves the previous frame up
+                    let _ = pixels.render();
+                },
+                Event::MainEventsCleared => *control_flow = ControlFlow::Exit,
+                _ => {},
+            }
+        });
+
+        command
+    }
+
+    fn set_resolution(&mut self, width: usize, height: usize) {
+        // the window stays the same size, so pixels get smaller as the resolution goes up
+        if self.pixels.resize_buffer(width as u32, height as u32).is_ok() {
+            self.width = width;
+        }
+        self.clear();
+    }
+
+    fn clear(&mut self) {
+        for pixel in self.pixels.frame_mut().chunks_exact_mut(4) {
+            pixel.copy_from_slice(&self.palette[0]);
+        }
+        self.window.request_redraw();
+    }
+}
+
+fn virtual_key_index(code: VirtualKeyCode) -> Option<usize> {
+    let ch = match code {
+        VirtualKeyCode::Key1 => '1',
+        VirtualKeyCode::Key2 => '2',
+        VirtualKeyCode::Key3 => '3',
+        VirtualKeyCode::Key4 => '4',
+        VirtualKeyCode::Q => 'q',
+        VirtualKeyCode::W => 'w',
+        VirtualKeyCode::E => 'e',
+        VirtualKeyCode::R => 'r',
+        VirtualKeyCode::A => 'a',
+        VirtualKeyCode::S => 's',
+        VirtualKeyCode::D => 'd',
+        VirtualKeyCode::F => 'f',
+        VirtualKeyCode::Z => 'z',
+        VirtualKeyCode::X => 'x',
+        VirtualKeyCode::C => 'c',
+        VirtualKeyCode::V => 'v',
+        _ => return None,
+    };
+    key_index(ch)
+}
@@ -0,0 +1,172 @@
+use super::{key_index, Command, Frontend, Palette};
+use crate::{Key, SCREEN_HEIGHT, SCREEN_WIDTH};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::{FullscreenType, Window};
+use sdl2::EventPump;
+use std::char;
+
+pub struct Sdl2Frontend {
+    canvas: Canvas<Window>,
+    event_pump: EventPump,
+    palette: [sdl2::pixels::Color; 4],
+
+    // how many CHIP-8 pixels wide the display is
+    width: usize,
+
+    // whether the next render has to draw the display even if it hasn't changed, because the
+    // window has been drawn over (on switching in or out of fullscreen)
+    redraw: bool,
+}
+
+impl Sdl2Frontend {
+    pub fn new(scale: u32, palette: Palette, fullscreen: bool)
+        -> Result<Sdl2Frontend, String>
+    {
+        let context = sdl2::init()?;
+        let video = context.video()?;
+
+        let width = SCREEN_WIDTH as u32 * scale;
+        let height = SCREEN_HEIGHT as u32 * scale;
+        let window = video.window("CHIP-8", width, height)
+            .position_centered()
+            .build()
+            .map_err(|err| err.to_string())?;
+
+        // we draw in CHIP-8 pixels, and let SDL scale them up to fill the window; it keeps them
+        // square, with black bars at the sides or the top and bottom if the window (or the screen,
+        // in fullscreen) isn't the same shape as the display
+        let mut canvas = window.into_canvas().build().map_err(|err| err.to_string())?;
+        canvas.set_logical_size(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+            .map_err(|err| err.to_string())?;
+        let event_pump = context.event_pump()?;
+
+        let mut frontend = Sdl2Frontend {
+            canvas,
+            event_pump,
+            palette: palette.map(|color| sdl2::pixels::Color::RGB(color.r, color.g, color.b)),
+            width: SCREEN_WIDTH,
+            redraw: false,
+        };
+        if fullscreen {
+            frontend.toggle_fullscreen();
+        }
+        Ok(frontend)
+    }
+
+    fn is_fullscreen(&self) -> bool {
+        self.canvas.window().fullscreen_state() != FullscreenType::Off
+    }
+
+    // Switch between fullscreen and a window. Fullscreen keeps the desktop's resolution, so the
+    // display is scaled up to fill as much of the screen as it can.
+    fn toggle_fullscreen(&mut self) {
+        let fullscreen = if self.is_fullscreen() {
+            FullscreenType::Off
+        } else {
+            FullscreenType::Desktop
+        };
+        // if it can't be done, we carry on as we are
+        let _ = self.canvas.window_mut().set_fullscreen(fullscreen);
+        self.redraw = true;
+    }
+}
+
+impl Frontend for Sdl2Frontend {
+    fn render(&mut self, display: &[u8], dirty_rows: u64) {
+        // presenting the same picture again would only cost us a wait for the display
+        if dirty_rows == 0 && !self.redraw {
+            return;
+        }
+        self.redraw = false;
+
+        // what's in the canvas after presenting it is undefined (it may be an older frame, or
+        // garbage), so each frame is drawn in full: the background, then the pixels of each color
+        self.canvas.set_draw_color(self.palette[0]);
+        self.canvas.clear();
+        let mut rects: [Vec<Rect>; 4] = Default::default();
+        for (n, &color) in display.iter().enumerate() {
+            if color != 0 {
+                let (x, y) = (n % self.width, n / self.width);
+                rects[color as usize].push(Rect::new(x as i32, y as i32, 1, 1));
+            }
+        }
+        for (color, rects) in rects.iter().enumerate().skip(1) {
+            self.canvas.set_draw_color(self.palette[color]);
+            // a failed fill just leaves the pixels out until the next frame
+            let _ = self.canvas.fill_rects(rects);
+        }
+        self.canvas.present();
+    }
+
+    fn poll_events(&mut self, keys: &mut [Key; 16]) -> Option<Command> {
+        // taken one at a time, since some of them change the window; any after a command stay
+        // queued for the next call, so a key released in the same frame isn't left held down
+        while let Some(event) = self.event_pump.poll_event() {
+            match event {
+                Event::Quit { .. } => return Some(Command::Quit),
+                Event::Window { win_event: WindowEvent::FocusLost, .. } => {
+                    return Some(Command::FocusLost);
+                },
+                // in fullscreen, Escape is the way out, rather than pausing
+                Event::KeyDown { keycode: Some(Keycode::Escape), repeat: false, .. }
+                    if self.is_fullscreen() => self.toggle_fullscreen(),
+                Event::KeyDown { keycode: Some(Keycode::Escape), repeat: false, .. } => {
+                    return Some(Command::Pause);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } => {
+                    self.toggle_fullscreen();
+                },
+                Event::KeyDown { keycode: Some(Keycode::F2), repeat: false, .. } => {
+                    return Some(Command::SaveState);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F3), repeat: false, .. } => {
+                    return Some(Command::LoadState);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F5), repeat: false, .. } => {
+                    return Some(Command::Reset);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F12), repeat: false, .. } => {
+                    return Some(Command::Screenshot);
+                },
+                // holding it down goes further back
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => {
+                    return Some(Command::Rewind);
+                },
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {},
+                Event::KeyDown { keycode: Some(keycode), .. } => {
+                    if let Some(key) = keycode_index(keycode) {
+                        keys[key] = Key::Down;
+                    }
+                },
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(key) = keycode_index(keycode) {
+                        keys[key] = Key::Up;
+                    }
+                },
+                _ => {},
+            }
+        }
+        None
+    }
+
+    fn set_resolution(&mut self, width: usize, height: usize) {
+        // the window stays the same size, so pixels get smaller as the resolution goes up
+        let _ = self.canvas.set_logical_size(width as u32, height as u32);
+        self.width = width;
+        self.clear();
+    }
+
+    fn clear(&mut self) {
+        self.canvas.set_draw_color(self.palette[0]);
+        self.canvas.clear();
+        self.canvas.present();
+    }
+}
+
+fn keycode_index(keycode: Keycode) -> Option<usize> {
+    // SDL keycodes for printable keys are the characters themselves
+    char::from_u32(keycode as u32).and_then(key_index)
+}
@@ -0,0 +1,78 @@
+// Lets gamepads press CHIP-8 keys, as set up in the [gamepad] section of the config file. With
+// the "gilrs" feature enabled we read them with gilrs; without it, Gamepads can never be
+// created.
+
+#[cfg(feature = "gilrs")]
+mod imp {
+    use crate::config::GamepadConfig;
+    use crate::Key;
+    use gilrs::{Button, Event, EventType, Gilrs};
+
+    pub struct Gamepads {
+        gilrs: Gilrs,
+        config: GamepadConfig,
+    }
+
+    impl Gamepads {
+        pub fn new(config: &GamepadConfig) -> Option<Gamepads> {
+            Some(Gamepads {
+                gilrs: Gilrs::new().ok()?,
+                config: *config,
+            })
+        }
+
+        // Update keys from any buttons pressed or released since the last call
+        pub fn poll(&mut self, keys: &mut [Key; 16]) {
+            while let Some(Event { id, event, .. }) = self.gilrs.next_event() {
+                if self.config.index.is_some_and(|index| index != usize::from(id)) {
+                    continue;
+                }
+                let (button, state) = match event {
+                    EventType::ButtonPressed(button, _) => (button, Key::Down),
+                    EventType::ButtonReleased(button, _) => (button, Key::Up),
+                    _ => continue,
+                };
+                if let Some(key) = self.key_for(button) {
+                    keys[key as usize] = state;
+                }
+            }
+        }
+
+        fn key_for(&self, button: Button) -> Option<u8> {
+            let config = &self.config;
+            match button {
+                Button::DPadUp => config.dpad_up,
+                Button::DPadDown => config.dpad_down,
+                Button::DPadLeft => config.dpad_left,
+                Button::DPadRight => config.dpad_right,
+                Button::South => config.south,
+                Button::East => config.east,
+                Button::West => config.west,
+                Button::North => config.north,
+                Button::Select => config.select,
+                Button::Start => config.start,
+                Button::LeftTrigger => config.left_trigger,
+                Button::RightTrigger => config.right_trigger,
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "gilrs"))]
+mod imp {
+    use crate::config::GamepadConfig;
+    use crate::Key;
+
+    pub struct Gamepads;
+
+    impl Gamepads {
+        pub fn new(_config: &GamepadConfig) -> Option<Gamepads> {
+            None
+        }
+
+        pub fn poll(&mut self, _keys: &mut [Key; 16]) {}
+    }
+}
+
+pub use imp::Gamepads;
@@ -0,0 +1,87 @@
+// Recording key presses (--record-input) and playing them back (--replay-input). The file is a
+// sequence of 10-byte records, one per key that went up or down: the number of instructions
+// executed before the change (a big-endian u64), the key (0-F), and 1 for down or 0 for up.
+// Since the program only sees keys between instructions, playing them back at the same
+// instruction counts reproduces a session exactly, as long as it wasn't reset or a state loaded
+// along the way. Timers are another matter, unless --cycle-accurate is used for both.
+
+use crate::{Chip8, Chip8Error, Key};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+
+const RECORD_SIZE: usize = 10;
+
+pub struct InputRecorder {
+    path: String,
+    out: BufWriter<File>,
+}
+
+impl InputRecorder {
+    pub fn create(path: &str) -> Result<InputRecorder, Chip8Error> {
+        let file = File::create(path).map_err(|err| input_failed(path, err))?;
+        Ok(InputRecorder {
+            path: path.to_string(),
+            out: BufWriter::new(file),
+        })
+    }
+
+    // Write out any differences between old, the keys as they were, and the Chip8's keys now
+    pub fn record(&mut self, old: &[Key; 16], chip8: &Chip8) -> Result<(), Chip8Error> {
+        for (key, (old, new)) in old.iter().zip(chip8.keys.iter()).enumerate() {
+            if old != new {
+                let mut record = [0; RECORD_SIZE];
+                record[..8].copy_from_slice(&chip8.cycles.to_be_bytes());
+                record[8] = key as u8;
+                record[9] = (*new == Key::Down) as u8;
+                self.out.write_all(&record).map_err(|err| input_failed(&self.path, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Chip8Error> {
+        self.out.flush().map_err(|err| input_failed(&self.path, err))
+    }
+}
+
+pub struct InputReplay {
+    // (instruction count, key, down), in order
+    events: VecDeque<(u64, u8, bool)>,
+}
+
+impl InputReplay {
+    pub fn load(path: &str) -> Result<InputReplay, Chip8Error> {
+        let data = fs::read(path).map_err(|err| input_failed(path, err))?;
+        if data.len() % RECORD_SIZE != 0 {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "not a key recording");
+            return Err(input_failed(path, err));
+        }
+
+        let events = data.chunks(RECORD_SIZE)
+            .map(|record| {
+                let mut cycles = [0; 8];
+                cycles.copy_from_slice(&record[..8]);
+                (u64::from_be_bytes(cycles), record[8] & 0xf, record[9] != 0)
+            })
+            .collect();
+        Ok(InputReplay { events })
+    }
+
+    // Press and release whatever keys were pressed and released by this point in the recording;
+    // called before each instruction
+    pub fn apply(&mut self, chip8: &mut Chip8) {
+        while let Some(&(cycles, key, down)) = self.events.front() {
+            if cycles > chip8.cycles {
+                break;
+            }
+            // keys were masked to 0-F when the recording was read, so this can't fail
+            let _ = chip8.set_key(key, down);
+            self.events.pop_front();
+        }
+    }
+}
+
+fn input_failed(path: &str, err: io::Error) -> Chip8Error {
+    Chip8Error::InputFailed { path: path.to_string(), reason: err.to_string() }
+}
@@ -1,11 +1,81 @@
-use ncurses;
-use rand::Rng;
-use std::char;
-use std::collections::HashMap;
-use std::fmt;
-use std::thread;
-use std::time;
-
+// With the "std" feature (on by default) this is the whole emulator: the run loop, frontends,
+// debugger and tools. Without it only the core is left, the Chip8 itself, which needs nothing but
+// an allocator, so it can be built for targets with no operating system.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+mod api;
+mod audio;
+#[cfg(feature = "tokio")]
+pub mod async_run;
+#[cfg(feature = "std")]
+mod callgraph;
+#[cfg(feature = "std")]
+pub mod assembler;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+mod crash;
+#[cfg(feature = "std")]
+mod debugger;
+#[cfg(feature = "std")]
+pub mod disasm;
+#[cfg(feature = "std")]
+pub mod frontend;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+#[cfg(feature = "std")]
+mod gamepad;
+#[cfg(feature = "std")]
+mod input;
+#[cfg(feature = "std")]
+mod perf;
+#[cfg(feature = "std")]
+mod recording;
+#[cfg(feature = "std")]
+mod rewind;
+#[cfg(feature = "std")]
+mod rpl;
+#[cfg(feature = "std")]
+mod screenshot;
+mod state;
+#[cfg(feature = "std")]
+mod trace;
+#[cfg(feature = "std")]
+mod watch;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use audio::AUDIO_PATTERN_SIZE;
+use core::fmt;
+use serde::Deserialize;
+
+#[cfg(feature = "std")]
+use {
+    api::{ApiRequest, ApiServer},
+    audio::Waveform,
+    callgraph::CallGraph,
+    config::Config,
+    crash::History,
+    debugger::{print_registers, DebugAction, Debugger},
+    frontend::{Command, Frontend},
+    input::{InputRecorder, InputReplay},
+    perf::PerfCounter,
+    recording::GifRecorder,
+    rewind::RewindBuffer,
+    std::fs,
+    std::thread,
+    std::time,
+    trace::Tracer,
+    watch::RomWatcher,
+};
+
+#[cfg(feature = "std")]
 const KEYBOARD_MAP: [(char, usize); 16] = [
     ('1', 1),
     ('2', 2),
@@ -25,12 +95,39 @@ const KEYBOARD_MAP: [(char, usize); 16] = [
     ('v', 0xf),
 ];
 
-const CYCLE_DURATION: u8 = 2;  // in ms
-const INSTRUCTIONS_START: u16 = 0x200;
-const SCREEN_WIDTH: usize = 64;
-const SCREEN_HEIGHT: usize = 32;
-
-const FONT: [u8; 80] = [
+pub const DEFAULT_SPEED: u32 = 500;  // instructions per second
+
+// The display is redrawn, and the timers count down, this many times a second
+const FRAME_RATE: u32 = 60;
+
+// if we fall further behind than this (in seconds), e.g. while stopped in the debugger, don't
+// try to catch up
+#[cfg(feature = "std")]
+const MAX_CATCH_UP: f64 = 0.1;
+
+// where F2 saves the state, and F3 loads it from
+#[cfg(feature = "std")]
+const STATE_PATH: &str = "state.chip8";
+// where programs are loaded, and start running from
+pub const INSTRUCTIONS_START: u16 = 0x200;
+const RAM_SIZE: usize = 4096;
+const XO_CHIP_RAM_SIZE: usize = 0x10000;
+
+// the original only had room for 16 return addresses, as did SUPER-CHIP, and programs don't
+// count on any more
+const STACK_SIZE: usize = 16;
+
+// The display's size in pixels, for frontends and the like; display_buffer has
+// SCREEN_PIXELS bytes in low resolution, and HI_RES_PIXELS in SUPER-CHIP's high resolution mode
+pub const SCREEN_WIDTH: usize = 64;
+pub const SCREEN_HEIGHT: usize = 32;
+pub const SCREEN_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+pub const HI_RES_WIDTH: usize = 128;
+pub const HI_RES_HEIGHT: usize = 64;
+pub const HI_RES_PIXELS: usize = HI_RES_WIDTH * HI_RES_HEIGHT;
+
+// The 4x5 hex digits FX29 points I at, five bytes each, loaded at FONT_START
+pub const FONT: [u8; 80] = [
   0xf0, 0x90, 0x90, 0x90, 0xf0, // 0
   0x20, 0x60, 0x20, 0x20, 0x70, // 1
   0xf0, 0x10, 0xf0, 0x80, 0xf0, // 2
@@ -48,609 +145,4701 @@ const FONT: [u8; 80] = [
   0xf0, 0x80, 0xf0, 0x80, 0xf0, // e
   0xf0, 0x80, 0xf0, 0x80, 0x80  // f
 ];
-const FONT_START: usize = 0x50;
+pub const FONT_START: usize = 0x50;
+
+// SUPER-CHIP's 8x10 digits, for FX30
+const BIG_FONT: [u8; 160] = [
+  0xff, 0xff, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xff, 0xff, // 0
+  0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xff, 0xff, // 1
+  0xff, 0xff, 0x03, 0x03, 0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, // 2
+  0xff, 0xff, 0x03, 0x03, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff, // 3
+  0xc3, 0xc3, 0xc3, 0xc3, 0xff, 0xff, 0x03, 0x03, 0x03, 0x03, // 4
+  0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff, // 5
+  0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, // 6
+  0xff, 0xff, 0x03, 0x03, 0x06, 0x0c, 0x18, 0x18, 0x18, 0x18, // 7
+  0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, // 8
+  0xff, 0xff, 0xc3, 0xc3, 0xff, 0xff, 0x03, 0x03, 0xff, 0xff, // 9
+  0x7e, 0xff, 0xc3, 0xc3, 0xc3, 0xff, 0xff, 0xc3, 0xc3, 0xc3, // a
+  0xfc, 0xfc, 0xc3, 0xc3, 0xfc, 0xfc, 0xc3, 0xc3, 0xfc, 0xfc, // b
+  0x3c, 0xff, 0xc3, 0xc0, 0xc0, 0xc0, 0xc0, 0xc3, 0xff, 0x3c, // c
+  0xfc, 0xfe, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xc3, 0xfe, 0xfc, // d
+  0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, // e
+  0xff, 0xff, 0xc0, 0xc0, 0xff, 0xff, 0xc0, 0xc0, 0xc0, 0xc0  // f
+];
+const BIG_FONT_START: usize = FONT_START + FONT.len();
 
-#[derive(Copy,Clone,PartialEq)]
-enum Pixel {
-    On,
-    Off,
-}
+// XO-CHIP draws in two planes, which are bits 0 and 1 of each pixel
+const ALL_PLANES: u8 = 0b11;
+const PLANE_COUNT: usize = 2;
+
+// the bit for the leftmost pixel in a bit-packed row of the display
+const LEFTMOST: u128 = 1 << 127;
 
+// At XO-CHIP's default pitch, audio patterns play at 4000 bits per second
+const DEFAULT_PITCH: u8 = 64;
+
+// SUPER-CHIP's FX75 and FX85 can save and restore this many registers
+const RPL_FLAG_COUNT: usize = 8;
 
 #[derive(Copy,Clone,PartialEq)]
-enum Key {
+pub enum Key {
     Up,
     Down,
 }
 
-impl Pixel {
-    fn flip(&self) -> Pixel {
-        if *self == Pixel::On {
-            Pixel::Off
-        } else {
-            Pixel::On
-        }
-    }
-}
+// Which interpreter to behave like: its instruction set, how much RAM it has and (through
+// default_quirks) its quirks
+#[derive(Copy,Clone,Debug,Default,PartialEq,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Machine {
+    // the original COSMAC VIP interpreter
+    #[default]
+    Chip8,
 
-pub struct Timer {
-    start_count: u8,
-    start_instant: time::Instant,
+    // CHIP-48, for HP-48 calculators, which has the same instructions with different quirks
+    Chip48,
+
+    // SUPER-CHIP 1.0, which adds a high resolution mode and a few other instructions
+    SuperChip10,
+
+    // SUPER-CHIP 1.1, which adds scrolling to those
+    #[serde(alias = "superchip")]
+    SuperChip11,
+
+    // XO-CHIP, which builds on SUPER-CHIP with a second drawing plane (among other things)
+    XoChip,
 }
 
-impl Timer {
-    fn initialize() -> Timer {
-        Timer {
-            start_count: 0,
-            start_instant: time::Instant::now(),
+impl Machine {
+    // The quirks the machine is best known for, which can then be changed one at a time
+    pub fn default_quirks(&self) -> QuirkFlags {
+        let quirks = match self {
+            Machine::Chip8 => QuirkFlags::chip8(),
+            Machine::Chip48 => QuirkFlags::chip48(),
+            Machine::SuperChip10 | Machine::SuperChip11 => QuirkFlags::super_chip(),
+            Machine::XoChip => QuirkFlags::xo_chip(),
+        };
+        QuirkFlags { machine: *self, ..quirks }
+    }
+
+    // How many bytes of RAM there are to address
+    pub fn max_ram(&self) -> usize {
+        match self {
+            Machine::XoChip => XO_CHIP_RAM_SIZE,
+            _ => RAM_SIZE,
         }
     }
 
-    fn start(&mut self, count: u8) {
-        self.start_count = count;
-        self.start_instant = time::Instant::now();
+    fn has_scroll_instructions(&self) -> bool {
+        matches!(self, Machine::SuperChip11 | Machine::XoChip)
     }
 
-    fn get_value(&self) -> u8 {
-        let now = time::Instant::now();
-        let millis = now.duration_since(self.start_instant).as_millis() as f32;
-        let ticks = millis * 60. / 1000.;
-        self.start_count.saturating_sub(ticks.floor() as u8)
+    fn has_super_chip_instructions(&self) -> bool {
+        matches!(self, Machine::SuperChip10 | Machine::SuperChip11 | Machine::XoChip)
     }
 }
 
-pub struct Chip8 {
-    // 4k of RAM
-    ram: [u8; 4096],
+#[derive(Copy,Clone,Default,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuirkFlags {
+    pub machine: Machine,
 
-    stack: Vec<u16>,
+    // CHIP-48 and SUPER-CHIP reset VF to 0 after AND/OR/XOR
+    pub vf_reset: bool,
 
-    pixels: [[Pixel; SCREEN_HEIGHT]; SCREEN_WIDTH],
+    // CHIP-48 shifts VX in place, while the original CHIP-8 shifts VY into VX
+    pub shift_quirk: bool,
 
-    // registers
-    v: [u8; 16],  // gen purpose
-    i: u16,       // index/address
-    pc: u16,      // program counter
+    // SUPER-CHIP treats BNNN as BXNN, jumping to XNN + VX rather than NNN + V0
+    pub jump_quirk: bool,
 
-    // state of keys
-    keys: [Key; 16],
+    // the original CHIP-8 leaves I pointing just past the last register loaded/stored by
+    // FX55/FX65, while CHIP-48 and SUPER-CHIP leave I unchanged
+    pub load_store_quirk: bool,
 
-    delay_timer: Timer,
-    sound_timer: u8,  // TODO: need to implement this so it counts down
+    // the COSMAC VIP wraps sprites that go past the edge of the screen around to the other side,
+    // rather than clipping them
+    pub wrap_sprites: bool,
 
-    draw_queue: Vec<(u8, u8)>,
+    // the Amiga interpreter sets VF to 1 when FX1E takes I past the end of memory (and to 0 when
+    // it doesn't), which at least one game (Spacefight 2091!) relies on; others leave VF alone
+    pub i_overflow_vf: bool,
 
+    // the COSMAC VIP draws sprites in time with the display, so after DXYN nothing more happens
+    // until the next frame, which slows down games that draw a lot
+    pub draw_wait: bool,
 }
 
-impl Chip8 {
-    fn initialize(rom: Vec<u8>) -> Chip8 {
-        let mut ram = [0; 4096];
-        // TODO: verify rom length < ram length - 0x200
-        for i in 0..rom.len() {
-            let location = i + (INSTRUCTIONS_START as usize);
-            ram[location] = rom[i];
+impl QuirkFlags {
+    // The original COSMAC VIP interpreter
+    pub fn chip8() -> QuirkFlags {
+        QuirkFlags {
+            load_store_quirk: true,
+            wrap_sprites: true,
+            draw_wait: true,
+            ..QuirkFlags::default()
         }
+    }
 
-		for i in 0..FONT.len() {
-            // TODO: generalize this - maybe an array_to_ram method?
-			let location = i + (FONT_START as usize);	
-			ram[location] = FONT[i]; 
-		}
+    // CHIP-48, for HP-48 calculators
+    pub fn chip48() -> QuirkFlags {
+        QuirkFlags {
+            machine: Machine::Chip48,
+            vf_reset: true,
+            shift_quirk: true,
+            jump_quirk: true,
+            ..QuirkFlags::default()
+        }
+    }
 
-        Chip8 {
-            ram,
-            stack: Vec::new(),
-            pixels: [[Pixel::Off; SCREEN_HEIGHT]; SCREEN_WIDTH],
-            v: [0; 16],
-            i: 0,
-            //sp: 0,
-            pc: INSTRUCTIONS_START,
-            keys: [Key::Up; 16],
-            
-            delay_timer: Timer::initialize(),
-            sound_timer: 0,
-            
-            draw_queue: Vec::new(),
+    // SUPER-CHIP 1.1, also for HP-48s
+    pub fn super_chip() -> QuirkFlags {
+        QuirkFlags {
+            machine: Machine::SuperChip11,
+            ..QuirkFlags::chip48()
         }
     }
 
-    pub fn emulate_cycle(&mut self) {
-        let instr = self.fetch();
-        //println!("Instruction: {}", instr);
-        match (instr & 0xf000) >> 12 {
-            0x0 => {
-                match instr & 0x0fff {
-                    0x0e0 => self.clear_screen(instr),
-                    0x0ee => self.ret(instr),
-                    _ => panic!("RCA 1802 program? Instr: {}", instr),
+    // XO-CHIP, as Octo runs it
+    pub fn xo_chip() -> QuirkFlags {
+        QuirkFlags {
+            machine: Machine::XoChip,
+            load_store_quirk: true,
+            wrap_sprites: true,
+            ..QuirkFlags::default()
+        }
+    }
+}
+
+// The quirks (and instruction set) of well known interpreters
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum QuirkPreset {
+    Chip8,
+    Chip48,
+    SuperChip,
+    XoChip,
+}
+
+impl QuirkPreset {
+    pub fn to_quirk_flags(&self) -> QuirkFlags {
+        match self {
+            QuirkPreset::Chip8 => QuirkFlags::chip8(),
+            QuirkPreset::Chip48 => QuirkFlags::chip48(),
+            QuirkPreset::SuperChip => QuirkFlags::super_chip(),
+            QuirkPreset::XoChip => QuirkFlags::xo_chip(),
+        }
+    }
+}
+
+#[derive(Debug,PartialEq)]
+pub enum Chip8Error {
+    UnknownOpcode { opcode: u16, pc: u16 },
+    IllegalOpcode { opcode: u16, pc: u16 },
+    StackUnderflow { pc: u16 },
+    StackOverflow { pc: u16 },
+    MemoryOutOfBounds { addr: usize, i: u16, pc: u16 },
+    Halted { cycles: usize },
+    CycleLimitReached { cycles: u64 },
+    RomTooLarge { size: usize, max: usize },
+    InvalidSaveState,
+    InvalidSettings { reason: &'static str },
+    InvalidRegister { reg: usize },
+    InvalidKey { key: u8 },
+    InvariantViolated { reason: &'static str, pc: u16 },
+    TraceFailed { path: String, reason: String },
+    CallGraphFailed { path: String, reason: String },
+    RecordingFailed { path: String, reason: String },
+    InputFailed { path: String, reason: String },
+    ApiFailed { port: u16, reason: String },
+}
+
+// The --mode that has opcode, for the instructions that only some machines have
+fn mode_with_opcode(opcode: u16) -> Option<&'static str> {
+    match opcode {
+        0x00c0..=0x00cf | 0x00fb..=0x00ff => Some("superchip"),
+        0xf000 | 0xf002 => Some("xochip"),
+        _ => match opcode & 0xf0ff {
+            0xf030 | 0xf075 | 0xf085 => Some("superchip"),
+            0xf001 | 0xf03a => Some("xochip"),
+            _ => None,
+        },
+    }
+}
+
+// Each message says what went wrong and where, and then, as far as we can tell, what to do about
+// it
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::UnknownOpcode { opcode, pc } => {
+                write!(f, "unknown opcode {:#06x} at {:#06x}; ", opcode, pc)?;
+                match mode_with_opcode(*opcode) {
+                    Some(mode) => write!(f, "this ROM may need --mode {}", mode),
+                    None => write!(f, "the program may have jumped into data, or be for another \
+                                       interpreter (--lenient skips unknown opcodes)"),
                 }
             },
-            0x1 => self.jump(instr),
-            0x2 => self.jump_subroutine(instr),
-            0x3 => self.skip_if_equal(instr),
-            0x4 => self.skip_if_unequal(instr),
-            0x6 => self.set_register(instr),
-            0x7 => self.add_const_to_v(instr),
-            0x8 => {
-                match instr & 0x00f {
-                    0x0 => self.reg_set(instr),
-                    0x3 => self.reg_xor(instr),
-                    0x2 => self.reg_and(instr),
-                    0x4 => self.reg_add(instr),
-                    0x5 => self.reg_subtract(instr),
-                    0x6 => self.shift_right(instr),
-                    _ => panic!("unrecognized instruction/leading 8: {}", instr),
+            Chip8Error::IllegalOpcode { opcode, pc } => {
+                write!(f, "illegal operand in opcode {:#06x} at {:#06x}", opcode, pc)?;
+                if let 0xf075 | 0xf085 = opcode & 0xf0ff {
+                    write!(f, "; there are only {} flags, for V0 to V{}", RPL_FLAG_COUNT,
+                           RPL_FLAG_COUNT - 1)?;
                 }
+                Ok(())
             },
-            0x9 => {
-                match instr & 0x000f {
-                    0 => self.skip_if_regs_unequal(instr),
-                    _ => panic!("unrecognized instruction/leading 9: {}", instr),
-                }
+            Chip8Error::StackUnderflow { pc } => {
+                write!(f, "returned from a subroutine at {:#06x} with an empty stack; there was \
+                           no call (2NNN) for the return (00EE) to go back to", pc)
             },
-            0xa => self.set_index(instr),
-            0xc => self.rand(instr),
-            0xd => self.draw_sprite(instr),
-            0xe => {
-                match instr & 0x00ff {
-                    0x9e => self.skip_if_key(instr),
-                    0xa1 => self.skip_if_not_key(instr),
-                    _ => panic!("unrecognized instruction/leading e: {}", instr),
+            Chip8Error::StackOverflow { pc } => {
+                write!(f, "called a subroutine at {:#06x} with all {} return addresses in use; a \
+                           subroutine may be calling itself, or jumping out instead of returning",
+                       pc, STACK_SIZE)
+            },
+            Chip8Error::MemoryOutOfBounds { addr, i, pc } => {
+                write!(f, "memory access out of bounds at {:#06x} (I: {:#06x}, pc: {:#06x}); ",
+                       addr, i, pc)?;
+                if *addr == *pc as usize {
+                    write!(f, "the program ran off the end of memory")
+                } else {
+                    write!(f, "the program may be for XO-CHIP, with more memory (--mode xochip)")
                 }
             },
-            0xf => {
-                match instr & 0x00ff {
-                    0x07 => self.get_delay_timer(instr),
-                    0x0a => self.await_key(instr),
-                    0x15 => self.set_delay_timer(instr),
-                    0x18 => self.set_sound_timer(instr),
-                    0x1e => self.add_reg_to_i(instr),
-                    0x29 => self.set_char_location(instr),
-                    0x33 => self.set_bcd(instr),
-                    0x65 => self.reg_load(instr),
-                    _ => panic!("unrecognized instruction/leading f: {}", instr),
+            Chip8Error::Halted { cycles } => {
+                write!(f, "the program exited (00FD) after {} cycles", cycles)
+            },
+            Chip8Error::CycleLimitReached { cycles } => {
+                write!(f, "stopped at the limit of {} cycles; raise --max-cycles to run longer",
+                       cycles)
+            },
+            Chip8Error::RomTooLarge { size, max } => {
+                write!(f, "ROM is {} bytes, but at most {} bytes fit in memory", size, max)?;
+                if max + (INSTRUCTIONS_START as usize) < XO_CHIP_RAM_SIZE {
+                    write!(f, "; XO-CHIP has room for more (--mode xochip)")?;
                 }
-            }
-            _ => panic!("unrecognized instruction: {}", instr),
+                Ok(())
+            },
+            Chip8Error::InvalidSaveState => {
+                write!(f, "not a save state for this machine; it may have been saved with \
+                           another --mode")
+            },
+            Chip8Error::InvalidSettings { reason } => write!(f, "invalid settings: {}", reason),
+            Chip8Error::InvalidRegister { reg } => {
+                write!(f, "there is no register V{:X}; they go from V0 to VF", reg)
+            },
+            Chip8Error::InvalidKey { key } => {
+                write!(f, "there is no key {:#04x}; they go from 0x0 to 0xf", key)
+            },
+            Chip8Error::InvariantViolated { reason, pc } => {
+                write!(f, "internal error at {:#06x}: {}; this is a bug in the emulator rather \
+                           than the ROM", pc, reason)
+            },
+            Chip8Error::TraceFailed { path, reason } => {
+                write!(f, "couldn't write trace to {}: {}", path, reason)
+            },
+            Chip8Error::CallGraphFailed { path, reason } => {
+                write!(f, "couldn't write call graph to {}: {}", path, reason)
+            },
+            Chip8Error::RecordingFailed { path, reason } => {
+                write!(f, "couldn't record to {}: {}", path, reason)
+            },
+            Chip8Error::InputFailed { path, reason } => {
+                write!(f, "couldn't record or replay keys with {}: {}", path, reason)
+            },
+            Chip8Error::ApiFailed { port, reason } => {
+                write!(f, "couldn't start the API server on port {}: {}; another program may \
+                           be using the port, so try another --api-port", port, reason)
+            },
         }
     }
+}
 
-    fn fetch(&self) -> u16 {
-        self.fetch_at(self.pc)
+#[cfg(feature = "std")]
+impl std::error::Error for Chip8Error {}
+
+// A write to a register or memory location the debugger is watching
+#[derive(Clone)]
+pub enum WatchHit {
+    Register { reg: usize, old: u8, new: u8 },
+    Memory { addr: usize, old: u8, new: u8 },
+}
+
+// Counts down at 60 Hz. The host says how many 60ths of a second have gone by, usually one per
+// frame, so the core never needs a clock of its own.
+#[derive(Clone)]
+pub struct Timer {
+    value: u8,
+}
+
+impl Timer {
+    fn initialize() -> Timer {
+        Timer {
+            value: 0,
+        }
     }
 
-    fn fetch_at(&self, addr: u16) -> u16 {
-        let addr = addr as usize;
-        let first_byte = self.ram[addr] as u16;
-        let second_byte = self.ram[addr + 1] as u16;
-        first_byte << 8 | second_byte
+    fn start(&mut self, count: u8) {
+        self.value = count;
     }
 
-    // Opcodes
-    fn add_const_to_v(&mut self, instr: u16) {
-        let reg = ((instr & 0x0f00) >> 8) as usize;
-        let n = (instr & 0x00ff) as u8;
+    fn get_value(&self) -> u8 {
+        self.value
+    }
 
-        self.v[reg] = self.v[reg].wrapping_add(n);
-        //println!("V{} == {}", reg, self.v[reg]);
-        self.pc = self.pc + 2;
+    fn tick(&mut self, elapsed_hz_ticks: u8) {
+        self.value = self.value.saturating_sub(elapsed_hz_ticks);
     }
+}
 
-    fn add_reg_to_i(&mut self, instr: u16) {
-        let reg = ((instr & 0x0f00) >> 8) as usize;
+// Where RND's random numbers come from. Hosts without the "std" feature should give the builder
+// their own (from a hardware RNG, say), as the fallback knows no entropy and so repeats itself
+// from one run to the next. Cloning a Chip8 clones its source too, so anything Clone will do.
+pub trait RandomSource: Send + CloneRandomSource {
+    fn gen_byte(&mut self) -> u8;
+}
 
-        // I is actually a 12 bit value, so overflow if > 4095
-        self.i = self.i + (self.v[reg] as u16);
-        if self.i > 4095 {
-            self.v[0xf] = 1;
-        } else {
-            self.v[0xf] = 0;
-        }
-        self.i = self.i % 4096;
+// Box<dyn RandomSource> can't be cloned directly, as Clone isn't object safe
+pub trait CloneRandomSource {
+    fn clone_box(&self) -> Box<dyn RandomSource>;
+}
 
-        self.pc = self.pc + 2;
+impl<T: RandomSource + Clone + 'static> CloneRandomSource for T {
+    fn clone_box(&self) -> Box<dyn RandomSource> {
+        Box::new(self.clone())
     }
+}
 
-    fn await_key(&mut self, _instr: u16) {
-        // TODO: implement this
-        self.pc = self.pc + 2;
+impl Clone for Box<dyn RandomSource> {
+    fn clone(&self) -> Box<dyn RandomSource> {
+        self.clone_box()
     }
+}
 
-    fn clear_screen(&mut self, _instr: u16) {
-        // TODO: should add all pixels to self.draw_queue
-        self.pixels = [[Pixel::Off; SCREEN_HEIGHT]; SCREEN_WIDTH];
+// An RNG seeded from the OS once, when the Chip8 is made, which is the default with the "std"
+// feature. (The thread's own RNG would do, but it can't be sent to another thread.)
+#[cfg(feature = "std")]
+#[derive(Clone)]
+struct OsSeededRandom(rand::rngs::StdRng);
 
-        for y in 0..SCREEN_HEIGHT {
-            for x in 0..SCREEN_WIDTH {
-                self.draw_queue.push((x as u8, y as u8));
-            }
-        }
+#[cfg(feature = "std")]
+impl RandomSource for OsSeededRandom {
+    fn gen_byte(&mut self) -> u8 {
+        rand::Rng::gen(&mut self.0)
+    }
+}
 
-        self.pc = self.pc + 2;
+// A xorshift generator, for when there's nothing better or the same numbers are wanted every
+// time (with Chip8Builder::random_seed)
+#[derive(Clone)]
+struct Xorshift(u64);
+
+impl Xorshift {
+    fn new(seed: u64) -> Xorshift {
+        // xorshift never gets anywhere from 0, and does badly for a while from seeds with few bits
+        // set, so the seed is scrambled first (with a step of splitmix64)
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Xorshift((z ^ (z >> 31)).max(1))
     }
+}
 
-    fn draw_sprite(&mut self, instr: u16) {
-        let instr = instr as usize;
+impl RandomSource for Xorshift {
+    fn gen_byte(&mut self) -> u8 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        (x >> 56) as u8
+    }
+}
 
-        let x_reg = (instr & 0x0f00) >> 8;
-        let y_reg = (instr & 0x00f0) >> 4;
+fn default_random_source() -> Box<dyn RandomSource> {
+    #[cfg(feature = "std")]
+    let random = OsSeededRandom(rand::SeedableRng::from_entropy());
+    #[cfg(not(feature = "std"))]
+    let random = Xorshift::new(0);
+    Box::new(random)
+}
 
-        let n = instr & 0x000f;
+// A clone is a snapshot that can be run on from where it was taken (e.g. to rewind), which
+// keeps the same breakpoints, watchpoints and random numbers to come
+#[derive(Clone)]
+pub struct Chip8 {
+    // 4k of RAM, or 64k for XO-CHIP
+    ram: Vec<u8>,
+
+    // return addresses, of which the first sp are in use
+    stack: [u16; STACK_SIZE],
+    sp: u8,
+
+    // one byte per pixel, row by row, with a bit for each plane it's on in (so 0 is off, and
+    // only XO-CHIP ever uses anything but 1 for on). Only the top left of the buffer is used in
+    // low resolution mode, in rows of SCREEN_WIDTH pixels.
+    //
+    // The display is double-buffered: instructions draw on back_pixels, which end_frame copies
+    // to front_pixels once a frame, and only front_pixels is ever shown. So nothing is seen
+    // half-drawn, and a sprite erased and drawn again within a frame doesn't flicker.
+    back_pixels: [u8; HI_RES_PIXELS],
+    hi_res: bool,
+    front_pixels: [u8; HI_RES_PIXELS],
+    front_hi_res: bool,
+
+    // whether every row is dirty at the end of the frame, not just the ones that changed (e.g.
+    // after loading a save state, when the frontend has been cleared)
+    full_redraw: bool,
+
+    // back_pixels again, bit-packed for drawing sprites: a row per u128 for each plane, with
+    // LEFTMOST for x = 0, so drawing a row of a sprite is a shift, an AND and an XOR. Anything
+    // changing pixels has to keep this in step (with set_color, or sync_rows after the fact).
+    rows: [[u128; HI_RES_HEIGHT]; PLANE_COUNT],
+
+    // the planes that drawing, clearing and scrolling affect, as bits like those in pixels
+    active_planes: u8,
+
+    // set by SUPER-CHIP's 00FD, after which nothing more is executed
+    halted: bool,
 
-        let x_start = self.v[x_reg] as usize;
-        let y_start = self.v[y_reg] as usize;
-        //println!("x: {}, y: {}", x_start, y_start);
-        //println!("n: {}", n);
+    // registers
+    v: [u8; 16],  // gen purpose
+    i: u16,       // index/address
+    pc: u16,      // program counter
 
-        let mem_start = self.i as usize;
+    // state of keys, as the frontend last saw them
+    keys: [Key; 16],
 
-        let mut collision = false;
+    // the key FX0A saw go down, once it's waiting for it to come back up
+    awaited_key: Option<usize>,
 
-        for i in 0..n {
-            let mem_location = mem_start + i;
-            let byte = self.ram[mem_location];
-            let y = y_start + i;
-            if y >= SCREEN_HEIGHT {
-                continue;
-            }
-            for j in 0..8 {
-                let x = x_start + j;
-                if x >= SCREEN_WIDTH {
-                    continue;
-                }
-                let needs_flip = byte & (1 << (7-j)) > 0;
-                let pixel = self.pixels[x][y];
-                if needs_flip {
-                    if self.pixels[x][y] == Pixel::On {
-                        collision = true;
-                    }
-                    self.pixels[x][y] = pixel.flip();
-                    self.draw_queue.push((x as u8, y as u8));
-                }
-            }
-        }
+    // with the draw_wait quirk, whether we're waiting for the next frame after drawing
+    waiting_for_frame: bool,
 
-        self.v[0xf] = if collision {1} else {0};
+    delay_timer: Timer,
+    sound_timer: Timer,
 
-        self.pc = self.pc + 2;
-    }
+    // instructions executed since the program (re)started, and with --cycle-accurate, how many
+    // of them make up one tick of the timers. Otherwise the timers tick once per frame of real
+    // time, which drifts if the host can't keep up.
+    cycles: u64,
+    cycles_per_tick: Option<u64>,
 
-    fn get_delay_timer(&mut self, instr: u16) {
-        let reg = (instr & 0x0f00) >> 8;
-        let reg = reg as usize;
+    // how many instructions to execute before stopping, if there's a limit
+    max_cycles: Option<u64>,
 
-        self.v[reg] = self.delay_timer.get_value();
+    // instructions per second, for hosts that leave the pacing to the Chip8 (run_async)
+    speed: u32,
 
-        //println!("Got delay_timer: {}", self.v[reg]);
-        self.pc = self.pc + 2;
-    }
+    buzzer_frequency: f32,
 
-    fn jump(&mut self, instr: u16) {
-        self.pc = instr & 0x0fff;
-    }
+    // XO-CHIP's sound: the pattern loaded by F002, if there's been one, and the pitch register
+    // that FX3A sets, which determines how fast it plays
+    audio_pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
+    pitch: u8,
 
-    fn jump_subroutine(&mut self, instr: u16) {
-        self.stack.push(self.pc);
-        self.pc = instr & 0x0fff;
+    // the rows of the display that have changed since the frontend last drew it, as bit N for
+    // row N (a u64, since there are 64 rows in high resolution)
+    dirty_rows: u64,
 
-        //println!("jumped to subroutine at {}", self.pc);
-    }
+    // where run_async sends the display whenever it changes, once it's been asked for
+    #[cfg(feature = "tokio")]
+    frame_sender: Option<async_run::FrameSender>,
 
-    fn rand(&mut self, instr: u16) {
-        let reg = (instr & 0x0f00) >> 8;
-        let reg = reg as usize;
+    quirks: QuirkFlags,
 
-        let random = rand::thread_rng().gen_range(0, 255) as u8;
-        let val = (instr & 0x00ff) as u8;
-        
-        self.v[reg] = val & random;
+    // skip unknown opcodes with a warning, rather than stopping with an error
+    lenient: bool,
 
-        self.pc = self.pc + 2;
-    }
+    // warn about writes to the ROM's own bytes, which start at INSTRUCTIONS_START
+    warn_self_modify: bool,
+    rom_size: usize,
 
-    fn reg_get_for_math(&mut self, instr: u16) -> (usize, usize) {
-        (
-            ((instr & 0x0f00) >> 8) as usize,
-            ((instr & 0x00f0) >> 4) as usize,
-        )
-    }
+    // where RND gets its numbers
+    random: Box<dyn RandomSource>,
 
-    fn reg_add(&mut self, instr: u16) {
-        let (reg1, reg2) = self.reg_get_for_math(instr);
+    // SUPER-CHIP's persistent flags (HP-48 user flags on the original), saved and restored with
+    // FX75 and FX85. They survive resets and loading a new ROM, as they did on the calculator.
+    rpl_flags: [u8; RPL_FLAG_COUNT],
 
-        let val1 = self.v[reg1];
-        let val2 = self.v[reg2];
+    // addresses where the debugger should stop before executing the instruction there (there's
+    // no debugger without std, but keeping the field saves cfgs wherever a Chip8 is made)
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    breakpoints: BTreeSet<u16>,
 
-        let (sum, overflow) = val1.overflowing_add(val2);
+    // watchpoints: bit N of watched_registers is set if VN is being watched
+    watched_registers: u16,
+    memory_watchpoints: BTreeSet<usize>,
+    watch_hits: Vec<WatchHit>,
+}
+
+impl Chip8 {
+    fn initialize(rom: Vec<u8>, quirks: QuirkFlags) -> Result<Chip8, Chip8Error> {
+        let mut ram = vec![0; quirks.machine.max_ram()];
+        ram[FONT_START..FONT_START + FONT.len()].copy_from_slice(&FONT);
+        ram[BIG_FONT_START..BIG_FONT_START + BIG_FONT.len()].copy_from_slice(&BIG_FONT);
 
-        self.v[0xf] = if overflow {1} else {0};
+        let mut chip8 = Chip8 {
+            ram,
+            stack: [0; STACK_SIZE],
+            sp: 0,
+            back_pixels: [0; HI_RES_PIXELS],
+            hi_res: false,
+            front_pixels: [0; HI_RES_PIXELS],
+            front_hi_res: false,
+            full_redraw: false,
+            rows: [[0; HI_RES_HEIGHT]; PLANE_COUNT],
+            active_planes: 1,
+            halted: false,
+            v: [0; 16],
+            i: 0,
+            pc: INSTRUCTIONS_START,
+            keys: [Key::Up; 16],
+            awaited_key: None,
+            waiting_for_frame: false,
+            
+            delay_timer: Timer::initialize(),
+            sound_timer: Timer::initialize(),
+            cycles: 0,
+            cycles_per_tick: None,
+            max_cycles: None,
+            speed: DEFAULT_SPEED,
+            buzzer_frequency: audio::DEFAULT_BUZZER_FREQUENCY,
+            audio_pattern: None,
+            pitch: DEFAULT_PITCH,
+            
+            dirty_rows: 0,
+            #[cfg(feature = "tokio")]
+            frame_sender: None,
 
-        //println!("V{} was {} and V{} was {}", reg1, self.v[reg1], reg2, self.v[reg2]);
-        //println!("result should be {}", sum);
-        //println!("VF is {}", self.v[0xf]);
+            quirks,
+            lenient: false,
+            warn_self_modify: false,
+            rom_size: 0,
+            random: default_random_source(),
 
-        self.v[reg1] = sum;
+            rpl_flags: [0; RPL_FLAG_COUNT],
 
-        //println!("result is: {}", self.v[reg1]);
+            breakpoints: BTreeSet::new(),
 
-        self.pc = self.pc + 2;
+            watched_registers: 0,
+            memory_watchpoints: BTreeSet::new(),
+            watch_hits: Vec::new(),
+        };
+        chip8.load_rom(rom)?;
+        Ok(chip8)
     }
 
-    fn reg_and(&mut self, instr: u16) {
-        let (reg1, reg2) = self.reg_get_for_math(instr);
+    #[cfg(test)]
+    fn new_for_testing(initial_ram: &[u8]) -> Chip8 {
+        // initial_ram is loaded where the program would normally go
+        Chip8::initialize(initial_ram.to_vec(), QuirkFlags::default()).unwrap()
+    }
 
-        let result = self.v[reg1] & self.v[reg2];
+    // Replace the program in memory with rom, and start it from the beginning
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> Result<(), Chip8Error> {
+        let start = INSTRUCTIONS_START as usize;
+        let max = self.ram.len() - start;
+        if rom.len() > max {
+            return Err(Chip8Error::RomTooLarge { size: rom.len(), max });
+        }
 
-        //println!("V{} was {} and V{} was {}", reg1, self.v[reg1], reg2, self.v[reg2]);
-        //println!("result should be {}", result);
+        // so nothing is left over from a longer ROM
+        for byte in self.ram[start..].iter_mut() {
+            *byte = 0;
+        }
+        self.ram[start..start + rom.len()].copy_from_slice(&rom);
+        self.rom_size = rom.len();
 
-        self.v[reg1] = result as u8;
+        self.reset();
+        Ok(())
+    }
 
-        //println!("result is: {}", self.v[reg1]);
+    // Start the program over, as if it had just been loaded. Memory is left alone, so anything
+    // the program wrote to RAM (including over itself) stays.
+    pub fn reset(&mut self) {
+        self.stack = [0; STACK_SIZE];
+        self.sp = 0;
+        self.back_pixels = [0; HI_RES_PIXELS];
+        self.hi_res = false;
+        self.rows = [[0; HI_RES_HEIGHT]; PLANE_COUNT];
+        self.active_planes = 1;
+        self.halted = false;
+        self.v = [0; 16];
+        self.i = 0;
+        self.pc = INSTRUCTIONS_START;
+        self.clear_keys();
+        self.awaited_key = None;
+        self.waiting_for_frame = false;
+        self.delay_timer = Timer::initialize();
+        self.sound_timer = Timer::initialize();
+        self.cycles = 0;
+        self.audio_pattern = None;
+        self.pitch = DEFAULT_PITCH;
+        self.clear_dirty();
+        self.redraw();
+        self.watch_hits.clear();
+    }
 
-        self.pc = self.pc + 2;
+    // Whether the timers are counted down by emulate_cycle, rather than tick_timers
+    pub fn is_cycle_accurate(&self) -> bool {
+        self.cycles_per_tick.is_some()
     }
 
-    fn reg_load(&mut self, instr: u16) {
-        let count = ((instr & 0x0f00) >> 8) + 1;
-        //println!("count: {}", count);
-        //println!("contents of &I: {} {} {}", self.ram[self.i as usize], self.ram[self.i as usize + 1], self.ram[self.i as usize + 2]);
-        for reg in 0..count {
-            let mem_location = (self.i + reg) as usize;
-            self.v[reg as usize] = self.ram[mem_location];
-            //println!("Stored {} in V{}", self.v[reg as usize], reg);
+    // Count the delay and sound timers down by the number of 60ths of a second that have gone by
+    // since they were last ticked; one at the end of every frame, usually (unless the Chip8 is
+    // cycle accurate, when it happens by itself)
+    pub fn tick_timers(&mut self, elapsed_hz_ticks: u8) {
+        self.delay_timer.tick(elapsed_hz_ticks);
+        self.sound_timer.tick(elapsed_hz_ticks);
+        if elapsed_hz_ticks > 0 {
+            self.waiting_for_frame = false;
         }
+    }
 
-        self.pc = self.pc + 2;
+    pub fn set_buzzer_frequency(&mut self, frequency: f32) {
+        self.buzzer_frequency = frequency;
     }
 
-    fn reg_set(&mut self, instr: u16) {
-        let (reg1, reg2) = self.reg_get_for_math(instr);
-        self.v[reg1] = self.v[reg2];
+    pub fn emulate_cycle(&mut self) -> Result<(), Chip8Error> {
+        if self.halted {
+            return Ok(());
+        }
+        if self.max_cycles.is_some_and(|max| self.cycles >= max) {
+            return Err(Chip8Error::CycleLimitReached { cycles: self.cycles });
+        }
 
-        self.pc = self.pc + 2;
-    }
+        self.cycles += 1;
+        if let Some(cycles_per_tick) = self.cycles_per_tick {
+            if self.cycles.is_multiple_of(cycles_per_tick) {
+                self.tick_timers(1);
+            }
+        }
+        if self.waiting_for_frame {
+            return Ok(());
+        }
 
-    fn reg_subtract(&mut self, instr: u16) {
-        let (reg1, reg2) = self.reg_get_for_math(instr);
+        // a jump can take PC anywhere, even somewhere with no room for an instruction
+        let pc = self.pc;
+        let instr = self.fetch()
+            .ok_or(Chip8Error::MemoryOutOfBounds { addr: pc as usize, i: self.i, pc })?;
+        match self.execute(instr) {
+            Err(Chip8Error::UnknownOpcode { opcode, pc }) if self.lenient => {
+                tracing::warn!(opcode = %format_args!("{:#06x}", opcode),
+                               pc = %format_args!("{:#06x}", pc), "skipping unknown opcode");
+                self.advance_pc(2);
+            },
+            result => result?,
+        }
 
-        let val1 = self.v[reg1];
-        let val2 = self.v[reg2];
+        // so tests (and debug builds generally) catch an opcode leaving things in a mess
+        if cfg!(debug_assertions) {
+            self.check_invariants()?;
+            self.check_pc(pc, instr)?;
+        }
+        Ok(())
+    }
 
-        let (sum, overflow) = val1.overflowing_sub(val2);
+    // Run opcode as if it were the instruction at PC, e.g. for tests. The two bytes at PC are put
+    // back as they were afterwards, even if the instruction itself wrote there (with FX55, say).
+    // Instructions longer than two bytes, like XO-CHIP's F000 NNNN, take the rest from memory as
+    // usual.
+    pub fn inject_opcode(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        let addr = self.pc as usize;
+        if addr + 2 > self.ram.len() {
+            return Err(Chip8Error::MemoryOutOfBounds { addr, i: self.i, pc: self.pc });
+        }
 
-        self.v[0xf] = if overflow {0} else {1};
+        let old = [self.ram[addr], self.ram[addr + 1]];
+        self.ram[addr..addr + 2].copy_from_slice(&opcode.to_be_bytes());
+        let result = self.emulate_cycle();
+        self.ram[addr..addr + 2].copy_from_slice(&old);
+        result
+    }
 
-        //panic!("subtracting {} from {} with result {}, overflow {}", val2, val1, sum, overflow);
+    // Everything a host with no run loop of its own (a microcontroller, say) needs in one call:
+    // set the keys from key_state (bit N is key N), count the timers down by the number of 60 Hz
+    // ticks since the last step (ending the frame, if there were any), run one instruction and
+    // return the display. Nothing is left dirty, since the host redraws the whole display.
+    pub fn step(&mut self, key_state: u16, elapsed_ticks: u8) -> Result<&[u8], Chip8Error> {
+        for (n, key) in self.keys.iter_mut().enumerate() {
+            *key = if key_state & (1 << n) != 0 {Key::Down} else {Key::Up};
+        }
+        if !self.is_cycle_accurate() {
+            self.tick_timers(elapsed_ticks);
+        }
+        if elapsed_ticks > 0 {
+            self.end_frame();
+        }
 
-        //println!("V{} was {} and V{} was {}", reg1, self.v[reg1], reg2, self.v[reg2]);
-        //println!("result should be {}", sum);
-        //println!("VF is {}", self.v[0xf]);
+        self.emulate_cycle()?;
+        self.clear_dirty();
+        Ok(self.display_buffer())
+    }
 
-        self.v[reg1] = sum;
+    // Emulate up to n cycles, returning how many were run. Halting (with 00FD) before all n of them
+    // is an error, saying how many there were.
+    pub fn step_n(&mut self, n: usize) -> Result<usize, Chip8Error> {
+        for cycles in 0..n {
+            if self.halted {
+                return Err(Chip8Error::Halted { cycles });
+            }
+            self.emulate_cycle()?;
+        }
+        Ok(n)
+    }
 
-        //println!("result is: {}", self.v[reg1]);
+    // Emulate as many cycles as a cpu_hz machine would in duration, returning how many were run.
+    // This doesn't wait for real time to catch up, but it does give up once duration has gone by,
+    // if the cycles were slower than that. It stops early at 00FD. Unless the Chip8 is cycle
+    // accurate, the timers tick (and a frame ends) every cpu_hz / 60 cycles.
+    #[cfg(feature = "std")]
+    pub fn run_for_duration(&mut self, duration: time::Duration, cpu_hz: u32)
+        -> Result<u64, Chip8Error>
+    {
+        let start = time::Instant::now();
+        let budget = (duration.as_secs_f64() * cpu_hz as f64) as u64;
+        let cycles_per_tick = (cpu_hz / FRAME_RATE).max(1) as u64;
+        let mut cycles = 0;
+        while cycles < budget && !self.halted && start.elapsed() < duration {
+            self.emulate_cycle()?;
+            cycles += 1;
+            if !self.is_cycle_accurate() && self.cycles.is_multiple_of(cycles_per_tick) {
+                self.tick_timers(1);
+                self.end_frame();
+            }
+        }
+        Ok(cycles)
+    }
 
-        self.pc = self.pc + 2;
+    // Whether the machine is in a state that no instruction should ever leave it in. It's cheap
+    // enough for emulate_cycle to check after every instruction in debug builds, along with
+    // check_pc. I wraps around the end of memory, so it always points into it, but PC isn't
+    // checked here: a jump can legitimately send it anywhere (even past the end of memory, which
+    // emulate_cycle reports when it gets there). The timers can't go past 255 anyway.
+    pub fn check_invariants(&self) -> Result<(), Chip8Error> {
+        let broken = |reason| Err(Chip8Error::InvariantViolated { reason, pc: self.pc });
+        if INSTRUCTIONS_START as usize + self.rom_size > self.ram.len() {
+            return broken("the ROM is larger than memory");
+        }
+        if self.sp as usize > STACK_SIZE {
+            return broken("the stack pointer is past the end of the stack");
+        }
+        if self.stack().iter().any(|addr| *addr as usize + 2 > self.ram.len()) {
+            return broken("a return address is past the end of memory");
+        }
+        if self.active_planes >= 1 << PLANE_COUNT {
+            return broken("a plane is selected that doesn't exist");
+        }
+        if self.hi_res && !self.quirks.machine.has_super_chip_instructions() {
+            return broken("high resolution is on, but the machine doesn't have it");
+        }
+        if self.awaited_key.is_some_and(|key| key >= self.keys.len()) {
+            return broken("FX0A is waiting for a key that doesn't exist");
+        }
+        if self.i as usize >= self.ram.len() {
+            return broken("I is past the end of memory");
+        }
+        Ok(())
     }
 
-    fn reg_xor(&mut self, instr: u16) {
-        let (reg1, reg2) = self.reg_get_for_math(instr);
-        self.v[reg1] = self.v[reg1] ^ self.v[reg2];
-        self.pc = self.pc + 2;
+    // What XO-CHIP's buzzer should play instead of a square wave, if anything (working out the
+    // rate needs powf, which only std has)
+    #[cfg(feature = "std")]
+    pub fn waveform(&self) -> Option<Waveform> {
+        // every 48 steps of pitch is an octave
+        let rate = 4000. * 2f32.powf((self.pitch as f32 - DEFAULT_PITCH as f32) / 48.);
+        self.audio_pattern.map(|pattern| (pattern, rate))
     }
 
-    fn ret(&mut self, _instr: u16) {
-        let addr = self.stack.pop().unwrap_or_else(|| {
-            panic!("Error popping stack");
-        });
+    // Whether the program has exited with 00FD
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
 
-        self.pc = addr + 2;
-        //println!("returned from subroutine to {}", self.pc);
+    // Whether the next emulate_cycle won't run an instruction, because the last DXYN is waiting
+    // for the frame to end (with the draw_wait quirk). Tracing and the like should leave that
+    // cycle out, since the instruction at PC hasn't run yet.
+    pub fn is_waiting_for_frame(&self) -> bool {
+        // a cycle accurate Chip8 ends the frame itself, at the start of a cycle
+        let ticks = self.cycles_per_tick.is_some_and(|n| (self.cycles + 1).is_multiple_of(n));
+        self.waiting_for_frame && !ticks
     }
 
-    fn set_bcd(&mut self, instr: u16) {
-        let reg = ((instr & 0x0f00) >> 8) as usize;
-        let val = self.v[reg];
+    // The display, row by row at the current resolution with a byte per pixel: 0 for off, and
+    // otherwise a bit for each plane it's on in (so 1, unless it's XO-CHIP). Renderers can hand
+    // this straight to a texture.
+    pub fn display_buffer(&self) -> &[u8] {
+        let (width, height) = self.display_size();
+        &self.front_pixels[..width * height]
+    }
 
-        let hundreds = val / 100;
-        let tens = (val - 100 * hundreds) / 10;
-        let ones = val - 100 * hundreds - 10 * tens;
-        //println!("val: {}; hundreds: {}, tens: {}, ones: {}", val, hundreds, tens, ones);
+    // The resolution of display_buffer, as (width, height), which is what screen_size was at
+    // the end of the last frame
+    pub fn display_size(&self) -> (usize, usize) {
+        if self.front_hi_res {
+            (HI_RES_WIDTH, HI_RES_HEIGHT)
+        } else {
+            (SCREEN_WIDTH, SCREEN_HEIGHT)
+        }
+    }
 
-        let start = self.i as usize;
-        self.ram[start] = hundreds;
-        self.ram[start + 1] = tens;
-        self.ram[start + 2] = ones;
+    // Finish a frame: show what's been drawn since the last one in display_buffer, and mark the
+    // rows that have changed as dirty. The run loop does this 60 times a second.
+    pub fn end_frame(&mut self) {
+        let (width, height) = self.screen_size();
+        let full_redraw = self.full_redraw || self.front_hi_res != self.hi_res;
+        for y in 0..height {
+            let row = y * width..(y + 1) * width;
+            if full_redraw || self.back_pixels[row.clone()] != self.front_pixels[row] {
+                self.dirty_rows |= 1 << y;
+            }
+        }
 
-        self.pc = self.pc + 2;
+        // a copy rather than a swap, as the next frame draws over this one
+        self.front_pixels = self.back_pixels;
+        self.front_hi_res = self.hi_res;
+        self.full_redraw = false;
     }
 
-    fn set_char_location(&mut self, instr: u16) {
-        let reg = ((instr & 0x0f00) >> 8) as usize;
-        let ch = self.v[reg] as usize;
-        self.i = (FONT_START + ch * 5) as u16;
+    // The rows of display_buffer that have changed since clear_dirty was last called, as bit N
+    // for row N
+    pub fn dirty_rows(&self) -> u64 {
+        self.dirty_rows
+    }
 
-        self.pc = self.pc + 2;
+    // Call once the dirty rows have been drawn
+    pub fn clear_dirty(&mut self) {
+        self.dirty_rows = 0;
     }
 
-    fn set_delay_timer(&mut self, instr: u16) {
-        let reg = (instr & 0x0f00) >> 8;
-        let reg = reg as usize;
+    // Whether the pixel at (x, y) is on, as drawn so far (which may be ahead of the display until
+    // the end of the frame). It's never on outside the screen.
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        let (width, height) = self.screen_size();
+        x < width && y < height && self.back_pixels[y * width + x] != 0
+    }
 
-        self.delay_timer.start(self.v[reg]);
+    // Turn the pixel at (x, y) on or off in the selected planes, if it's on the screen
+    pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
+        let (width, height) = self.screen_size();
+        if x < width && y < height {
+            let color = self.color(x, y);
+            let color = if on {color | self.active_planes} else {color & !self.active_planes};
+            self.set_color(x, y, color);
+        }
+    }
 
-        //println!("set delay_timer to {} based on register {}", self.v[reg], reg);
-        
-        self.pc = self.pc + 2;
+    // The display as one line per row of pixels, '#' for on and ' ' for off, e.g. for tests and
+    // logs. Anything wanting the display as text should use this (or Display), so they all agree.
+    pub fn get_display_string(&self) -> String {
+        let (width, height) = self.screen_size();
+        let lines: Vec<String> = (0..height)
+            .map(|y| (0..width).map(|x| if self.get_pixel(x, y) {'#'} else {' '}).collect())
+            .collect();
+        lines.join("\n")
     }
 
-    fn set_index(&mut self, instr: u16) {
-        // set the "I" register (index/address register)
-        let value = instr & 0x0fff;
-        self.i = value;
+    // The current resolution, as (width, height)
+    pub fn screen_size(&self) -> (usize, usize) {
+        if self.hi_res {
+            (HI_RES_WIDTH, HI_RES_HEIGHT)
+        } else {
+            (SCREEN_WIDTH, SCREEN_HEIGHT)
+        }
+    }
 
-        //println!("set I to {}", self.i);
+    // How many emulate_cycle calls there have been since the program was loaded or reset, not
+    // counting ones while halted. Cycles spent waiting for the frame (with draw_wait) count too,
+    // since the timers and replays go by this, so it can be more than the instructions executed.
+    pub fn instruction_count(&self) -> u64 {
+        self.cycles
+    }
 
-        self.pc = self.pc + 2;
+    // The registers and memory, for debuggers and the like. Like indexing, get_register and
+    // get_ram panic if there's no such register or address; the setters return an error instead.
+    // Changes made through them don't count as the program's, so watchpoints don't see them.
+    pub fn get_pc(&self) -> u16 {
+        self.pc
     }
 
-    fn set_register(&mut self, instr: u16) {
-        // set a general purpose register (one of the "V's")
-        let reg = (instr & 0x0f00) >> 8;
-        let reg = reg as usize;
-        let value = (instr & 0x00ff) as u8;
+    pub fn get_register(&self, n: usize) -> u8 {
+        self.v[n]
+    }
 
-        self.v[reg] = value;
+    pub fn get_i(&self) -> u16 {
+        self.i
+    }
 
-        //println!("Set V{} to {}", reg, self.v[reg]);
+    // Where each of the subroutines being run was called from, the latest last
+    pub fn get_stack(&self) -> &[u16] {
+        self.stack()
+    }
 
-        self.pc = self.pc + 2;
+    pub fn get_ram(&self, addr: usize) -> u8 {
+        self.ram[addr]
     }
 
-    fn set_sound_timer(&mut self, instr: u16) {
-        let reg = ((instr & 0x0f00) >> 8) as usize;
-        self.sound_timer = self.v[reg];
-        //println!("setting sound_timer to {}", self.sound_timer);
+    pub fn set_register(&mut self, n: usize, v: u8) -> Result<(), Chip8Error> {
+        let reg = self.v.get_mut(n).ok_or(Chip8Error::InvalidRegister { reg: n })?;
+        *reg = v;
+        Ok(())
+    }
 
-        self.pc = self.pc + 2;
+    // I has to point somewhere in memory
+    pub fn set_i(&mut self, v: u16) -> Result<(), Chip8Error> {
+        if v as usize >= self.ram.len() {
+            return Err(Chip8Error::MemoryOutOfBounds { addr: v as usize, i: self.i, pc: self.pc });
+        }
+        self.i = v;
+        Ok(())
     }
 
-    fn shift_right(&mut self, instr: u16) {
-        let reg = ((instr & 0x0f00) >> 8) as usize;
-        let val = self.v[reg];
+    pub fn poke(&mut self, addr: usize, v: u8) -> Result<(), Chip8Error> {
+        let (i, pc) = (self.i, self.pc);
+        let byte = self.ram.get_mut(addr).ok_or(Chip8Error::MemoryOutOfBounds { addr, i, pc })?;
+        *byte = v;
+        Ok(())
+    }
 
-        self.v[0xf] = 1 & val;
-        self.v[reg] = val >> 1;
+    // Whether key is held; there are only keys 0-F, so any other key never is
+    pub fn is_key_down(&self, key: u8) -> bool {
+        self.keys.get(key as usize) == Some(&Key::Down)
+    }
 
-        self.pc = self.pc + 2;
+    // The keys that are held, as a bit for each (bit 0 for key 0 and so on), e.g. for chords
+    pub fn active_keys(&self) -> u16 {
+        self.keys.iter()
+            .enumerate()
+            .filter(|(_, key)| **key == Key::Down)
+            .fold(0, |keys, (n, _)| keys | 1 << n)
     }
 
-    fn skip_if_equal(&mut self, instr: u16) {
-        let reg = ((instr & 0x0f00) >> 8) as usize;
-        let n = (instr & 0x00ff) as u8;
+    // Release all the keys, e.g. when the window loses focus and their releases won't reach us
+    pub fn clear_keys(&mut self) {
+        self.keys = [Key::Up; 16];
+    }
 
-        let incr = if self.v[reg] == n {4} else {2};
-        //println!("Incrementing by {}", incr);
-        self.pc = self.pc + incr;
+    // Press or release one of the keys 0-F, e.g. for bindings to other languages or tests
+    pub fn set_key(&mut self, key: u8, down: bool) -> Result<(), Chip8Error> {
+        let key_state = self.keys.get_mut(key as usize).ok_or(Chip8Error::InvalidKey { key })?;
+        *key_state = if down {Key::Down} else {Key::Up};
+        Ok(())
     }
 
-    fn skip_if_regs_unequal(&mut self, instr: u16) {
-       let (reg1, reg2) = self.reg_get_for_math(instr); 
-       let incr = if self.v[reg1] != self.v[reg2] {4} else {2};
-       self.pc = self.pc + incr;
+    // Move I on by amount bytes, wrapping around the end of RAM as FX1E does, since it can only
+    // address RAM. Otherwise loading or storing the last bytes of memory leaves it past the end.
+    fn advance_i(&mut self, amount: u16) {
+        self.i = ((self.i as usize + amount as usize) % self.ram.len()) as u16;
     }
 
-    fn skip_if_unequal(&mut self, instr: u16) {
+    // Move PC on by amount bytes. With XO-CHIP's 64K of RAM PC can be at the very top of memory,
+    // and like the address bus it wraps around to 0 rather than overflowing.
+    fn advance_pc(&mut self, amount: u16) {
+        self.pc = self.pc.wrapping_add(amount);
+    }
+
+    // Check PC has moved on from old_pc properly after instr: anywhere after a jump, call or
+    // return, but otherwise on to the next instruction (or the one after, for a skip), except
+    // that it stays put while the machine is halted or waiting for a key
+    fn check_pc(&self, old_pc: u16, instr: u16) -> Result<(), Chip8Error> {
+        let jumped = matches!(instr & 0xf000, 0x1000 | 0x2000 | 0xb000) || instr == 0x00ee;
+        let waiting = self.halted || instr & 0xf0ff == 0xf00a;
+        match self.pc.wrapping_sub(old_pc) {
+            _ if jumped => Ok(()),
+            0 if waiting => Ok(()),
+            2 | 4 | 6 => Ok(()),
+            _ => Err(Chip8Error::InvariantViolated {
+                reason: "PC didn't move on to the next instruction",
+                pc: self.pc,
+            }),
+        }
+    }
+
+    // The planes the pixel is on in, as a color number from 0 (off) to 3
+    fn color(&self, x: usize, y: usize) -> u8 {
+        let (width, _) = self.screen_size();
+        self.back_pixels[y * width + x]
+    }
+
+    // Decode instr and run the opcode handler for it
+    fn execute(&mut self, instr: u16) -> Result<(), Chip8Error> {
+        let unknown = Chip8Error::UnknownOpcode { opcode: instr, pc: self.pc };
+        tracing::debug!(pc = %format_args!("{:#06x}", self.pc),
+                        instr = %format_args!("{:#06x}", instr), "executing opcode");
+        match (instr & 0xf000) >> 12 {
+            0x0 => {
+                let super_chip = self.quirks.machine.has_super_chip_instructions();
+                let scroll = self.quirks.machine.has_scroll_instructions();
+                match instr & 0x0fff {
+                    0x0c0..=0x0cf if scroll => self.scroll_down(instr),
+                    0x0e0 => self.clear_screen(instr),
+                    0x0ee => self.ret(instr)?,
+                    0x0fb if scroll => self.scroll_right(instr),
+                    0x0fc if scroll => self.scroll_left(instr),
+                    0x0fd if super_chip => self.halt(instr),
+                    0x0fe if super_chip => self.low_resolution(instr),
+                    0x0ff if super_chip => self.high_resolution(instr),
+                    _ => return Err(unknown),
+                }
+            },
+            0x1 => self.jump(instr),
+            0x2 => self.jump_subroutine(instr)?,
+            0x3 => self.skip_if_equal(instr),
+            0x4 => self.skip_if_unequal(instr),
+            0x5 => {
+                match instr & 0x000f {
+                    0 => self.skip_if_regs_equal(instr),
+                    _ => return Err(unknown),
+                }
+            },
+            0x6 => self.load_const(instr),
+            0x7 => self.add_const_to_v(instr),
+            0x8 => {
+                match instr & 0x00f {
+                    0x0 => self.reg_set(instr),
+                    0x1 => self.reg_or(instr),
+                    0x3 => self.reg_xor(instr),
+                    0x2 => self.reg_and(instr),
+                    0x4 => self.reg_add(instr),
+                    0x5 => self.reg_subtract(instr),
+                    0x6 => self.shift_right(instr),
+                    0x7 => self.reg_subtract_n(instr),
+                    0xe => self.shift_left(instr),
+                    _ => return Err(unknown),
+                }
+            },
+            0x9 => {
+                match instr & 0x000f {
+                    0 => self.skip_if_regs_unequal(instr),
+                    _ => return Err(unknown),
+                }
+            },
+            0xa => self.set_index(instr),
+            0xb => self.jump_with_offset(instr),
+            0xc => self.rand(instr),
+            0xd => self.draw_sprite(instr)?,
+            0xe => {
+                match instr & 0x00ff {
+                    0x9e => self.skip_if_key(instr),
+                    0xa1 => self.skip_if_not_key(instr),
+                    _ => return Err(unknown),
+                }
+            },
+            0xf => {
+                let super_chip = self.quirks.machine.has_super_chip_instructions();
+                let xo_chip = self.quirks.machine == Machine::XoChip;
+                match instr & 0x00ff {
+                    0x00 if xo_chip && instr == 0xf000 => self.set_long_index(instr)?,
+                    0x01 if xo_chip => self.select_planes(instr),
+                    0x02 if xo_chip && instr == 0xf002 => self.load_audio_pattern(instr)?,
+                    0x07 => self.get_delay_timer(instr),
+                    0x0a => self.wait_for_key(instr),
+                    0x15 => self.set_delay_timer(instr),
+                    0x18 => self.set_sound_timer(instr),
+                    0x1e => self.add_reg_to_i(instr),
+                    0x29 => self.set_char_location(instr),
+                    0x30 if super_chip => self.set_big_char_location(instr),
+                    0x33 => self.set_bcd(instr)?,
+                    0x3a if xo_chip => self.set_pitch(instr),
+                    0x55 => self.reg_store(instr)?,
+                    0x65 => self.reg_load(instr)?,
+                    0x75 if super_chip => self.store_rpl(instr)?,
+                    0x85 if super_chip => self.load_rpl(instr)?,
+                    _ => return Err(unknown),
+                }
+            }
+            _ => return Err(unknown),
+        }
+
+        Ok(())
+    }
+
+    // The instruction at PC, unless there's no room for one there (which the tracing and so on
+    // that look before each cycle leave to emulate_cycle to report)
+    fn fetch(&self) -> Option<u16> {
+        self.fetch_at(self.pc)
+    }
+
+    fn fetch_at(&self, addr: u16) -> Option<u16> {
+        let addr = addr as usize;
+        let bytes = self.ram.get(addr..addr + 2)?;
+        Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    // SUPER-CHIP scrolls half as many pixels left or right in low resolution, so the picture
+    // moves the same distance on the screen either way
+    fn horizontal_scroll_amount(&self) -> isize {
+        if self.hi_res {4} else {2}
+    }
+
+    // Have the frontend draw every row at the end of the frame, e.g. when it's been cleared
+    fn redraw(&mut self) {
+        self.full_redraw = true;
+    }
+
+    // XO-CHIP only scrolls the selected planes, so put the others back the way they were in old
+    fn restore_unselected_planes(&mut self, old: &[u8]) {
+        for (pixel, old) in self.back_pixels.iter_mut().zip(old.iter()) {
+            *pixel = (*pixel & self.active_planes) | (*old & !self.active_planes);
+        }
+    }
+
+    fn set_color(&mut self, x: usize, y: usize, color: u8) {
+        let (width, _) = self.screen_size();
+        self.back_pixels[y * width + x] = color;
+        for (plane, rows) in self.rows.iter_mut().enumerate() {
+            if color & (1 << plane) != 0 {
+                rows[y] |= LEFTMOST >> x;
+            } else {
+                rows[y] &= !(LEFTMOST >> x);
+            }
+        }
+    }
+
+    // The bits of a bit-packed row that a row of a sprite covers when drawn at x, clipped or
+    // wrapped at the edge of the screen
+    fn sprite_mask(&self, row: u16, sprite_width: usize, x: usize) -> u128 {
+        let (width, _) = self.screen_size();
+        let sprite = (row as u128) << (128 - sprite_width);
+        let screen = !0 << (128 - width);
+        if self.quirks.wrap_sprites {
+            let x = x % width;
+            // whatever goes past the right edge comes back at the left
+            let wrapped = sprite.checked_shl((width - x) as u32).unwrap_or(0);
+            (sprite >> x | wrapped) & screen
+        } else if x < width {
+            (sprite >> x) & screen
+        } else {
+            0
+        }
+    }
+
+    // Rebuild rows from pixels, after changing lots of pixels at once
+    fn sync_rows(&mut self) {
+        let (width, height) = self.screen_size();
+        self.rows = [[0; HI_RES_HEIGHT]; PLANE_COUNT];
+        for y in 0..height {
+            for x in 0..width {
+                let color = self.back_pixels[y * width + x];
+                for (plane, rows) in self.rows.iter_mut().enumerate() {
+                    if color & (1 << plane) != 0 {
+                        rows[y] |= LEFTMOST >> x;
+                    }
+                }
+            }
+        }
+    }
+
+    // All writes to V registers and RAM go through set_v and write_ram, so we can tell the
+    // debugger about writes to any it's watching.
+    fn set_v(&mut self, reg: usize, val: u8) {
+        if self.watched_registers & (1 << reg) != 0 {
+            self.watch_hits.push(WatchHit::Register { reg, old: self.v[reg], new: val });
+        }
+        self.v[reg] = val;
+    }
+
+    // How far a skip instruction moves the PC when it skips. XO-CHIP's F000 NNNN is twice as long
+    // as other instructions, so skipping it means skipping both halves.
+    fn skip_amount(&self) -> u16 {
+        let next = self.pc.wrapping_add(2);
+        if self.quirks.machine == Machine::XoChip && self.fetch_at(next) == Some(0xf000) {
+            6
+        } else {
+            4
+        }
+    }
+
+    // The return addresses in use, the most recent last
+    fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    fn write_ram(&mut self, addr: usize, val: u8) {
+        if self.memory_watchpoints.contains(&addr) {
+            self.watch_hits.push(WatchHit::Memory { addr, old: self.ram[addr], new: val });
+        }
+        let rom_start = INSTRUCTIONS_START as usize;
+        if self.warn_self_modify && (rom_start..rom_start + self.rom_size).contains(&addr) {
+            tracing::warn!(addr = %format_args!("{:#06x}", addr),
+                           pc = %format_args!("{:#06x}", self.pc), "program wrote over itself");
+        }
+        self.ram[addr] = val;
+    }
+
+    // Opcodes
+    fn add_const_to_v(&mut self, instr: u16) {
         let reg = ((instr & 0x0f00) >> 8) as usize;
         let n = (instr & 0x00ff) as u8;
-        let incr = if self.v[reg] == n {2} else {4};
-        self.pc = self.pc + incr;
+
+        self.set_v(reg, self.v[reg].wrapping_add(n));
+        tracing::trace!(reg, value = self.v[reg], "added constant");
+        self.advance_pc(2);
     }
 
-    fn skip_if_key(&mut self, instr: u16) {
-        let reg = (instr & 0x0f00) >> 8;
-        let reg = reg as usize;
+    fn add_reg_to_i(&mut self, instr: u16) {
+        let reg = ((instr & 0x0f00) >> 8) as usize;
 
-        let incr = match self.test_key(self.v[reg]) {
-            Key::Up => 2,
-            Key::Down => 4,
+        // I can only address RAM (so it's actually a 12 bit value, except on XO-CHIP), and some
+        // interpreters say so in VF if it overflows
+        let i = self.i as usize + self.v[reg] as usize;
+        if self.quirks.i_overflow_vf {
+            self.set_v(0xf, if i >= self.ram.len() {1} else {0});
+        }
+        self.i = (i % self.ram.len()) as u16;
+
+        self.advance_pc(2);
+    }
+
+    fn check_index_range(&self, len: usize) -> Result<(), Chip8Error> {
+        // make sure the len bytes of memory starting at I are in RAM
+        let start = self.i as usize;
+        if start + len > self.ram.len() {
+            let addr = start.max(self.ram.len());
+            return Err(Chip8Error::MemoryOutOfBounds { addr, i: self.i, pc: self.pc });
+        }
+        Ok(())
+    }
+
+    // Turn every pixel off in the given planes
+    fn clear_planes(&mut self, planes: u8) {
+        for pixel in self.back_pixels.iter_mut() {
+            *pixel &= !planes;
+        }
+        for (plane, rows) in self.rows.iter_mut().enumerate() {
+            if planes & (1 << plane) != 0 {
+                *rows = [0; HI_RES_HEIGHT];
+            }
+        }
+    }
+
+    fn clear_screen(&mut self, _instr: u16) {
+        self.clear_planes(self.active_planes);
+        self.advance_pc(2);
+    }
+
+    fn draw_sprite(&mut self, instr: u16) -> Result<(), Chip8Error> {
+        let instr = instr as usize;
+
+        let x_reg = (instr & 0x0f00) >> 8;
+        let y_reg = (instr & 0x00f0) >> 4;
+
+        let n = instr & 0x000f;
+
+        // SUPER-CHIP draws a 16x16 sprite, two bytes per row, when N is 0
+        let big_sprite = n == 0 && self.quirks.machine.has_super_chip_instructions();
+        let (sprite_width, rows) = if big_sprite {
+            (16, 16)
+        } else {
+            (8, n)
         };
+        let bytes_per_row = sprite_width / 8;
+
+        let (width, height) = self.screen_size();
+        let x_start = self.v[x_reg] as usize;
+        let y_start = self.v[y_reg] as usize;
+        tracing::trace!(x = x_start, y = y_start, n, "drawing sprite");
+
+        // XO-CHIP draws into each selected plane in turn, with the sprite for the second plane
+        // straight after the one for the first
+        let planes: Vec<u8> = [1, 2].iter()
+            .copied()
+            .filter(|plane| self.active_planes & plane != 0)
+            .collect();
+        let sprite_size = rows * bytes_per_row;
+        self.check_index_range(sprite_size * planes.len())?;
+
+        let mut collision = false;
+
+        for (n, plane) in planes.into_iter().enumerate() {
+            let mem_start = self.i as usize + n * sprite_size;
+            for i in 0..rows {
+                let mem_location = mem_start + i * bytes_per_row;
+                let row = self.ram[mem_location..mem_location + bytes_per_row]
+                    .iter()
+                    .fold(0u16, |row, byte| row << 8 | *byte as u16);
+                let mut y = y_start + i;
+                if self.quirks.wrap_sprites {
+                    y %= height;
+                } else if y >= height {
+                    continue;
+                }
+
+                let mask = self.sprite_mask(row, sprite_width, x_start);
+                let plane_row = &mut self.rows[plane as usize - 1][y];
+                collision |= *plane_row & mask != 0;
+                *plane_row ^= mask;
+
+                // the pixels themselves only need touching where the sprite has a bit set
+                let mut flipped = mask;
+                while flipped != 0 {
+                    let x = flipped.leading_zeros() as usize;
+                    flipped &= !(LEFTMOST >> x);
+                    self.back_pixels[y * width + x] ^= plane;
+                }
+            }
+        }
+
+        self.set_v(0xf, if collision {1} else {0});
+        self.waiting_for_frame = self.quirks.draw_wait;
 
-        self.pc = self.pc + incr;
+        self.advance_pc(2);
+        Ok(())
     }
 
-    fn skip_if_not_key(&mut self, instr: u16) {
+    fn get_delay_timer(&mut self, instr: u16) {
         let reg = (instr & 0x0f00) >> 8;
         let reg = reg as usize;
 
-        let incr = match self.test_key(self.v[reg]) {
-            Key::Up => 4,
-            Key::Down => 2,
-        };
-        self.pc = self.pc + incr;
+        self.set_v(reg, self.delay_timer.get_value());
+
+        tracing::trace!(reg, value = self.v[reg], "got delay timer");
+        self.advance_pc(2);
     }
 
-    fn test_key(&mut self, key_index: u8) -> Key {
-        // This isn't right - in the Chip8, keys don't get "reset" when read. However, ncurses
-        // doesn't detect "key up" events, so this seems like a good place to set they key back to
-        // up.
-        let key_index = key_index as usize;
-        let key = self.keys[key_index].clone();
-        self.keys[key_index] = Key::Up;
-        key
+    // 00FD (SUPER-CHIP): exit the interpreter. The PC is left pointing at the 00FD.
+    fn halt(&mut self, _instr: u16) {
+        self.halted = true;
     }
-}
 
-impl fmt::Display for Chip8 {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "At {}, instruction {}", self.pc, self.ram[self.pc as usize])
+    // 00FF (SUPER-CHIP): switch to 128x64
+    fn high_resolution(&mut self, _instr: u16) {
+        self.set_resolution(true);
+        self.advance_pc(2);
     }
-}
 
-impl fmt::Display for Pixel {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let output = match *self {
-            Pixel::On => "*",
-            Pixel::Off => " ",
-        };
-        write!(f, "{}", output)
+    fn jump(&mut self, instr: u16) {
+        self.pc = instr & 0x0fff;
     }
-}
 
-fn draw(chip8: &mut Chip8) {
-    for item in chip8.draw_queue.iter() {
-        let (x, y) = item;
-        let x = *x;
-        let y = *y;
+    fn jump_subroutine(&mut self, instr: u16) -> Result<(), Chip8Error> {
+        if self.sp as usize >= STACK_SIZE {
+            return Err(Chip8Error::StackOverflow { pc: self.pc });
+        }
+
+        self.stack[self.sp as usize] = self.pc;
+        self.sp += 1;
+        self.pc = instr & 0x0fff;
 
-        let pixel = chip8.pixels[x as usize][y as usize];
+        tracing::trace!(addr = %format_args!("{:#06x}", self.pc), "called subroutine");
+        Ok(())
+    }
 
-        let ch = match pixel {
-            Pixel::On => ncurses::ACS_BLOCK(),
-            Pixel::Off => ' ' as ncurses::chtype,
+    fn jump_with_offset(&mut self, instr: u16) {
+        let reg = if self.quirks.jump_quirk {
+            ((instr & 0x0f00) >> 8) as usize
+        } else {
+            0
         };
-        ncurses::mvaddch(y as i32, x as i32, ch);
+
+        self.pc = (instr & 0x0fff) + self.v[reg] as u16;
     }
-    ncurses::refresh();
-    chip8.draw_queue.clear();
-}
 
-pub fn run(rom: Vec<u8>) {
-    let keyboard: HashMap<char, usize> = KEYBOARD_MAP.iter().cloned().collect();
+    // F002 (XO-CHIP): load the 16 byte audio pattern at I
+    fn load_audio_pattern(&mut self, _instr: u16) -> Result<(), Chip8Error> {
+        self.check_index_range(AUDIO_PATTERN_SIZE)?;
+        let start = self.i as usize;
+        let mut pattern = [0; AUDIO_PATTERN_SIZE];
+        pattern.copy_from_slice(&self.ram[start..start + AUDIO_PATTERN_SIZE]);
+        self.audio_pattern = Some(pattern);
+
+        self.advance_pc(2);
+        Ok(())
+    }
+
+    fn load_const(&mut self, instr: u16) {
+        // set a general purpose register (one of the "V's")
+        let reg = (instr & 0x0f00) >> 8;
+        let reg = reg as usize;
+        let value = (instr & 0x00ff) as u8;
+
+        self.set_v(reg, value);
 
-    ncurses::initscr();
-    ncurses::raw();
-    ncurses::curs_set(ncurses::CURSOR_VISIBILITY::CURSOR_INVISIBLE);
-    ncurses::nodelay(ncurses::stdscr(), true);
-    ncurses::noecho();
+        tracing::trace!(reg, value, "set register");
 
-    let mut chip8 = Chip8::initialize(rom);
-    loop {
-        let start_time = time::Instant::now();
+        self.advance_pc(2);
+    }
 
-        let ch = ncurses::getch();
-        if ch == 27 {  // ESC (and other keys)
-            ncurses::endwin();
-            break;
+    // FX85 (SUPER-CHIP): load V0 to VX from the RPL flags
+    fn load_rpl(&mut self, instr: u16) -> Result<(), Chip8Error> {
+        let count = self.rpl_count(instr)?;
+        for reg in 0..count {
+            self.set_v(reg, self.rpl_flags[reg]);
         }
 
-        let character = char::from_u32(ch as u32);
-        if let Some(k) = character {
-            if let Some(key) = keyboard.get(&k) {
-                chip8.keys[*key] = Key::Down;
-            }
-        }
+        self.advance_pc(2);
+        Ok(())
+    }
+
+    // 00FE (SUPER-CHIP): switch back to 64x32
+    fn low_resolution(&mut self, _instr: u16) {
+        self.set_resolution(false);
+        self.advance_pc(2);
+    }
+
+    fn rand(&mut self, instr: u16) {
+        let reg = (instr & 0x0f00) >> 8;
+        let reg = reg as usize;
 
-        chip8.emulate_cycle();
+        let random = self.random.gen_byte();
+        let val = (instr & 0x00ff) as u8;
+        
+        self.set_v(reg, val & random);
 
-        draw(&mut chip8);
+        self.advance_pc(2);
+    }
 
-        let elapsed = time::Instant::now().duration_since(start_time).as_millis();
-        let remaining = (CYCLE_DURATION as u128).saturating_sub(elapsed);
-        let duration = time::Duration::from_millis(remaining as u64);
-        thread::sleep(duration);
+    fn reg_get_for_math(&mut self, instr: u16) -> (usize, usize) {
+        (
+            ((instr & 0x0f00) >> 8) as usize,
+            ((instr & 0x00f0) >> 4) as usize,
+        )
     }
 
-    ncurses::endwin();
+    fn reg_add(&mut self, instr: u16) {
+        let (reg1, reg2) = self.reg_get_for_math(instr);
+
+        let val1 = self.v[reg1];
+        let val2 = self.v[reg2];
+
+        let (sum, overflow) = val1.overflowing_add(val2);
+
+        tracing::trace!(reg1, val1, reg2, val2, sum, overflow, "adding registers");
+
+        // set VF last, so the flag wins if VF is also the destination
+        self.set_v(reg1, sum);
+        self.set_v(0xf, if overflow {1} else {0});
+
+        self.advance_pc(2);
+    }
+
+    fn reg_and(&mut self, instr: u16) {
+        let (reg1, reg2) = self.reg_get_for_math(instr);
+
+        let result = self.v[reg1] & self.v[reg2];
+
+        tracing::trace!(reg1, val1 = self.v[reg1], reg2, val2 = self.v[reg2], result,
+                        "ANDing registers");
+
+        self.set_v(reg1, result);
+
+        if self.quirks.vf_reset {
+            self.set_v(0xf, 0);
+        }
+
+        self.advance_pc(2);
+    }
+
+    fn reg_load(&mut self, instr: u16) -> Result<(), Chip8Error> {
+        let count = ((instr & 0x0f00) >> 8) + 1;
+        self.check_index_range(count as usize)?;
+        tracing::trace!(count, i = %format_args!("{:#06x}", self.i), "loading registers");
+        for reg in 0..count {
+            let mem_location = (self.i + reg) as usize;
+            self.set_v(reg as usize, self.ram[mem_location]);
+        }
+
+        if self.quirks.load_store_quirk {
+            self.advance_i(count);
+        }
+
+        self.advance_pc(2);
+        Ok(())
+    }
+
+    fn reg_or(&mut self, instr: u16) {
+        let (reg1, reg2) = self.reg_get_for_math(instr);
+        self.set_v(reg1, self.v[reg1] | self.v[reg2]);
+
+        if self.quirks.vf_reset {
+            self.set_v(0xf, 0);
+        }
+
+        self.advance_pc(2);
+    }
+
+    fn reg_set(&mut self, instr: u16) {
+        let (reg1, reg2) = self.reg_get_for_math(instr);
+        self.set_v(reg1, self.v[reg2]);
+
+        self.advance_pc(2);
+    }
+
+    fn reg_store(&mut self, instr: u16) -> Result<(), Chip8Error> {
+        let count = ((instr & 0x0f00) >> 8) + 1;
+        self.check_index_range(count as usize)?;
+        for reg in 0..count {
+            let mem_location = (self.i + reg) as usize;
+            self.write_ram(mem_location, self.v[reg as usize]);
+        }
+
+        if self.quirks.load_store_quirk {
+            self.advance_i(count);
+        }
+
+        self.advance_pc(2);
+        Ok(())
+    }
+
+    fn reg_subtract(&mut self, instr: u16) {
+        let (reg1, reg2) = self.reg_get_for_math(instr);
+
+        let val1 = self.v[reg1];
+        let val2 = self.v[reg2];
+
+        let (sum, overflow) = val1.overflowing_sub(val2);
+
+        tracing::trace!(reg1, val1, reg2, val2, difference = sum, borrow = overflow,
+                        "subtracting registers");
+
+        self.set_v(reg1, sum);
+        self.set_v(0xf, if overflow {0} else {1});
+
+        self.advance_pc(2);
+    }
+
+    fn reg_subtract_n(&mut self, instr: u16) {
+        // like reg_subtract, but VX = VY - VX
+        let (reg1, reg2) = self.reg_get_for_math(instr);
+
+        let val1 = self.v[reg1];
+        let val2 = self.v[reg2];
+
+        let (diff, overflow) = val2.overflowing_sub(val1);
+
+        self.set_v(reg1, diff);
+        self.set_v(0xf, if overflow {0} else {1});
+
+        self.advance_pc(2);
+    }
+
+    fn reg_xor(&mut self, instr: u16) {
+        let (reg1, reg2) = self.reg_get_for_math(instr);
+        self.set_v(reg1, self.v[reg1] ^ self.v[reg2]);
+
+        if self.quirks.vf_reset {
+            self.set_v(0xf, 0);
+        }
+
+        self.advance_pc(2);
+    }
+
+    fn ret(&mut self, _instr: u16) -> Result<(), Chip8Error> {
+        if self.sp == 0 {
+            return Err(Chip8Error::StackUnderflow { pc: self.pc });
+        }
+        self.sp -= 1;
+        let addr = self.stack[self.sp as usize];
+
+        self.pc = addr;
+        self.advance_pc(2);
+        tracing::trace!(addr = %format_args!("{:#06x}", self.pc), "returned from subroutine");
+        Ok(())
+    }
+
+    // How many registers FX75 or FX85 copies. There are only 8 flags, so X can't be more than 7.
+    fn rpl_count(&self, instr: u16) -> Result<usize, Chip8Error> {
+        let count = (((instr & 0x0f00) >> 8) + 1) as usize;
+        if count > RPL_FLAG_COUNT {
+            return Err(Chip8Error::IllegalOpcode { opcode: instr, pc: self.pc });
+        }
+        Ok(count)
+    }
+
+    // Move every pixel dx to the right (or left, if dx is negative). Pixels moved off the edge are
+    // lost, and the columns uncovered are turned off.
+    fn scroll_columns(&mut self, dx: isize) {
+        let (width, height) = self.screen_size();
+        let old = self.back_pixels;
+        let n = dx.unsigned_abs().min(width);
+
+        for row in self.back_pixels[..width * height].chunks_exact_mut(width) {
+            if dx > 0 {
+                row.copy_within(0..width - n, n);
+                row[..n].iter_mut().for_each(|pixel| *pixel = 0);
+            } else {
+                row.copy_within(n.., 0);
+                row[width - n..].iter_mut().for_each(|pixel| *pixel = 0);
+            }
+        }
+
+        self.restore_unselected_planes(&old);
+        self.sync_rows();
+    }
+
+    // 00CN (SUPER-CHIP): scroll the display down N rows
+    fn scroll_down(&mut self, instr: u16) {
+        self.scroll_rows_down((instr & 0x000f) as usize);
+        self.advance_pc(2);
+    }
+
+    // 00FC (SUPER-CHIP): scroll the display 4 pixels left (2 in low resolution)
+    fn scroll_left(&mut self, _instr: u16) {
+        let n = self.horizontal_scroll_amount();
+        self.scroll_columns(-n);
+        self.advance_pc(2);
+    }
+
+    // 00FB (SUPER-CHIP): scroll the display 4 pixels right (2 in low resolution)
+    fn scroll_right(&mut self, _instr: u16) {
+        let n = self.horizontal_scroll_amount();
+        self.scroll_columns(n);
+        self.advance_pc(2);
+    }
+
+    // Move every row n rows down. Rows moved off the bottom are lost, and the n rows at the top
+    // are turned off.
+    fn scroll_rows_down(&mut self, n: usize) {
+        let (width, height) = self.screen_size();
+        let old = self.back_pixels;
+        let n = n.min(height);
+
+        // copy_within copies as if through a temporary buffer, so overlapping rows are fine
+        self.back_pixels.copy_within(0..(height - n) * width, n * width);
+        for pixel in self.back_pixels[..n * width].iter_mut() {
+            *pixel = 0;
+        }
+
+        self.restore_unselected_planes(&old);
+        self.sync_rows();
+    }
+
+    // FN01 (XO-CHIP): draw in the planes given by the bits of N, 1 for the first and 2 for the
+    // second
+    fn select_planes(&mut self, instr: u16) {
+        self.active_planes = ((instr & 0x0f00) >> 8) as u8 & ALL_PLANES;
+        self.advance_pc(2);
+    }
+
+    fn set_bcd(&mut self, instr: u16) -> Result<(), Chip8Error> {
+        let reg = ((instr & 0x0f00) >> 8) as usize;
+        let val = self.v[reg];
+
+        let hundreds = val / 100;
+        let tens = (val - 100 * hundreds) / 10;
+        let ones = val - 100 * hundreds - 10 * tens;
+        tracing::trace!(val, hundreds, tens, ones, "storing BCD");
+
+        self.check_index_range(3)?;
+        let start = self.i as usize;
+        self.write_ram(start, hundreds);
+        self.write_ram(start + 1, tens);
+        self.write_ram(start + 2, ones);
+
+        self.advance_pc(2);
+        Ok(())
+    }
+
+    // FX30 (SUPER-CHIP): point I at the big digit for VX
+    fn set_big_char_location(&mut self, instr: u16) {
+        let reg = ((instr & 0x0f00) >> 8) as usize;
+        let ch = self.v[reg] as usize;
+        self.i = (BIG_FONT_START + ch * 10) as u16;
+
+        self.advance_pc(2);
+    }
+
+    fn set_char_location(&mut self, instr: u16) {
+        let reg = ((instr & 0x0f00) >> 8) as usize;
+        let ch = self.v[reg] as usize;
+        self.i = (FONT_START + ch * 5) as u16;
+
+        self.advance_pc(2);
+    }
+
+    fn set_delay_timer(&mut self, instr: u16) {
+        let reg = (instr & 0x0f00) >> 8;
+        let reg = reg as usize;
+
+        self.delay_timer.start(self.v[reg]);
+
+        tracing::trace!(reg, value = self.v[reg], "set delay timer");
+
+        self.advance_pc(2);
+    }
+
+    fn set_index(&mut self, instr: u16) {
+        // set the "I" register (index/address register)
+        let value = instr & 0x0fff;
+        self.i = value;
+
+        tracing::trace!(i = %format_args!("{:#06x}", self.i), "set I");
+
+        self.advance_pc(2);
+    }
+
+    // F000 NNNN (XO-CHIP): set I to NNNN, which is too big to fit in the instruction itself, so
+    // it's in the next two bytes instead
+    fn set_long_index(&mut self, _instr: u16) -> Result<(), Chip8Error> {
+        let addr = self.pc.wrapping_add(2);
+        self.i = self.fetch_at(addr)
+            .ok_or(Chip8Error::MemoryOutOfBounds { addr: addr as usize, i: self.i, pc: self.pc })?;
+        self.advance_pc(4);
+        Ok(())
+    }
+
+    // FX3A (XO-CHIP): set the pitch audio patterns play at to VX
+    fn set_pitch(&mut self, instr: u16) {
+        let reg = ((instr & 0x0f00) >> 8) as usize;
+        self.pitch = self.v[reg];
+
+        self.advance_pc(2);
+    }
+
+    // Switching resolution clears the screen, as it does on most interpreters since SUPER-CHIP
+    fn set_resolution(&mut self, hi_res: bool) {
+        self.hi_res = hi_res;
+        self.clear_planes(ALL_PLANES);
+    }
+
+    fn set_sound_timer(&mut self, instr: u16) {
+        let reg = ((instr & 0x0f00) >> 8) as usize;
+        self.sound_timer.start(self.v[reg]);
+
+        self.advance_pc(2);
+    }
+
+    fn shift_get_operands(&mut self, instr: u16) -> (usize, u8) {
+        let (reg1, reg2) = self.reg_get_for_math(instr);
+        let source = if self.quirks.shift_quirk {reg1} else {reg2};
+        (reg1, self.v[source])
+    }
+
+    fn shift_left(&mut self, instr: u16) {
+        let (reg, val) = self.shift_get_operands(instr);
+
+        self.set_v(reg, val << 1);
+        self.set_v(0xf, val >> 7);
+
+        self.advance_pc(2);
+    }
+
+    fn shift_right(&mut self, instr: u16) {
+        let (reg, val) = self.shift_get_operands(instr);
+
+        self.set_v(reg, val >> 1);
+        self.set_v(0xf, 1 & val);
+
+        self.advance_pc(2);
+    }
+
+    fn skip_if_equal(&mut self, instr: u16) {
+        let reg = ((instr & 0x0f00) >> 8) as usize;
+        let n = (instr & 0x00ff) as u8;
+
+        let incr = if self.v[reg] == n {self.skip_amount()} else {2};
+        tracing::trace!(reg, n, incr, "skip if equal");
+        self.advance_pc(incr);
+    }
+
+    fn skip_if_regs_equal(&mut self, instr: u16) {
+        let (reg1, reg2) = self.reg_get_for_math(instr);
+        let incr = if self.v[reg1] == self.v[reg2] {self.skip_amount()} else {2};
+        self.advance_pc(incr);
+    }
+
+    fn skip_if_regs_unequal(&mut self, instr: u16) {
+       let (reg1, reg2) = self.reg_get_for_math(instr); 
+       let incr = if self.v[reg1] != self.v[reg2] {self.skip_amount()} else {2};
+       self.advance_pc(incr);
+    }
+
+    fn skip_if_unequal(&mut self, instr: u16) {
+        let reg = ((instr & 0x0f00) >> 8) as usize;
+        let n = (instr & 0x00ff) as u8;
+        let incr = if self.v[reg] == n {2} else {self.skip_amount()};
+        self.advance_pc(incr);
+    }
+
+    fn skip_if_key(&mut self, instr: u16) {
+        let reg = (instr & 0x0f00) >> 8;
+        let reg = reg as usize;
+
+        let incr = if self.is_key_down(self.v[reg]) {self.skip_amount()} else {2};
+        self.advance_pc(incr);
+    }
+
+    fn skip_if_not_key(&mut self, instr: u16) {
+        let reg = (instr & 0x0f00) >> 8;
+        let reg = reg as usize;
+
+        let incr = if self.is_key_down(self.v[reg]) {2} else {self.skip_amount()};
+        self.advance_pc(incr);
+    }
+
+    // FX75 (SUPER-CHIP): save V0 to VX in the RPL flags
+    fn store_rpl(&mut self, instr: u16) -> Result<(), Chip8Error> {
+        let count = self.rpl_count(instr)?;
+        self.rpl_flags[..count].copy_from_slice(&self.v[..count]);
+
+        self.advance_pc(2);
+        Ok(())
+    }
+
+    fn wait_for_key(&mut self, instr: u16) {
+        // As on the original interpreter, FX0A waits for a key to be pressed *and released*
+        // before moving on, so a held key doesn't satisfy several FX0A's in a row. Until then we
+        // leave pc alone, so we execute this instruction again next cycle.
+        let reg = ((instr & 0x0f00) >> 8) as usize;
+
+        match self.awaited_key {
+            None => self.awaited_key = (0..16).find(|key| self.is_key_down(*key as u8)),
+            Some(key_index) if !self.is_key_down(key_index as u8) => {
+                self.awaited_key = None;
+                self.set_v(reg, key_index as u8);
+                self.advance_pc(2);
+            },
+            Some(_) => {},
+        }
+    }
+}
+
+// The display, as get_display_string draws it
+impl fmt::Display for Chip8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.get_display_string())
+    }
+}
+
+// A register or address, which Debug shows in hex
+struct Hex<T>(T);
+
+impl fmt::Debug for Hex<u8> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#04x}", self.0)
+    }
+}
+
+impl fmt::Debug for Hex<u16> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#06x}", self.0)
+    }
+}
+
+// The registers, stack and timers, so a failed assert_eq! on a Chip8 (or one of its getters)
+// says something about where it was; the memory and display are left out
+impl fmt::Debug for Chip8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stack: Vec<Hex<u16>> = self.stack().iter().map(|addr| Hex(*addr)).collect();
+        f.debug_struct("Chip8")
+            .field("v", &self.v.map(Hex))
+            .field("i", &Hex(self.i))
+            .field("pc", &Hex(self.pc))
+            .field("stack", &stack)
+            .field("delay_timer", &self.delay_timer.get_value())
+            .field("sound_timer", &self.sound_timer.get_value())
+            .finish_non_exhaustive()
+    }
+}
+
+// The only public way to make a Chip8, e.g.
+// Chip8Builder::new().rom(rom).machine(Machine::SuperChip11).build()
+// Anything not given keeps its default: no ROM, a CHIP-8 with none of the quirks, DEFAULT_SPEED,
+// timers ticked by the host, and RND's numbers from an RNG seeded by the OS (with the "std"
+// feature).
+#[derive(Default)]
+pub struct Chip8Builder {
+    rom: Vec<u8>,
+    quirks: QuirkFlags,
+    cycle_accurate_speed: Option<u32>,
+    speed: Option<u32>,
+    random: Option<Box<dyn RandomSource>>,
+    random_seed: Option<u64>,
+    lenient: bool,
+    warn_self_modify: bool,
+    max_cycles: Option<u64>,
+}
+
+impl Chip8Builder {
+    pub fn new() -> Chip8Builder {
+        Chip8Builder::default()
+    }
+
+    pub fn rom(mut self, rom: Vec<u8>) -> Chip8Builder {
+        self.rom = rom;
+        self
+    }
+
+    pub fn quirks(mut self, quirks: QuirkFlags) -> Chip8Builder {
+        self.quirks = quirks;
+        self
+    }
+
+    // Behave like machine, with its instructions, RAM and quirks. To change some of the quirks,
+    // pass quirks instead, e.g.
+    // QuirkFlags { shift_quirk: false, ..Machine::Chip48.default_quirks() }
+    pub fn machine(mut self, machine: Machine) -> Chip8Builder {
+        self.quirks = machine.default_quirks();
+        self
+    }
+
+    // Tick the timers every speed / 60 instructions, so that they keep exact time with the
+    // program whatever the host is doing
+    pub fn cycle_accurate(mut self, speed: u32) -> Chip8Builder {
+        self.cycle_accurate_speed = Some(speed);
+        self
+    }
+
+    // How many instructions a second run_async runs (DEFAULT_SPEED otherwise)
+    pub fn speed(mut self, speed: u32) -> Chip8Builder {
+        self.speed = Some(speed);
+        self
+    }
+
+    // Take RND's numbers from random rather than the default source
+    pub fn random_source(mut self, random: Box<dyn RandomSource>) -> Chip8Builder {
+        self.random = Some(random);
+        self
+    }
+
+    // Have RND give the same numbers every time for the same seed, e.g. for tests or for replaying
+    // a session
+    pub fn random_seed(mut self, seed: u64) -> Chip8Builder {
+        self.random_seed = Some(seed);
+        self
+    }
+
+    // Skip unknown opcodes, with a warning, rather than stopping at them with an error
+    pub fn lenient(mut self, lenient: bool) -> Chip8Builder {
+        self.lenient = lenient;
+        self
+    }
+
+    // Warn whenever the program writes into the ROM it was loaded from, which is usually a bug
+    // (though some programs do it on purpose)
+    pub fn warn_self_modify(mut self, warn: bool) -> Chip8Builder {
+        self.warn_self_modify = warn;
+        self
+    }
+
+    // Stop with CycleLimitReached after max emulate_cycle calls (see instruction_count), e.g. for
+    // tests that mustn't loop forever
+    pub fn max_cycles(mut self, max: u64) -> Chip8Builder {
+        self.max_cycles = Some(max);
+        self
+    }
+
+    // The Chip8, with the ROM loaded, as long as it fits in the machine's memory and the settings
+    // make sense together
+    pub fn build(self) -> Result<Chip8, Chip8Error> {
+        let invalid = |reason| Err(Chip8Error::InvalidSettings { reason });
+        if self.speed == Some(0) || self.cycle_accurate_speed == Some(0) {
+            return invalid("the speed must be at least 1 instruction per second");
+        }
+        if let (Some(speed), Some(cycle_accurate_speed)) = (self.speed, self.cycle_accurate_speed) {
+            if speed != cycle_accurate_speed {
+                return invalid("speed and cycle_accurate were given different speeds");
+            }
+        }
+        if self.random.is_some() && self.random_seed.is_some() {
+            return invalid("random_source and random_seed can't both be used");
+        }
+
+        let mut chip8 = Chip8::initialize(self.rom, self.quirks)?;
+        if let Some(random) = self.random {
+            chip8.random = random;
+        }
+        if let Some(seed) = self.random_seed {
+            chip8.random = Box::new(Xorshift::new(seed));
+        }
+        if let Some(speed) = self.speed {
+            chip8.speed = speed;
+        }
+        chip8.lenient = self.lenient;
+        chip8.warn_self_modify = self.warn_self_modify;
+        chip8.max_cycles = self.max_cycles;
+        chip8.cycles_per_tick = self.cycle_accurate_speed
+            .map(|speed| (speed / FRAME_RATE).max(1) as u64);
+        Ok(chip8)
+    }
+}
+
+// Run a ROM for a number of cycles without any frontend (e.g. for tests), stopping early on errors.
+// The Chip8 has Chip8Builder's defaults
+pub fn run_headless(rom: Vec<u8>, cycles: usize) -> Result<Chip8, Chip8Error> {
+    let mut chip8 = Chip8Builder::new().rom(rom).build()?;
+    let cycles_per_frame = (DEFAULT_SPEED / FRAME_RATE) as usize;
+    for cycle in 1..=cycles {
+        chip8.emulate_cycle()?;
+        if chip8.is_halted() {
+            break;
+        }
+        if cycle % cycles_per_frame == 0 {
+            chip8.tick_timers(1);
+            chip8.end_frame();
+            // there's no frontend to draw it
+            chip8.clear_dirty();
+        }
+    }
+    Ok(chip8)
+}
+
+#[cfg(feature = "std")]
+pub fn run(rom: Vec<u8>, mut frontend: Box<dyn Frontend>, config: &Config)
+    -> Result<(), Chip8Error>
+{
+    let mut builder = Chip8Builder::new()
+        .rom(rom)
+        .quirks(config.quirks)
+        .lenient(config.lenient)
+        .warn_self_modify(config.warn_self_modify);
+    if config.cycle_accurate {
+        builder = builder.cycle_accurate(config.speed);
+    }
+    if let Some(max) = config.max_cycles {
+        builder = builder.max_cycles(max);
+    }
+    let mut chip8 = builder.build()?;
+    chip8.set_buzzer_frequency(config.audio.frequency);
+    let buzzer = if config.audio.enabled {audio::Buzzer::new(chip8.buzzer_frequency)} else {None};
+    chip8.breakpoints.extend(config.breakpoints.iter());
+
+    // --break on its own runs until the first breakpoint; --debug starts out stepping
+    let mut debugger = if config.debug || !config.breakpoints.is_empty() {
+        Some(Debugger::new(config.debug))
+    } else {
+        None
+    };
+
+    let watcher = if config.watch {RomWatcher::new(&config.rom_path)} else {None};
+
+    let mut tracer = match &config.trace {
+        Some(path) => Some(Tracer::create(path, config.trace_limit)?),
+        None => None,
+    };
+
+    let mut call_graph = if config.callgraph.is_some() {Some(CallGraph::new())} else {None};
+
+    let mut recorder = match &config.record {
+        Some(path) => Some(GifRecorder::create(path, &config.palette)?),
+        None => None,
+    };
+
+    let mut input_recorder = match &config.record_input {
+        Some(path) => Some(InputRecorder::create(path)?),
+        None => None,
+    };
+    let mut replay = match &config.replay_input {
+        Some(path) => Some(InputReplay::load(path)?),
+        None => None,
+    };
+
+    let mut gamepads = gamepad::Gamepads::new(&config.gamepad);
+
+    // while replaying, the keyboard still works for commands, but the keys themselves go here
+    let mut ignored_keys = [Key::Up; 16];
+
+    let mut perf = if config.perf {Some(PerfCounter::new())} else {None};
+    let mut history = History::new();
+    let mut reached_cycle_limit = false;
+
+    // the error the program stopped with, if it did, which waits until the call graph is saved
+    // and the recordings are finished, so they're there to look at afterwards
+    let mut stopped_by = None;
+    let mut rewind_buffer = config.rewind_buffer.map(RewindBuffer::new);
+    let api = match config.api_port {
+        Some(port) => {
+            let server = ApiServer::start(port, &chip8, &config.palette, config.scale as u32)
+                .map_err(|reason| Chip8Error::ApiFailed { port, reason })?;
+            Some(server)
+        },
+        None => None,
+    };
+
+    // what's on the status line, and the part of it from perf, which only changes once a second
+    let mut status = String::new();
+    let mut perf_summary = String::new();
+
+    // the RPL flags as last saved, so we only write them out when they change
+    let super_chip = config.quirks.machine.has_super_chip_instructions();
+    let mut saved_rpl_flags = chip8.rpl_flags;
+    if super_chip {
+        if let Some(flags) = rpl::load() {
+            chip8.rpl_flags = flags;
+            saved_rpl_flags = flags;
+        }
+    }
+
+    let mut paused = false;
+
+    // Each time round the loop is one frame. Rather than running a fixed number of instructions
+    // per frame, we work out how many we should have run since the last one, and run that many.
+    // That keeps the speed right however long instructions (and sleeping) actually take.
+    let frame_duration = time::Duration::from_secs(1) / FRAME_RATE;
+    let mut next_frame = time::Instant::now();
+    let mut last_wakeup = time::Instant::now();
+    let mut cycles_owed = 0.;
+
+    // what the frontend thinks the resolution is
+    let mut screen_size = chip8.display_size();
+
+    frontend.clear();
+    'running: loop {
+        let now = time::Instant::now();
+        let frame_time = now.duration_since(last_wakeup);
+        let elapsed = frame_time.as_secs_f64();
+        last_wakeup = now;
+
+        if let Some(perf) = &mut perf {
+            perf.record_frame(frame_time);
+            // once a second is plenty, and easier to read
+            if perf.frame_count().is_multiple_of(FRAME_RATE as u64) {
+                perf_summary = perf.summary();
+            }
+        }
+        let mut new_status = perf_summary.clone();
+        if let Some(buffer) = &rewind_buffer {
+            if !new_status.is_empty() {
+                new_status += "  ";
+            }
+            new_status += &buffer.summary();
+        }
+        if new_status != status {
+            frontend.show_status(&new_status);
+            status = new_status;
+        }
+
+        let old_keys = chip8.keys;
+        let keys = if replay.is_some() {&mut ignored_keys} else {&mut chip8.keys};
+        let command = frontend.poll_events(keys);
+        if let Some(gamepads) = &mut gamepads {
+            gamepads.poll(keys);
+        }
+        if let Some(api) = &api {
+            api.handle(|request| match request {
+                // as with the keyboard, only the replay's keys reach the program
+                ApiRequest::Key { key, .. } if replay.is_some() && key > 0xf => {
+                    Err(Chip8Error::InvalidKey { key })
+                },
+                ApiRequest::Key { .. } if replay.is_some() => Ok(()),
+                ApiRequest::Key { key, down } => chip8.set_key(key, down),
+                ApiRequest::Reset => {
+                    chip8.reset();
+                    frontend.clear();
+                    Ok(())
+                },
+                ApiRequest::Load(rom) => {
+                    chip8.load_rom(rom)?;
+                    frontend.clear();
+                    Ok(())
+                },
+            });
+        }
+        // before recording, so that a replay lets go of the keys at the same point
+        if let Some(Command::FocusLost) = command {
+            if replay.is_none() {
+                chip8.clear_keys();
+            }
+        }
+        if let Some(recorder) = &mut input_recorder {
+            recorder.record(&old_keys, &chip8)?;
+        }
+
+        // when a frame has been rewound, we show it rather than running on from it straight away
+        let mut rewound = false;
+
+        match command {
+            Some(Command::Quit) => break,
+            Some(Command::Pause) => paused = !paused,
+            Some(Command::Reset) => {
+                chip8.reset();
+                frontend.clear();
+            },
+            Some(Command::SaveState) => {
+                // there's nowhere to report a failure without spoiling the display, and nothing
+                // is lost by carrying on
+                let _ = fs::write(STATE_PATH, chip8.save_state());
+            },
+            Some(Command::LoadState) => {
+                // likewise, a missing or broken save state just leaves things as they are
+                if let Ok(data) = fs::read(STATE_PATH) {
+                    if chip8.load_state(&data).is_ok() {
+                        frontend.clear();
+                    }
+                }
+            },
+            Some(Command::Screenshot) => {
+                // as with saving the state, there's nowhere to say if this fails
+                let path = screenshot::default_path();
+                let _ = screenshot::save(&chip8, &config.palette, config.scale as u32, &path);
+            },
+            Some(Command::Rewind) => {
+                // recorded and replayed input is tied to the instruction count, which this would
+                // send backwards
+                let snapshot = match &mut rewind_buffer {
+                    Some(buffer) if replay.is_none() && input_recorder.is_none() => buffer.rewind(),
+                    _ => None,
+                };
+                if let Some(snapshot) = snapshot {
+                    chip8 = snapshot;
+                    chip8.redraw();
+                    frontend.clear();
+                    rewound = true;
+                }
+            },
+            Some(Command::FocusLost) | None => {},
+        }
+
+        if watcher.as_ref().is_some_and(|watcher| watcher.changed()) {
+            // a ROM we can't read may just be half written, in which case we'll hear about it
+            // again once it's finished
+            if let Ok(rom) = fs::read(&config.rom_path) {
+                chip8.load_rom(rom)?;
+                frontend.clear();
+            }
+        }
+
+        if paused || rewound {
+            // keep handling input and drawing (frontends may need to redraw), but nothing else
+            if let Some(buzzer) = &buzzer {
+                buzzer.set_playing(false);
+            }
+        } else {
+            cycles_owed += elapsed * config.speed as f64;
+            cycles_owed = cycles_owed.min(MAX_CATCH_UP * config.speed as f64);
+            while cycles_owed >= 1. {
+                cycles_owed -= 1.;
+
+                if let Some(debugger) = &mut debugger {
+                    if debugger.should_pause(&chip8) {
+                        frontend.suspend();
+                        let action = debugger.prompt(&mut chip8);
+                        frontend.resume();
+                        if let DebugAction::Quit = action {
+                            break 'running;
+                        }
+
+                        // time spent at the prompt doesn't count
+                        last_wakeup = time::Instant::now();
+                        next_frame = last_wakeup;
+                    }
+                    debugger.record(&chip8);
+                }
+
+                if let Some(replay) = &mut replay {
+                    replay.apply(&mut chip8);
+                }
+                if let Some(tracer) = &mut tracer {
+                    tracer.record(&chip8)?;
+                }
+                if let Some(call_graph) = &mut call_graph {
+                    call_graph.record(&chip8);
+                }
+                history.record(&chip8);
+
+                if let Err(err) = chip8.emulate_cycle() {
+                    if let Chip8Error::CycleLimitReached { .. } = err {
+                        reached_cycle_limit = true;
+                        break 'running;
+                    }
+                    stopped_by = Some(err);
+                    break 'running;
+                }
+                if let Some(perf) = &mut perf {
+                    perf.record_cycle();
+                }
+                if chip8.is_halted() {
+                    break 'running;
+                }
+            }
+
+            if !chip8.is_cycle_accurate() {
+                chip8.tick_timers(1);
+            }
+
+            // not being able to save shouldn't stop the game; if it fails we try again next frame
+            let rpl_changed = super_chip && chip8.rpl_flags != saved_rpl_flags;
+            if rpl_changed && rpl::save(&chip8.rpl_flags).is_ok() {
+                saved_rpl_flags = chip8.rpl_flags;
+            }
+
+            if let Some(buzzer) = &buzzer {
+                buzzer.set_waveform(chip8.waveform());
+                buzzer.set_playing(chip8.sound_timer.get_value() > 0);
+            }
+
+            if let Some(buffer) = &mut rewind_buffer {
+                buffer.record(&chip8);
+            }
+        }
+
+        chip8.end_frame();
+        if chip8.display_size() != screen_size {
+            screen_size = chip8.display_size();
+            frontend.set_resolution(screen_size.0, screen_size.1);
+        }
+        frontend.render(chip8.display_buffer(), chip8.dirty_rows());
+        if let Some(api) = &api {
+            api.publish(&chip8);
+        }
+        chip8.clear_dirty();
+
+        if let Some(recorder) = &mut recorder {
+            recorder.record_frame(&chip8)?;
+        }
+
+        // sleep until the next frame is due, unless we're already late for it
+        next_frame += frame_duration;
+        let now = time::Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+
+    // we may have stopped partway through a frame, before the flags could be saved
+    if super_chip && chip8.rpl_flags != saved_rpl_flags {
+        let _ = rpl::save(&chip8.rpl_flags);
+    }
+
+    // everything is saved even if some of it can't be, and the first failure reported
+    let mut saved = Ok(());
+    if let (Some(call_graph), Some(path)) = (&call_graph, &config.callgraph) {
+        saved = saved.and(call_graph.save(path));
+    }
+    if let Some(recorder) = recorder {
+        saved = saved.and(recorder.finish());
+    }
+    if let Some(recorder) = input_recorder {
+        saved = saved.and(recorder.finish());
+    }
+
+    // put the terminal back before saying anything (like where the crash dump is)
+    drop(frontend);
+    if let Some(err) = stopped_by {
+        if matches!(err, Chip8Error::UnknownOpcode { .. } | Chip8Error::IllegalOpcode { .. }) {
+            crash::save(&chip8, &err, &history);
+        }
+        return Err(err);
+    }
+    saved?;
+    if chip8.is_halted() {
+        println!("Program exited at {:#06x}", chip8.pc);
+    } else if reached_cycle_limit {
+        println!("Stopped after {} cycles", chip8.cycles);
+    }
+    if config.verbose {
+        println!("{}", chip8);
+        print_registers(&chip8);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run a single instruction with the given registers already set
+    fn run_one(instr: u16, registers: &[(usize, u8)]) -> Chip8 {
+        let mut chip8 = Chip8::new_for_testing(&instr.to_be_bytes());
+        for (reg, val) in registers {
+            chip8.v[*reg] = *val;
+        }
+        chip8.emulate_cycle().unwrap();
+        chip8
+    }
+
+    fn run_program(program: &[u16], cycles: usize) -> Chip8 {
+        let rom: Vec<u8> = program.iter().flat_map(|instr| instr.to_be_bytes().to_vec()).collect();
+        let mut chip8 = Chip8::new_for_testing(&rom);
+        chip8.step_n(cycles).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn rom_too_large() {
+        let rom = vec![0; RAM_SIZE];
+        let max = RAM_SIZE - INSTRUCTIONS_START as usize;
+        assert_eq!(
+            Chip8::initialize(rom, QuirkFlags::default()).err(),
+            Some(Chip8Error::RomTooLarge { size: RAM_SIZE, max }),
+        );
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let mut chip8 = Chip8::new_for_testing(&[0x01, 0x23]);
+        let err = Chip8Error::UnknownOpcode { opcode: 0x0123, pc: 0x200 };
+        assert_eq!(chip8.emulate_cycle(), Err(err));
+
+        let mut chip8 = Chip8::new_for_testing(&[0x80, 0x1f]);
+        let err = Chip8Error::UnknownOpcode { opcode: 0x801f, pc: 0x200 };
+        assert_eq!(chip8.emulate_cycle(), Err(err));
+    }
+
+    #[test]
+    fn get_and_set_pixels() {
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        chip8.set_pixel(3, 4, true);
+        assert!(chip8.get_pixel(3, 4));
+        chip8.end_frame();
+        assert_eq!(chip8.display_buffer()[4 * SCREEN_WIDTH + 3], 1);
+
+        // off the screen, nothing happens
+        chip8.set_pixel(SCREEN_WIDTH, 0, true);
+        assert!(!chip8.get_pixel(SCREEN_WIDTH, 0));
+        assert!(!chip8.get_pixel(0, SCREEN_HEIGHT));
+        assert_eq!(chip8.display_buffer().len(), SCREEN_PIXELS);
+
+        chip8.set_pixel(3, 4, false);
+        assert!(!chip8.get_pixel(3, 4));
+    }
+
+    #[test]
+    fn clear_screen() {
+        let mut chip8 = Chip8::new_for_testing(&[0x00, 0xe0]);
+        chip8.set_color(3, 4, 1);
+        chip8.end_frame();
+        chip8.clear_dirty();
+        chip8.emulate_cycle().unwrap();
+        assert!(!chip8.get_pixel(3, 4));
+        chip8.end_frame();
+        assert_eq!(chip8.dirty_rows(), 1 << 4);
+        assert_eq!(chip8.pc, 0x202);
+    }
+
+    #[test]
+    fn call_and_return() {
+        // 0x200: CALL 0x204, 0x202: (unused), 0x204: RET
+        let chip8 = run_program(&[0x2204, 0x0000, 0x00ee], 1);
+        assert_eq!(chip8.pc, 0x204);
+        assert_eq!(chip8.stack(), [0x200]);
+
+        let chip8 = run_program(&[0x2204, 0x0000, 0x00ee], 2);
+        assert_eq!(chip8.pc, 0x202);
+        assert!(chip8.stack().is_empty());
+    }
+
+    #[test]
+    fn return_with_empty_stack() {
+        let mut chip8 = Chip8::new_for_testing(&[0x00, 0xee]);
+        assert_eq!(chip8.emulate_cycle(), Err(Chip8Error::StackUnderflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn error_is_std_error() {
+        // so it can come back from main with ?
+        fn run() -> Result<(), Box<dyn std::error::Error>> {
+            Chip8::new_for_testing(&[0x00, 0xee]).emulate_cycle()?;
+            Ok(())
+        }
+        let err = run().unwrap_err();
+        assert_eq!(err.to_string(), Chip8Error::StackUnderflow { pc: 0x200 }.to_string());
+    }
+
+    #[test]
+    fn error_suggestions() {
+        let message = |opcode| Chip8Error::UnknownOpcode { opcode, pc: 0x200 }.to_string();
+        assert!(message(0x00ff).ends_with("this ROM may need --mode superchip"));
+        assert!(message(0xf375).ends_with("this ROM may need --mode superchip"));
+        assert!(message(0xf201).ends_with("this ROM may need --mode xochip"));
+        assert!(message(0x5001).contains("--lenient"));
+
+        let err = Chip8::new_for_testing(&[]).load_rom(vec![0; 0x1000]).unwrap_err();
+        assert!(err.to_string().ends_with("(--mode xochip)"));
+        let err = xo_chip(&[]).load_rom(vec![0; 0x10000]).unwrap_err();
+        assert_eq!(err.to_string(), "ROM is 65536 bytes, but at most 65024 bytes fit in memory");
+    }
+
+    #[test]
+    fn call_stack_overflow() {
+        // a subroutine that calls itself forever
+        let mut chip8 = Chip8::new_for_testing(&[0x22, 0x00]);
+        for _ in 0..STACK_SIZE {
+            chip8.emulate_cycle().unwrap();
+        }
+        assert_eq!(chip8.emulate_cycle(), Err(Chip8Error::StackOverflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn jump() {
+        let chip8 = run_one(0x1abc, &[]);
+        assert_eq!(chip8.pc, 0xabc);
+    }
+
+    #[test]
+    fn jump_with_offset() {
+        let chip8 = run_one(0xb300, &[(0, 0x12), (3, 0x40)]);
+        assert_eq!(chip8.pc, 0x312);
+    }
+
+    #[test]
+    fn jump_past_end_of_memory() {
+        // LD V0, 0xfe; JP V0, 0xfff
+        let mut chip8 = run_program(&[0x60fe, 0xbfff], 2);
+        assert_eq!(
+            chip8.emulate_cycle(),
+            Err(Chip8Error::MemoryOutOfBounds { addr: 0x10fd, i: 0, pc: 0x10fd }),
+        );
+    }
+
+    #[test]
+    fn jump_with_offset_quirk() {
+        let mut chip8 = Chip8::new_for_testing(&[0xb3, 0x00]);
+        chip8.quirks.jump_quirk = true;
+        chip8.v[0] = 0x12;
+        chip8.v[3] = 0x40;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x340);
+    }
+
+    #[test]
+    fn skip_if_equal() {
+        assert_eq!(run_one(0x3142, &[(1, 0x42)]).pc, 0x204);
+        assert_eq!(run_one(0x3142, &[(1, 0x41)]).pc, 0x202);
+    }
+
+    #[test]
+    fn skip_if_unequal() {
+        assert_eq!(run_one(0x4142, &[(1, 0x42)]).pc, 0x202);
+        assert_eq!(run_one(0x4142, &[(1, 0x41)]).pc, 0x204);
+    }
+
+    #[test]
+    fn skip_if_regs_equal() {
+        assert_eq!(run_one(0x5120, &[(1, 7), (2, 7)]).pc, 0x204);
+        assert_eq!(run_one(0x5120, &[(1, 7), (2, 8)]).pc, 0x202);
+    }
+
+    #[test]
+    fn skip_if_regs_unequal() {
+        assert_eq!(run_one(0x9120, &[(1, 7), (2, 7)]).pc, 0x202);
+        assert_eq!(run_one(0x9120, &[(1, 7), (2, 8)]).pc, 0x204);
+    }
+
+    // A CHIP-8 (with no ROM) running each of opcodes in turn
+    fn inject(opcodes: &[u16]) -> Chip8 {
+        let mut chip8 = Chip8Builder::new().build().unwrap();
+        for opcode in opcodes {
+            chip8.inject_opcode(*opcode).unwrap();
+        }
+        chip8
+    }
+
+    #[test]
+    fn inject_opcode_leaves_memory_alone() {
+        let chip8 = inject(&[0x6042]);
+        assert_eq!(chip8.get_register(0), 0x42);
+        assert_eq!(chip8.get_pc(), 0x202);
+        assert_eq!((chip8.get_ram(0x200), chip8.get_ram(0x201)), (0, 0));
+
+        // even when the instruction stores something there itself
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        chip8.i = 0x200;
+        chip8.v[0] = 0x12;
+        chip8.inject_opcode(0xf055).unwrap();
+        assert_eq!(chip8.get_ram(0x200), 0);
+
+        let mut chip8 = inject(&[0x1fff]);
+        assert_eq!(
+            chip8.inject_opcode(0x6042),
+            Err(Chip8Error::MemoryOutOfBounds { addr: 0xfff, i: 0, pc: 0xfff }),
+        );
+    }
+
+    #[test]
+    fn inject_clear_screen() {
+        let mut chip8 = inject(&[0xa000 | FONT_START as u16, 0xd005]);
+        assert!(chip8.get_pixel(0, 0));
+        chip8.inject_opcode(0x00e0).unwrap();
+        assert!(!chip8.get_pixel(0, 0));
+    }
+
+    #[test]
+    fn inject_call_and_return() {
+        let chip8 = inject(&[0x2400]);
+        assert_eq!((chip8.get_pc(), chip8.get_stack()), (0x400, &[0x200][..]));
+        let chip8 = inject(&[0x2400, 0x00ee]);
+        assert_eq!((chip8.get_pc(), chip8.get_stack()), (0x202, &[][..]));
+    }
+
+    #[test]
+    fn inject_jump() {
+        assert_eq!(inject(&[0x1234]).get_pc(), 0x234);
+        assert_eq!(inject(&[0x6010, 0xb300]).get_pc(), 0x310);
+    }
+
+    #[test]
+    fn inject_skip_if_equal() {
+        assert_eq!(inject(&[0x6142, 0x3142]).get_pc(), 0x206);
+        assert_eq!(inject(&[0x6142, 0x3143]).get_pc(), 0x204);
+    }
+
+    #[test]
+    fn inject_skip_if_unequal() {
+        assert_eq!(inject(&[0x6142, 0x4143]).get_pc(), 0x206);
+        assert_eq!(inject(&[0x6142, 0x4142]).get_pc(), 0x204);
+    }
+
+    #[test]
+    fn inject_skip_if_regs_equal() {
+        assert_eq!(inject(&[0x6105, 0x6205, 0x5120]).get_pc(), 0x208);
+        assert_eq!(inject(&[0x6105, 0x6206, 0x9120]).get_pc(), 0x208);
+        assert_eq!(inject(&[0x6105, 0x6205, 0x9120]).get_pc(), 0x206);
+    }
+
+    #[test]
+    fn inject_add_const() {
+        let chip8 = inject(&[0x60ff, 0x7002]);
+        assert_eq!((chip8.get_register(0), chip8.get_register(0xf)), (1, 0));
+    }
+
+    #[test]
+    fn inject_logic() {
+        let chip8 = inject(&[0x600c, 0x610a, 0x8011]);
+        assert_eq!(chip8.get_register(0), 0x0e);
+        let chip8 = inject(&[0x600c, 0x610a, 0x8012]);
+        assert_eq!(chip8.get_register(0), 0x08);
+        let chip8 = inject(&[0x600c, 0x610a, 0x8013]);
+        assert_eq!(chip8.get_register(0), 0x06);
+        let chip8 = inject(&[0x600c, 0x610a, 0x8010]);
+        assert_eq!(chip8.get_register(0), 0x0a);
+    }
+
+    #[test]
+    fn inject_arithmetic() {
+        let chip8 = inject(&[0x60f0, 0x6120, 0x8014]);
+        assert_eq!((chip8.get_register(0), chip8.get_register(0xf)), (0x10, 1));
+        let chip8 = inject(&[0x6010, 0x6120, 0x8015]);
+        assert_eq!((chip8.get_register(0), chip8.get_register(0xf)), (0xf0, 0));
+        let chip8 = inject(&[0x6010, 0x6120, 0x8017]);
+        assert_eq!((chip8.get_register(0), chip8.get_register(0xf)), (0x10, 1));
+    }
+
+    #[test]
+    fn inject_shifts() {
+        let chip8 = inject(&[0x6103, 0x8016]);
+        assert_eq!((chip8.get_register(0), chip8.get_register(0xf)), (1, 1));
+        let chip8 = inject(&[0x6181, 0x801e]);
+        assert_eq!((chip8.get_register(0), chip8.get_register(0xf)), (2, 1));
+    }
+
+    #[test]
+    fn inject_set_index() {
+        let chip8 = inject(&[0xa123, 0x6105, 0xf11e]);
+        assert_eq!(chip8.get_i(), 0x128);
+    }
+
+    #[test]
+    fn inject_random() {
+        let mut chip8 = Chip8Builder::new().random_source(Box::new(Counter(0xf0))).build().unwrap();
+        chip8.inject_opcode(0xc30f).unwrap();
+        assert_eq!(chip8.get_register(3), 0x01);
+    }
+
+    #[test]
+    fn inject_draw() {
+        let chip8 = inject(&[0xa000 | FONT_START as u16, 0xd005, 0xd005]);
+        assert!(!chip8.get_pixel(0, 0));
+        assert_eq!(chip8.get_register(0xf), 1);
+    }
+
+    #[test]
+    fn inject_skip_on_key() {
+        let mut chip8 = inject(&[0x6007]);
+        chip8.set_key(7, true).unwrap();
+        chip8.inject_opcode(0xe09e).unwrap();
+        assert_eq!(chip8.get_pc(), 0x206);
+        chip8.inject_opcode(0xe0a1).unwrap();
+        assert_eq!(chip8.get_pc(), 0x208);
+    }
+
+    #[test]
+    fn inject_timers() {
+        let chip8 = inject(&[0x6020, 0xf015, 0xf018, 0xf107]);
+        assert_eq!(chip8.get_register(1), 0x20);
+        assert_eq!(chip8.sound_timer.get_value(), 0x20);
+    }
+
+    #[test]
+    fn inject_bcd_and_font() {
+        let chip8 = inject(&[0x60fe, 0xa300, 0xf033]);
+        assert_eq!([chip8.get_ram(0x300), chip8.get_ram(0x301), chip8.get_ram(0x302)], [2, 5, 4]);
+        let chip8 = inject(&[0x6002, 0xf029]);
+        assert_eq!(chip8.get_i() as usize, FONT_START + 2 * 5);
+    }
+
+    #[test]
+    fn inject_store_and_load() {
+        let chip8 = inject(&[0x6001, 0x6102, 0xa300, 0xf155, 0x6000, 0x6100, 0xf165]);
+        assert_eq!((chip8.get_register(0), chip8.get_register(1)), (1, 2));
+        assert_eq!((chip8.get_ram(0x300), chip8.get_ram(0x301)), (1, 2));
+    }
+
+    #[test]
+    fn getters_and_setters() {
+        // CALL 0x300
+        let mut chip8 = Chip8::new_for_testing(&[0x23, 0x00]);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.get_pc(), 0x300);
+        assert_eq!(chip8.get_stack(), [0x200]);
+
+        chip8.set_register(3, 0x42).unwrap();
+        assert_eq!(chip8.get_register(3), 0x42);
+        assert_eq!(chip8.set_register(16, 1), Err(Chip8Error::InvalidRegister { reg: 16 }));
+
+        chip8.set_i(0x123).unwrap();
+        assert_eq!(chip8.get_i(), 0x123);
+        assert!(chip8.set_i(RAM_SIZE as u16).is_err());
+        assert_eq!(chip8.get_i(), 0x123);
+
+        chip8.poke(0xfff, 7).unwrap();
+        assert_eq!(chip8.get_ram(0xfff), 7);
+        assert_eq!(
+            chip8.poke(RAM_SIZE, 7),
+            Err(Chip8Error::MemoryOutOfBounds { addr: RAM_SIZE, i: 0x123, pc: 0x300 }),
+        );
+    }
+
+    #[test]
+    fn set_register() {
+        let chip8 = run_one(0x6a42, &[]);
+        assert_eq!(chip8.v[0xa], 0x42);
+        assert_eq!(chip8.pc, 0x202);
+    }
+
+    #[test]
+    fn add_const_wraps_without_touching_vf() {
+        let chip8 = run_one(0x7102, &[(1, 0xff), (0xf, 5)]);
+        assert_eq!(chip8.v[1], 1);
+        assert_eq!(chip8.v[0xf], 5);
+    }
+
+    #[test]
+    fn reg_set() {
+        let chip8 = run_one(0x8120, &[(2, 9)]);
+        assert_eq!(chip8.v[1], 9);
+    }
+
+    #[test]
+    fn reg_or() {
+        let chip8 = run_one(0x8121, &[(1, 0b1100), (2, 0b1010), (0xf, 5)]);
+        assert_eq!(chip8.v[1], 0b1110);
+        assert_eq!(chip8.v[0xf], 5);
+    }
+
+    #[test]
+    fn reg_or_vf_reset() {
+        let mut chip8 = Chip8::new_for_testing(&[0x81, 0x21]);
+        chip8.quirks.vf_reset = true;
+        chip8.v[0xf] = 5;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.v[0xf], 0);
+    }
+
+    #[test]
+    fn reg_and() {
+        let chip8 = run_one(0x8122, &[(1, 0b1100), (2, 0b1010)]);
+        assert_eq!(chip8.v[1], 0b1000);
+    }
+
+    #[test]
+    fn reg_and_vf_reset() {
+        let quirks = QuirkFlags { vf_reset: true, ..QuirkFlags::default() };
+        let mut chip8 = Chip8Builder::new().rom(vec![0x81, 0x22]).quirks(quirks).build().unwrap();
+        chip8.v[0xf] = 5;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.v[0xf], 0);
+    }
+
+    #[test]
+    fn reg_xor() {
+        let chip8 = run_one(0x8123, &[(1, 0b1100), (2, 0b1010)]);
+        assert_eq!(chip8.v[1], 0b0110);
+    }
+
+    #[test]
+    fn reg_xor_vf_reset() {
+        let mut chip8 = Chip8::new_for_testing(&[0x81, 0x23]);
+        chip8.quirks.vf_reset = true;
+        chip8.v[0xf] = 5;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.v[0xf], 0);
+    }
+
+    #[test]
+    fn reg_add() {
+        let chip8 = run_one(0x8124, &[(1, 200), (2, 50)]);
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (250, 0));
+
+        let chip8 = run_one(0x8124, &[(1, 200), (2, 60)]);
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (4, 1));
+    }
+
+    #[test]
+    fn reg_add_into_vf() {
+        // the carry flag takes precedence over the sum
+        let chip8 = run_one(0x8f14, &[(1, 1), (0xf, 0xff)]);
+        assert_eq!(chip8.v[0xf], 1);
+    }
+
+    #[test]
+    fn reg_add_from_vf() {
+        let chip8 = run_one(0x81f4, &[(1, 1), (0xf, 2)]);
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (3, 0));
+    }
+
+    #[test]
+    fn reg_subtract() {
+        let chip8 = run_one(0x8125, &[(1, 50), (2, 20)]);
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (30, 1));
+
+        let chip8 = run_one(0x8125, &[(1, 20), (2, 50)]);
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (226, 0));
+
+        // equal values don't borrow
+        let chip8 = run_one(0x8125, &[(1, 20), (2, 20)]);
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (0, 1));
+    }
+
+    #[test]
+    fn reg_subtract_n() {
+        let chip8 = run_one(0x8127, &[(1, 20), (2, 50)]);
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (30, 1));
+
+        let chip8 = run_one(0x8127, &[(1, 50), (2, 20)]);
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (226, 0));
+    }
+
+    #[test]
+    fn shift_right() {
+        // by default the value comes from VY
+        let chip8 = run_one(0x8126, &[(1, 0), (2, 0b101)]);
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (0b10, 1));
+    }
+
+    // The arithmetic opcodes against Rust's own arithmetic, for every pair of values
+    proptest::proptest! {
+        #[test]
+        fn reg_add_matches_overflowing_add(a: u8, b: u8) {
+            let chip8 = run_one(0x8124, &[(1, a), (2, b)]);
+            let (sum, carry) = a.overflowing_add(b);
+            proptest::prop_assert_eq!((chip8.v[1], chip8.v[0xf]), (sum, carry as u8));
+        }
+
+        #[test]
+        fn reg_subtract_matches_overflowing_sub(a: u8, b: u8) {
+            // VF is 1 when there's no borrow
+            let chip8 = run_one(0x8125, &[(1, a), (2, b)]);
+            let (difference, borrow) = a.overflowing_sub(b);
+            proptest::prop_assert_eq!((chip8.v[1], chip8.v[0xf]), (difference, !borrow as u8));
+        }
+
+        #[test]
+        fn shift_right_halves(a: u8) {
+            let chip8 = run_one(0x8126, &[(2, a)]);
+            proptest::prop_assert_eq!((chip8.v[1], chip8.v[0xf]), (a / 2, a & 1));
+        }
+
+        #[test]
+        fn add_const_wraps_without_carry(a: u8, b: u8, flag: u8) {
+            let chip8 = run_one(0x7100 | b as u16, &[(1, a), (0xf, flag)]);
+            proptest::prop_assert_eq!((chip8.v[1], chip8.v[0xf]), (a.wrapping_add(b), flag));
+        }
+    }
+
+    #[test]
+    fn shift_right_in_place() {
+        let mut chip8 = Chip8::new_for_testing(&[0x81, 0x26]);
+        chip8.quirks.shift_quirk = true;
+        chip8.v[1] = 0b100;
+        chip8.v[2] = 0b101;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (0b10, 0));
+    }
+
+    #[test]
+    fn shift_left() {
+        let chip8 = run_one(0x812e, &[(1, 0), (2, 0b1000_0001)]);
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (0b10, 1));
+    }
+
+    #[test]
+    fn shift_left_in_place() {
+        let mut chip8 = Chip8::new_for_testing(&[0x81, 0x2e]);
+        chip8.quirks.shift_quirk = true;
+        chip8.v[1] = 0b0100_0001;
+        chip8.v[2] = 0b1000_0001;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!((chip8.v[1], chip8.v[0xf]), (0b1000_0010, 0));
+    }
+
+    #[test]
+    fn set_index() {
+        let chip8 = run_one(0xa123, &[]);
+        assert_eq!(chip8.i, 0x123);
+    }
+
+    #[test]
+    fn rand_is_masked() {
+        let chip8 = run_one(0xc100, &[(1, 0xff)]);
+        assert_eq!(chip8.v[1], 0);
+
+        let chip8 = run_one(0xc10f, &[]);
+        assert_eq!(chip8.v[1] & 0xf0, 0);
+    }
+
+    #[derive(Clone)]
+    struct Counter(u8);
+
+    impl RandomSource for Counter {
+        fn gen_byte(&mut self) -> u8 {
+            self.0 += 1;
+            self.0
+        }
+    }
+
+    #[test]
+    fn random_source() {
+        // RND V1, 0xff; RND V2, 0xfe
+        let mut chip8 = Chip8Builder::new()
+            .rom(vec![0xc1, 0xff, 0xc2, 0xfe])
+            .random_source(Box::new(Counter(0x40)))
+            .build()
+            .unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!((chip8.v[1], chip8.v[2]), (0x41, 0x42));
+    }
+
+    #[test]
+    fn clone() {
+        // RND V1, 0xff; LD V2, 7
+        let mut chip8 = Chip8Builder::new()
+            .rom(vec![0xc1, 0xff, 0x62, 0x07])
+            .random_seed(5)
+            .build()
+            .unwrap();
+        let mut snapshot = chip8.clone();
+        chip8.step_n(2).unwrap();
+        assert_eq!(snapshot.get_register(2), 0);
+        snapshot.step_n(2).unwrap();
+        assert_eq!(snapshot.get_register(1), chip8.get_register(1));
+        assert_eq!(snapshot.get_register(2), 7);
+    }
+
+    #[test]
+    fn random_seed() {
+        let rolls = |seed| {
+            // RND V1, 0xff, over and over
+            let mut chip8 = Chip8Builder::new()
+                .rom(vec![0xc1, 0xff, 0x12, 0x00])
+                .random_seed(seed)
+                .build()
+                .unwrap();
+            (0..16).map(|_| {
+                chip8.emulate_cycle().unwrap();
+                chip8.emulate_cycle().unwrap();
+                chip8.v[1]
+            }).collect::<Vec<u8>>()
+        };
+        assert_eq!(rolls(1), rolls(1));
+        assert_ne!(rolls(1), rolls(2));
+        assert!(rolls(0).iter().any(|roll| *roll != 0));
+    }
+
+    #[test]
+    fn builder_rejects_conflicting_settings() {
+        let error = |builder: Chip8Builder| {
+            matches!(builder.build(), Err(Chip8Error::InvalidSettings { .. }))
+        };
+        assert!(error(Chip8Builder::new().speed(0)));
+        assert!(error(Chip8Builder::new().speed(600).cycle_accurate(700)));
+        assert!(!error(Chip8Builder::new().speed(600).cycle_accurate(600)));
+        assert!(error(Chip8Builder::new().random_seed(1).random_source(Box::new(Counter(0)))));
+        assert!(matches!(
+            Chip8Builder::new().rom(vec![0; RAM_SIZE]).build(),
+            Err(Chip8Error::RomTooLarge { .. }),
+        ));
+    }
+
+    #[test]
+    fn draw_sprite() {
+        // draw the "0" glyph at (1, 2)
+        let mut chip8 = Chip8::new_for_testing(&[0xd0, 0x15]);
+        chip8.i = FONT_START as u16;
+        chip8.v[0] = 1;
+        chip8.v[1] = 2;
+        chip8.emulate_cycle().unwrap();
+
+        assert!(chip8.get_pixel(1, 2));
+        assert!(chip8.get_pixel(4, 2));
+        assert!(!chip8.get_pixel(2, 3));
+        assert!(!chip8.get_pixel(5, 2));
+        assert_eq!(chip8.v[0xf], 0);
+        assert_eq!(chip8.pc, 0x202);
+    }
+
+    #[test]
+    fn draw_sprite_collision() {
+        // drawing the same sprite twice erases it and sets VF
+        let mut chip8 = Chip8::new_for_testing(&[0xd0, 0x05, 0xd0, 0x05]);
+        chip8.i = FONT_START as u16;
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+
+        assert!(!chip8.get_pixel(0, 0));
+        assert_eq!(chip8.v[0xf], 1);
+    }
+
+    #[test]
+    fn draw_sprite_clips_at_edges() {
+        let mut chip8 = Chip8::new_for_testing(&[0xd0, 0x15]);
+        chip8.i = FONT_START as u16;
+        chip8.v[0] = (SCREEN_WIDTH - 2) as u8;
+        chip8.v[1] = (SCREEN_HEIGHT - 2) as u8;
+        chip8.emulate_cycle().unwrap();
+
+        assert!(chip8.get_pixel(SCREEN_WIDTH - 2, SCREEN_HEIGHT - 2));
+        assert!(!chip8.get_pixel(0, 0));
+        assert!(!chip8.get_pixel(0, SCREEN_HEIGHT - 2));
+    }
+
+    #[test]
+    fn draw_sprite_wraps_at_edges() {
+        let mut chip8 = Chip8::new_for_testing(&[0xd0, 0x15, 0xd0, 0x15]);
+        chip8.quirks.wrap_sprites = true;
+        chip8.i = FONT_START as u16;
+        chip8.v[0] = (SCREEN_WIDTH - 2) as u8;
+        chip8.v[1] = (SCREEN_HEIGHT - 2) as u8;
+        chip8.emulate_cycle().unwrap();
+
+        // the top right of the "0" wraps to the left edge, and the bottom wraps to the top
+        assert!(chip8.get_pixel(SCREEN_WIDTH - 2, SCREEN_HEIGHT - 2));
+        assert!(chip8.get_pixel(1, SCREEN_HEIGHT - 2));
+        assert!(!chip8.get_pixel(0, 0));
+        assert!(chip8.get_pixel(1, 2));
+        assert_eq!(chip8.v[0xf], 0);
+
+        // wrapped pixels still collide
+        chip8.emulate_cycle().unwrap();
+        assert!(!chip8.get_pixel(1, 2));
+        assert_eq!(chip8.v[0xf], 1);
+    }
+
+    #[test]
+    fn draw_sprite_out_of_memory() {
+        let mut chip8 = Chip8::new_for_testing(&[0xd0, 0x06]);
+        chip8.i = 0xffb;
+        assert_eq!(
+            chip8.emulate_cycle(),
+            Err(Chip8Error::MemoryOutOfBounds { addr: RAM_SIZE, i: 0xffb, pc: 0x200 }),
+        );
+    }
+
+    #[test]
+    fn skip_if_key() {
+        let mut chip8 = Chip8::new_for_testing(&[0xe1, 0x9e]);
+        chip8.v[1] = 5;
+        chip8.set_key(5, true).unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x204);
+
+        assert_eq!(run_one(0xe19e, &[(1, 5)]).pc, 0x202);
+    }
+
+    #[test]
+    fn skip_if_not_key() {
+        let mut chip8 = Chip8::new_for_testing(&[0xe1, 0xa1]);
+        chip8.v[1] = 5;
+        chip8.set_key(5, true).unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x202);
+
+        assert_eq!(run_one(0xe1a1, &[(1, 5)]).pc, 0x204);
+    }
+
+    #[test]
+    fn wait_for_key() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf3, 0x0a]);
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x200);
+
+        // nothing happens until the key is released
+        chip8.set_key(0xb, true).unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x200);
+
+        chip8.set_key(0xb, false).unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x202);
+        assert_eq!(chip8.v[3], 0xb);
+    }
+
+    #[test]
+    fn keys_stay_down_when_read() {
+        // SKP V1; SKP V2; (skipped); SKP V1
+        let mut chip8 = Chip8::new_for_testing(&[0xe1, 0x9e, 0xe2, 0x9e, 0, 0, 0xe1, 0x9e]);
+        chip8.v[1] = 5;
+        chip8.v[2] = 6;
+        chip8.set_key(5, true).unwrap();
+        chip8.set_key(6, true).unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x204);
+        chip8.pc = 0x202;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x206);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x20a);
+    }
+
+    #[test]
+    fn reset() {
+        let mut chip8 = Chip8::new_for_testing(&[0x60, 0x05, 0xa3, 0x00, 0x22, 0x00]);
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.set_color(3, 4, 1);
+        chip8.reset();
+
+        assert_eq!(chip8.v[0], 0);
+        assert_eq!(chip8.i, 0);
+        assert_eq!(chip8.pc, INSTRUCTIONS_START);
+        assert!(chip8.stack().is_empty());
+        assert!(!chip8.get_pixel(3, 4));
+        assert_eq!(chip8.ram[0x200..0x206], [0x60, 0x05, 0xa3, 0x00, 0x22, 0x00]);
+    }
+
+    #[test]
+    fn load_rom() {
+        let mut chip8 = Chip8::new_for_testing(&[0x60, 0x05, 0x61, 0x06]);
+        chip8.emulate_cycle().unwrap();
+        chip8.load_rom(vec![0x62, 0x07]).unwrap();
+
+        assert_eq!(chip8.pc, INSTRUCTIONS_START);
+        assert_eq!(chip8.v[0], 0);
+        assert_eq!(chip8.ram[0x200..0x204], [0x62, 0x07, 0, 0]);
+        assert_eq!(
+            chip8.load_rom(vec![0; RAM_SIZE]),
+            Err(Chip8Error::RomTooLarge { size: RAM_SIZE, max: RAM_SIZE - 0x200 }),
+        );
+    }
+
+    #[test]
+    fn delay_timer() {
+        // LD DT, V1; LD V2, DT
+        let mut chip8 = Chip8::new_for_testing(&[0xf1, 0x15, 0xf2, 0x07]);
+        chip8.v[1] = 100;
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.v[2], 100);
+    }
+
+    #[test]
+    fn sound_timer() {
+        let chip8 = run_one(0xf118, &[(1, 100)]);
+        assert_eq!(chip8.sound_timer.get_value(), 100);
+    }
+
+    #[test]
+    fn timers_tick() {
+        let mut chip8 = run_one(0xf118, &[(1, 2)]);
+        chip8.delay_timer.start(1);
+        chip8.tick_timers(1);
+        assert_eq!(chip8.delay_timer.get_value(), 0);
+        assert_eq!(chip8.sound_timer.get_value(), 1);
+
+        // timers stop at 0
+        chip8.tick_timers(1);
+        chip8.tick_timers(1);
+        assert_eq!(chip8.delay_timer.get_value(), 0);
+        assert_eq!(chip8.sound_timer.get_value(), 0);
+    }
+
+    #[test]
+    fn step() {
+        // SKP V0; JP 0x200; LD F, V1; DRW V2, V2, 5
+        let rom = vec![0xe0, 0x9e, 0x12, 0x00, 0xf1, 0x29, 0xd2, 0x25];
+        let mut chip8 = Chip8::new_for_testing(&rom);
+        chip8.v[1] = 8;
+        chip8.delay_timer.start(10);
+
+        chip8.step(0, 3).unwrap();
+        assert_eq!(chip8.pc, 0x202);
+        assert_eq!(chip8.delay_timer.get_value(), 7);
+
+        // back round, and this time key 0 is down so SKP skips the jump
+        chip8.step(0, 0).unwrap();
+        chip8.step(1, 0).unwrap();
+        chip8.step(1, 0).unwrap();
+        let display = chip8.step(0, 0).unwrap();
+        assert_eq!(display.len(), SCREEN_PIXELS);
+
+        // the sprite isn't shown until the end of the frame
+        assert!(display.iter().all(|pixel| *pixel == 0));
+        assert_eq!(chip8.dirty_rows(), 0);
+        chip8.end_frame();
+        assert_eq!(&chip8.display_buffer()[..4], [1, 1, 1, 1]);
+    }
+
+    #[cfg(feature = "tokio")]
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        runtime.block_on(future)
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn run_async_sends_frames() {
+        use async_run::run_async;
+        use futures::{stream, StreamExt};
+
+        // LD F, V0; DRW V0, V0, 5; EXIT
+        let mut chip8 = Chip8Builder::new()
+            .rom(vec![0xf0, 0x29, 0xd0, 0x05, 0x00, 0xfd])
+            .quirks(QuirkPreset::SuperChip.to_quirk_flags())
+            .build()
+            .unwrap();
+        let mut frames = chip8.display_frames();
+        block_on(run_async(&mut chip8, stream::pending())).unwrap();
+        assert!(chip8.is_halted());
+
+        let frame = block_on(frames.next()).unwrap();
+        assert_eq!((frame.width, frame.height), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        assert_eq!(&frame.pixels[..4], [1, 1, 1, 1]);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn run_async_takes_input() {
+        use async_run::{run_async, InputEvent};
+        use futures::stream;
+
+        // JP 0x200
+        let mut chip8 = Chip8::new_for_testing(&[0x12, 0x00]);
+        let events = stream::iter(vec![
+            InputEvent::Key { key: 3, down: true },
+            InputEvent::Key { key: 0x10, down: true },
+            InputEvent::Quit,
+        ]);
+        block_on(run_async(&mut chip8, events)).unwrap();
+        assert_eq!(chip8.active_keys(), 1 << 3);
+    }
+
+    #[test]
+    fn timers_tick_several_at_once() {
+        let mut chip8 = run_one(0xf118, &[(1, 10)]);
+        chip8.tick_timers(4);
+        assert_eq!(chip8.sound_timer.get_value(), 6);
+        chip8.tick_timers(200);
+        assert_eq!(chip8.sound_timer.get_value(), 0);
+    }
+
+    #[test]
+    fn cycle_accurate_timers() {
+        // LD V0, 5; LD DT, V0; JP 0x204
+        let rom = vec![0x60, 0x05, 0xf0, 0x15, 0x12, 0x04];
+        let mut chip8 = Chip8Builder::new().rom(rom).cycle_accurate(600).build().unwrap();
+
+        // at 600 instructions a second, the timers tick every 10
+        for _ in 0..10 {
+            chip8.emulate_cycle().unwrap();
+        }
+        assert_eq!(chip8.delay_timer.get_value(), 4);
+        for _ in 0..40 {
+            chip8.emulate_cycle().unwrap();
+        }
+        assert_eq!(chip8.delay_timer.get_value(), 0);
+    }
+
+    #[test]
+    fn add_reg_to_i() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf1, 0x1e]);
+        chip8.i = 0x100;
+        chip8.v[1] = 0x20;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!((chip8.i, chip8.v[0xf]), (0x120, 0));
+    }
+
+    #[test]
+    fn add_reg_to_i_overflow() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf1, 0x1e, 0xf1, 0x1e]);
+        chip8.i = 0xfff;
+        chip8.v[1] = 2;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!((chip8.i, chip8.v[0xf]), (1, 0));
+
+        chip8.quirks.i_overflow_vf = true;
+        chip8.i = 0xfff;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!((chip8.i, chip8.v[0xf]), (1, 1));
+    }
+
+    #[test]
+    fn set_char_location() {
+        let chip8 = run_one(0xf129, &[(1, 0xa)]);
+        assert_eq!(chip8.i as usize, FONT_START + 10 * 5);
+    }
+
+    #[test]
+    fn set_big_char_location() {
+        // LD HF, V1; DRW V0, V0, 10
+        let mut chip8 = Chip8::new_for_testing(&[0xf1, 0x30, 0xd0, 0x0a]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        chip8.v[1] = 8;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.i as usize, BIG_FONT_START + 8 * 10);
+        assert_eq!(chip8.ram[chip8.i as usize..][..10], BIG_FONT[80..90]);
+
+        chip8.emulate_cycle().unwrap();
+        assert!(chip8.get_pixel(0, 0));
+        assert!(!chip8.get_pixel(2, 2));
+        assert!(chip8.get_pixel(7, 9));
+    }
+
+    #[test]
+    fn store_and_load_rpl_flags() {
+        // LD R, V2; LD V0, 0; LD V1, 0; LD V2, 0; LD V1, R
+        let mut chip8 = Chip8::new_for_testing(&[0xf2, 0x75, 0x60, 0x00, 0x61, 0x00, 0x62, 0x00,
+                                                 0xf1, 0x85]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        chip8.v[..3].copy_from_slice(&[1, 2, 3]);
+        for _ in 0..5 {
+            chip8.emulate_cycle().unwrap();
+        }
+        assert_eq!(chip8.rpl_flags[..3], [1, 2, 3]);
+        assert_eq!(chip8.v[..3], [1, 2, 0]);
+    }
+
+    #[test]
+    fn rpl_flags_survive_reset() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf0, 0x75]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        chip8.v[0] = 0x42;
+        chip8.emulate_cycle().unwrap();
+        chip8.reset();
+        assert_eq!(chip8.rpl_flags[0], 0x42);
+    }
+
+    #[test]
+    fn only_eight_rpl_flags() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf8, 0x75]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        assert_eq!(
+            chip8.emulate_cycle(),
+            Err(Chip8Error::IllegalOpcode { opcode: 0xf875, pc: 0x200 }),
+        );
+    }
+
+    #[test]
+    fn halt() {
+        // EXIT; LD V0, 1
+        let mut chip8 = Chip8::new_for_testing(&[0x00, 0xfd, 0x60, 0x01]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert!(chip8.is_halted());
+        assert_eq!(chip8.pc, 0x200);
+        assert_eq!(chip8.v[0], 0);
+
+        chip8.reset();
+        assert!(!chip8.is_halted());
+    }
+
+    #[test]
+    fn set_key() {
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        chip8.set_key(0xf, true).unwrap();
+        assert!(chip8.keys[0xf] == Key::Down);
+        chip8.set_key(0xf, false).unwrap();
+        assert!(chip8.keys[0xf] == Key::Up);
+        assert_eq!(chip8.set_key(0x10, true), Err(Chip8Error::InvalidKey { key: 0x10 }));
+    }
+
+    #[test]
+    fn display_string() {
+        // LD I, <the font's 0>; DRW V0, V0, 5
+        let chip8 = run_program(&[0xa000 | FONT_START as u16, 0xd005], 2);
+        let display = chip8.get_display_string();
+        let lines: Vec<&str> = display.lines().collect();
+        assert_eq!(lines.len(), SCREEN_HEIGHT);
+        assert!(lines.iter().all(|line| line.len() == SCREEN_WIDTH));
+        assert_eq!(lines[0], format!("####{}", " ".repeat(SCREEN_WIDTH - 4)));
+        assert_eq!(lines[1], format!("#  #{}", " ".repeat(SCREEN_WIDTH - 4)));
+        assert_eq!(format!("{}", chip8), display);
+    }
+
+    #[test]
+    fn debug() {
+        // LD V0, 0x2a; LD I, 0x300; CALL 0x208; LD DT, V0
+        let chip8 = run_program(&[0x602a, 0xa300, 0x2208, 0x0000, 0xf015], 4);
+        let debug = format!("{:?}", chip8);
+        assert!(debug.starts_with("Chip8 { v: [0x2a, 0x00, "));
+        assert!(debug.ends_with("i: 0x0300, pc: 0x020a, stack: [0x0204], delay_timer: 42, \
+                                 sound_timer: 0, .. }"));
+    }
+
+    #[test]
+    fn instruction_count() {
+        // JP 0x200
+        let mut chip8 = Chip8::new_for_testing(&[0x12, 0x00]);
+        assert_eq!(chip8.instruction_count(), 0);
+        chip8.step_n(3).unwrap();
+        assert_eq!(chip8.instruction_count(), 3);
+        chip8.reset();
+        assert_eq!(chip8.instruction_count(), 0);
+
+        // EXIT
+        let mut chip8 = Chip8Builder::new().rom(vec![0x00, 0xfd]).machine(Machine::SuperChip11)
+            .build()
+            .unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.instruction_count(), 1);
+    }
+
+    #[test]
+    fn check_invariants() {
+        // CALL 0x204; CLS; LD V0, 1; LD V0, 2
+        let mut chip8 = run_program(&[0x2204, 0x00e0, 0x6001, 0x6002], 2);
+        assert_eq!(chip8.check_invariants(), Ok(()));
+
+        chip8.sp = STACK_SIZE as u8 + 1;
+        let err = Chip8Error::InvariantViolated {
+            reason: "the stack pointer is past the end of the stack",
+            pc: 0x206,
+        };
+        assert_eq!(chip8.check_invariants(), Err(err));
+
+        chip8.sp = 1;
+        chip8.active_planes = 4;
+        let result = chip8.emulate_cycle();
+        if cfg!(debug_assertions) {
+            let err = Chip8Error::InvariantViolated {
+                reason: "a plane is selected that doesn't exist",
+                pc: 0x208,
+            };
+            assert_eq!(result, Err(err));
+        } else {
+            assert_eq!(result, Ok(()));
+        }
+
+        chip8.active_planes = 1;
+        chip8.i = RAM_SIZE as u16;
+        let err = Chip8Error::InvariantViolated {
+            reason: "I is past the end of memory",
+            pc: 0x208,
+        };
+        assert_eq!(chip8.check_invariants(), Err(err));
+    }
+
+    #[cfg(feature = "lsp")]
+    #[test]
+    fn lsp_hover_past_non_ascii() {
+        use tower_lsp::lsp_types::*;
+        use tower_lsp::{LanguageServer, LspService};
+
+        let (service, _socket) = LspService::new(lsp::Backend::new);
+        let backend = service.inner();
+        let uri = Url::parse("file:///test.asm").unwrap();
+        let hover = |character| {
+            let position = Position::new(0, character);
+            let params = HoverParams {
+                text_document_position_params: TextDocumentPositionParams::new(
+                    TextDocumentIdentifier::new(uri.clone()), position),
+                work_done_progress_params: WorkDoneProgressParams::default(),
+            };
+            backend.hover(params)
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let text = "; café CLS".to_string();
+            let item = TextDocumentItem::new(uri.clone(), "chip8".to_string(), 0, text);
+            backend.did_open(DidOpenTextDocumentParams { text_document: item }).await;
+
+            // character 6 is just after the é, which is two bytes but one UTF-16 code unit
+            assert_eq!(hover(6).await, Ok(None));
+            let markup = match hover(8).await {
+                Ok(Some(Hover { contents: HoverContents::Markup(markup), .. })) => markup,
+                other => panic!("no hover for CLS: {:?}", other),
+            };
+            assert!(markup.value.contains("CLS clears the display"));
+        });
+
+        // and the other way, columns after the é are a code unit further on than in bytes
+        let range = lsp::range("DB é, 300", 1, &(7..10));
+        assert_eq!((range.start, range.end), (Position::new(0, 6), Position::new(0, 9)));
+    }
+
+    #[test]
+    fn check_pc() {
+        // whether instr at old_pc can leave PC at pc
+        let check = |old_pc, instr, pc| {
+            let mut chip8 = xo_chip(&[]);
+            chip8.pc = pc;
+            chip8.check_pc(old_pc, instr)
+        };
+        let err = Chip8Error::InvariantViolated {
+            reason: "PC didn't move on to the next instruction",
+            pc: 0x200,
+        };
+
+        // LD V0, 1 staying put, or going back
+        assert_eq!(check(0x200, 0x6001, 0x200), Err(err));
+        assert!(check(0x204, 0x6001, 0x200).is_err());
+        // LD V0, 1 and a skip from the top of memory, and a jump back
+        assert_eq!(check(0xfffe, 0x6001, 0), Ok(()));
+        assert_eq!(check(0xfffc, 0x3000, 0), Ok(()));
+        assert_eq!(check(0x300, 0x1200, 0x200), Ok(()));
+        // LD V0, K waiting for a key
+        assert_eq!(check(0x200, 0xf00a, 0x200), Ok(()));
+    }
+
+    #[test]
+    fn max_cycles() {
+        // JP 0x200
+        let mut chip8 = Chip8Builder::new().rom(vec![0x12, 0x00]).max_cycles(3).build().unwrap();
+        assert_eq!(chip8.step_n(3), Ok(3));
+        assert_eq!(chip8.emulate_cycle(), Err(Chip8Error::CycleLimitReached { cycles: 3 }));
+        assert_eq!(chip8.cycles, 3);
+    }
+
+    #[test]
+    fn lenient() {
+        // SYS 0x123; LD V0, 1
+        let rom = vec![0x01, 0x23, 0x60, 0x01];
+        let mut chip8 = Chip8Builder::new().rom(rom.clone()).build().unwrap();
+        let err = Chip8Error::UnknownOpcode { opcode: 0x0123, pc: 0x200 };
+        assert_eq!(chip8.emulate_cycle(), Err(err));
+
+        let mut chip8 = Chip8Builder::new().rom(rom).lenient(true).build().unwrap();
+        chip8.step_n(2).unwrap();
+        assert_eq!((chip8.get_pc(), chip8.get_register(0)), (0x204, 1));
+    }
+
+    #[test]
+    fn crash_report() {
+        // LD V0, 5; SYS 0x123
+        let mut chip8 = Chip8::new_for_testing(&[0x60, 0x05, 0x01, 0x23]);
+        let mut history = crash::History::new();
+        history.record(&chip8);
+        chip8.emulate_cycle().unwrap();
+        history.record(&chip8);
+        let err = chip8.emulate_cycle().unwrap_err();
+
+        let report = crash::report(&chip8, &err, &history);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(err, Chip8Error::UnknownOpcode { opcode: 0x0123, pc: 0x202 });
+        assert_eq!(lines[0], err.to_string());
+        assert!(lines[2].starts_with("V0=05 V1=00"));
+        assert_eq!(lines[3], "I=0x0000");
+        assert_eq!(lines[4], "stack (innermost last): empty");
+        // 16 bytes either side of PC
+        assert!(lines[7].starts_with("0x01f2: 00 00"));
+        assert!(lines[8].starts_with("0x0202: 01 23 00"));
+        let history = &lines[lines.len() - 2..];
+        assert_eq!(history, ["0x0200  6005  LD V0, 0x05", "0x0202  0123  SYS 0x123"]);
+    }
+
+    #[test]
+    fn rewind_buffer() {
+        // ADD V0, 1; JP 0x200
+        let mut chip8 = Chip8::new_for_testing(&[0x70, 0x01, 0x12, 0x00]);
+        let mut buffer = RewindBuffer::new(3);
+        assert_eq!(buffer.summary(), format!("rewind [{}]", "-".repeat(20)));
+        for _ in 0..5 {
+            chip8.step_n(2).unwrap();
+            buffer.record(&chip8);
+        }
+        assert_eq!(buffer.summary(), format!("rewind [{}]", "#".repeat(20)));
+
+        let rewound: Vec<u8> = (0..4)
+            .map_while(|_| buffer.rewind())
+            .map(|snapshot| snapshot.get_register(0))
+            .collect();
+        assert_eq!(rewound, [5, 4, 3]);
+        assert_eq!(chip8.get_register(0), 5);
+    }
+
+    #[test]
+    fn clear_keys() {
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        chip8.set_key(1, true).unwrap();
+        chip8.set_key(0xc, true).unwrap();
+        chip8.clear_keys();
+        assert_eq!(chip8.active_keys(), 0);
+    }
+
+    #[test]
+    fn active_keys() {
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        assert_eq!(chip8.active_keys(), 0);
+        chip8.set_key(2, true).unwrap();
+        chip8.set_key(0xf, true).unwrap();
+        assert_eq!(chip8.active_keys(), 0x8004);
+        assert!(chip8.is_key_down(2) && !chip8.is_key_down(3) && !chip8.is_key_down(0x12));
+    }
+
+    #[test]
+    fn run_for_duration() {
+        // LD V0, 40; LD DT, V0; JP 0x204
+        let mut chip8 = Chip8::new_for_testing(&[0x60, 0x28, 0xf0, 0x15, 0x12, 0x04]);
+        let second = time::Duration::from_secs(1);
+        assert_eq!(chip8.run_for_duration(second / 2, 600), Ok(300));
+        assert_eq!(chip8.cycles, 300);
+        assert_eq!(chip8.delay_timer.get_value(), 10);
+        assert_eq!(chip8.run_for_duration(time::Duration::ZERO, 600), Ok(0));
+
+        // EXIT
+        let mut chip8 = Chip8::new_for_testing(&[0x00, 0xfd]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        assert_eq!(chip8.run_for_duration(second, 600), Ok(1));
+
+        // RET
+        let mut chip8 = Chip8::new_for_testing(&[0x00, 0xee]);
+        let underflow = Chip8Error::StackUnderflow { pc: 0x200 };
+        assert_eq!(chip8.run_for_duration(second, 600), Err(underflow));
+    }
+
+    #[test]
+    fn step_n() {
+        // LD V0, 1; ADD V0, 1; EXIT; ADD V0, 1
+        let rom = [0x60, 0x01, 0x70, 0x01, 0x00, 0xfd, 0x70, 0x01];
+        let mut chip8 = Chip8::new_for_testing(&rom);
+        chip8.quirks.machine = Machine::SuperChip11;
+        assert_eq!(chip8.step_n(2), Ok(2));
+        assert_eq!(chip8.v[0], 2);
+        assert_eq!(chip8.step_n(0), Ok(0));
+        assert_eq!(chip8.step_n(5), Err(Chip8Error::Halted { cycles: 1 }));
+        assert_eq!(chip8.v[0], 2);
+        assert_eq!(chip8.step_n(1), Err(Chip8Error::Halted { cycles: 0 }));
+
+        // RET
+        let mut chip8 = Chip8::new_for_testing(&[0x00, 0xee]);
+        assert_eq!(chip8.step_n(3), Err(Chip8Error::StackUnderflow { pc: 0x200 }));
+    }
+
+    #[test]
+    fn super_chip_instructions_need_super_chip() {
+        assert_eq!(
+            Chip8::new_for_testing(&[0x00, 0xfb]).emulate_cycle(),
+            Err(Chip8Error::UnknownOpcode { opcode: 0x00fb, pc: 0x200 }),
+        );
+    }
+
+    #[test]
+    fn scroll_right() {
+        let mut chip8 = Chip8::new_for_testing(&[0x00, 0xfb, 0x00, 0xff, 0x00, 0xfb]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        chip8.set_color(0, 0, 1);
+        chip8.set_color(SCREEN_WIDTH - 1, 1, 1);
+        chip8.end_frame();
+        chip8.clear_dirty();
+
+        // 2 pixels in low resolution; the one at the right edge is lost
+        chip8.emulate_cycle().unwrap();
+        chip8.end_frame();
+        assert!(!chip8.get_pixel(0, 0));
+        assert!(chip8.get_pixel(2, 0));
+        assert!(!chip8.get_pixel(SCREEN_WIDTH - 1, 1));
+        assert_eq!(chip8.back_pixels.iter().filter(|pixel| **pixel != 0).count(), 1);
+        assert_eq!(chip8.dirty_rows(), 0b11);
+
+        // 4 in high resolution
+        chip8.emulate_cycle().unwrap();
+        chip8.set_color(1, 5, 1);
+        chip8.emulate_cycle().unwrap();
+        assert!(!chip8.get_pixel(1, 5));
+        assert!(chip8.get_pixel(5, 5));
+    }
+
+    #[test]
+    fn scroll_left() {
+        let mut chip8 = Chip8::new_for_testing(&[0x00, 0xfc, 0x00, 0xff, 0x00, 0xfc]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        chip8.set_color(1, 0, 1);
+        chip8.set_color(SCREEN_WIDTH - 1, 1, 1);
+
+        // 2 pixels in low resolution; the one at the left edge is lost
+        chip8.emulate_cycle().unwrap();
+        assert!(chip8.get_pixel(SCREEN_WIDTH - 3, 1));
+        assert!(!chip8.get_pixel(SCREEN_WIDTH - 1, 1));
+        assert!(!chip8.get_pixel(SCREEN_WIDTH - 2, 1));
+        assert_eq!(chip8.back_pixels.iter().filter(|pixel| **pixel != 0).count(), 1);
+
+        // 4 in high resolution
+        chip8.emulate_cycle().unwrap();
+        chip8.set_color(HI_RES_WIDTH - 1, 5, 1);
+        chip8.emulate_cycle().unwrap();
+        assert!(chip8.get_pixel(HI_RES_WIDTH - 5, 5));
+        assert!((HI_RES_WIDTH - 4..HI_RES_WIDTH).all(|x| !chip8.get_pixel(x, 5)));
+    }
+
+    // A SUPER-CHIP machine with pixels lit in rows 0 and 1 (one pixel each)
+    fn super_chip_with_rows() -> Chip8 {
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        chip8.set_color(2, 0, 1);
+        chip8.set_color(5, 1, 1);
+        chip8.end_frame();
+        chip8.clear_dirty();
+        chip8
+    }
+
+    #[test]
+    fn scroll_down_zero_rows() {
+        let mut chip8 = super_chip_with_rows();
+        chip8.scroll_rows_down(0);
+        chip8.end_frame();
+        assert!(chip8.get_pixel(2, 0));
+        assert!(chip8.get_pixel(5, 1));
+        assert_eq!(chip8.dirty_rows(), 0);
+    }
+
+    #[test]
+    fn scroll_down_one_row() {
+        let mut chip8 = super_chip_with_rows();
+        chip8.scroll_rows_down(1);
+        chip8.end_frame();
+        assert!(!chip8.get_pixel(2, 0));
+        assert!(chip8.get_pixel(2, 1));
+        assert!(!chip8.get_pixel(5, 1));
+        assert!(chip8.get_pixel(5, 2));
+        assert_eq!(chip8.dirty_rows(), 0b111);
+    }
+
+    #[test]
+    fn scroll_down_whole_screen() {
+        for n in [SCREEN_HEIGHT, SCREEN_HEIGHT + 1] {
+            let mut chip8 = super_chip_with_rows();
+            chip8.scroll_rows_down(n);
+            chip8.end_frame();
+            assert!(chip8.back_pixels.iter().all(|pixel| *pixel == 0));
+            assert_eq!(chip8.dirty_rows(), 0b11);
+        }
+    }
+
+    #[test]
+    fn scroll_down_instruction() {
+        let mut chip8 = Chip8::new_for_testing(&[0x00, 0xc3]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        chip8.set_color(5, 1, 1);
+        chip8.emulate_cycle().unwrap();
+        assert!(chip8.get_pixel(5, 4));
+        assert_eq!(chip8.pc, 0x202);
+    }
+
+    #[test]
+    fn redrawing_within_a_frame_queues_nothing() {
+        // LD F, V0; DRW V0, V0, 5; and then erase it and draw it again
+        let mut chip8 = Chip8::new_for_testing(&[0xf0, 0x29, 0xd0, 0x05, 0xd0, 0x05, 0xd0, 0x05]);
+        chip8.end_frame();
+        chip8.clear_dirty();
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.end_frame();
+        assert_eq!(chip8.dirty_rows(), 0b11111);
+
+        chip8.clear_dirty();
+        chip8.emulate_cycle().unwrap();
+        assert!(!chip8.get_pixel(0, 0));
+        assert_eq!(chip8.display_buffer()[0], 1);
+        chip8.emulate_cycle().unwrap();
+        chip8.end_frame();
+        assert_eq!(chip8.dirty_rows(), 0);
+        assert_eq!(chip8.display_buffer()[0], 1);
+    }
+
+    #[test]
+    fn sprites_collide_after_scrolling() {
+        // DRW V0, V0, 1; SCD 1; DRW V0, V0, 1; DRW V0, V1, 1
+        let rom = [0xd0, 0x01, 0x00, 0xc1, 0xd0, 0x01, 0xd0, 0x11];
+        let mut chip8 = Chip8::new_for_testing(&rom);
+        chip8.quirks.machine = Machine::SuperChip11;
+        chip8.i = FONT_START as u16;
+        chip8.v[1] = 1;
+
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        // the first sprite has moved down out of the way
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.v[0xf], 0);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.v[0xf], 1);
+        assert!(!chip8.get_pixel(0, 1));
+    }
+
+    #[test]
+    fn high_resolution() {
+        // HIGH; DRW V0, V1, 0 (a 16x16 sprite); LOW
+        let mut chip8 = Chip8::new_for_testing(&[0x00, 0xff, 0xd0, 0x10, 0x00, 0xfe]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        chip8.i = 0x300;
+        chip8.ram[0x300..0x320].copy_from_slice(&[0xff; 32]);
+        chip8.v[0] = 120;
+        chip8.v[1] = 56;
+
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.screen_size(), (HI_RES_WIDTH, HI_RES_HEIGHT));
+        assert_eq!(chip8.display_size(), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        chip8.end_frame();
+        assert_eq!(chip8.display_size(), (HI_RES_WIDTH, HI_RES_HEIGHT));
+        assert_eq!(chip8.dirty_rows(), u64::MAX);
+
+        chip8.emulate_cycle().unwrap();
+        assert!(chip8.get_pixel(127, 63));
+        assert!(chip8.get_pixel(120, 56));
+        assert!(!chip8.get_pixel(119, 63));
+        assert_eq!(chip8.get_display_string().lines().count(), HI_RES_HEIGHT);
+
+        // switching back clears the screen
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.screen_size(), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        assert!(chip8.back_pixels.iter().all(|pixel| *pixel == 0));
+    }
+
+    #[test]
+    fn set_bcd() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf1, 0x33]);
+        chip8.i = 0x300;
+        chip8.v[1] = 254;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(&chip8.ram[0x300..0x303], &[2, 5, 4]);
+        assert_eq!(chip8.i, 0x300);
+    }
+
+    #[test]
+    fn set_bcd_out_of_memory() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf1, 0x33]);
+        chip8.i = 0xffe;
+        assert!(chip8.emulate_cycle().is_err());
+    }
+
+    #[test]
+    fn reg_store() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf2, 0x55]);
+        chip8.i = 0x300;
+        chip8.v[0] = 1;
+        chip8.v[1] = 2;
+        chip8.v[2] = 3;
+        chip8.v[3] = 4;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(&chip8.ram[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(chip8.i, 0x300);
+    }
+
+    #[test]
+    fn reg_store_increments_i() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf2, 0x55]);
+        chip8.quirks.load_store_quirk = true;
+        chip8.i = 0x300;
+        chip8.v[0..3].copy_from_slice(&[1, 2, 3]);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(&chip8.ram[0x300..0x303], &[1, 2, 3]);
+        assert_eq!(chip8.i, 0x303);
+    }
+
+    #[test]
+    fn reg_store_at_end_of_memory_wraps_i() {
+        // LD I, LONG 0xffff; LD [I], V0
+        let mut chip8 = xo_chip(&[0xf0, 0x00, 0xff, 0xff, 0xf0, 0x55]);
+        chip8.quirks.load_store_quirk = true;
+        chip8.v[0] = 7;
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.ram[0xffff], 7);
+        assert_eq!(chip8.i, 0);
+
+        // LD [I], V2 with the last three bytes of CHIP-8 memory
+        let mut chip8 = Chip8::new_for_testing(&[0xf2, 0x55]);
+        chip8.quirks.load_store_quirk = true;
+        chip8.i = 0xffd;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.i, 0);
+    }
+
+    #[test]
+    fn memory_watchpoints() {
+        // LD B, V0; LD [I], V1
+        let mut chip8 = Chip8::new_for_testing(&[0xf0, 0x33, 0xf1, 0x55]);
+        chip8.memory_watchpoints.insert(0x301);
+        chip8.i = 0x300;
+        chip8.v[0] = 123;
+        chip8.v[1] = 9;
+
+        chip8.emulate_cycle().unwrap();
+        assert!(matches!(chip8.watch_hits[..],
+                         [WatchHit::Memory { addr: 0x301, old: 0, new: 2 }]));
+
+        // the hit is reported even though the value stays the same
+        chip8.watch_hits.clear();
+        chip8.v[1] = 2;
+        chip8.emulate_cycle().unwrap();
+        assert!(matches!(chip8.watch_hits[..],
+                         [WatchHit::Memory { addr: 0x301, old: 2, new: 2 }]));
+    }
+
+    #[test]
+    fn reg_load() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf2, 0x65]);
+        chip8.i = 0x300;
+        chip8.ram[0x300..0x304].copy_from_slice(&[1, 2, 3, 4]);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(&chip8.v[0..4], &[1, 2, 3, 0]);
+        assert_eq!(chip8.i, 0x300);
+    }
+
+    #[test]
+    fn reg_load_increments_i() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf2, 0x65]);
+        chip8.quirks.load_store_quirk = true;
+        chip8.i = 0x300;
+        chip8.ram[0x300..0x303].copy_from_slice(&[1, 2, 3]);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(&chip8.v[0..3], &[1, 2, 3]);
+        assert_eq!(chip8.i, 0x303);
+    }
+
+    #[test]
+    fn reg_load_at_end_of_memory_wraps_i() {
+        // LD I, LONG 0xfffe; LD V1, [I]
+        let mut chip8 = xo_chip(&[0xf0, 0x00, 0xff, 0xfe, 0xf1, 0x65]);
+        chip8.quirks.load_store_quirk = true;
+        chip8.ram[0xfffe..].copy_from_slice(&[1, 2]);
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(&chip8.v[0..2], &[1, 2]);
+        assert_eq!(chip8.i, 0);
+    }
+
+    #[test]
+    fn reg_load_from_vf() {
+        // FF65 fills every register, including VF
+        let mut chip8 = Chip8::new_for_testing(&[0xff, 0x65]);
+        chip8.i = 0x300;
+        chip8.ram[0x30f] = 0x42;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.v[0xf], 0x42);
+    }
+
+    #[test]
+    fn draw_sprite_in_both_planes() {
+        // PLANE 3; DRW V0, V0, 1, with one row for each plane
+        let mut chip8 = Chip8::new_for_testing(&[0xf3, 0x01, 0xd0, 0x01]);
+        chip8.quirks.machine = Machine::XoChip;
+        chip8.i = 0x300;
+        chip8.ram[0x300..0x302].copy_from_slice(&[0b1100_0000, 0b1010_0000]);
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+
+        assert_eq!(chip8.color(0, 0), 3);
+        assert_eq!(chip8.color(1, 0), 1);
+        assert_eq!(chip8.color(2, 0), 2);
+        assert_eq!(chip8.color(3, 0), 0);
+        assert_eq!(chip8.v[0xf], 0);
+    }
+
+    #[test]
+    fn draw_sprite_collision_in_second_plane() {
+        // PLANE 2; DRW V0, V0, 1
+        let mut chip8 = Chip8::new_for_testing(&[0xf2, 0x01, 0xd0, 0x01]);
+        chip8.quirks.machine = Machine::XoChip;
+        chip8.i = 0x300;
+        chip8.ram[0x300] = 0b1100_0000;
+        chip8.set_color(0, 0, 1);
+        chip8.set_color(1, 0, 3);
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+
+        assert_eq!(chip8.color(0, 0), 3);
+        assert_eq!(chip8.color(1, 0), 1);
+        assert_eq!(chip8.v[0xf], 1);
+    }
+
+    #[test]
+    fn clear_screen_clears_selected_planes() {
+        // PLANE 1; CLS
+        let mut chip8 = Chip8::new_for_testing(&[0xf1, 0x01, 0x00, 0xe0]);
+        chip8.quirks.machine = Machine::XoChip;
+        chip8.set_color(0, 0, 3);
+        chip8.set_color(1, 0, 2);
+        chip8.end_frame();
+        chip8.clear_dirty();
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.end_frame();
+
+        assert_eq!(chip8.color(0, 0), 2);
+        assert_eq!(chip8.color(1, 0), 2);
+        assert_eq!(chip8.dirty_rows(), 1);
+    }
+
+    #[test]
+    fn scroll_selected_planes() {
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        chip8.quirks.machine = Machine::XoChip;
+        chip8.active_planes = 2;
+        chip8.set_color(0, 0, 3);
+        chip8.end_frame();
+        chip8.clear_dirty();
+        chip8.scroll_rows_down(1);
+        chip8.end_frame();
+
+        assert_eq!(chip8.color(0, 0), 1);
+        assert_eq!(chip8.color(0, 1), 2);
+        assert_eq!(chip8.dirty_rows(), 0b11);
+    }
+
+    #[test]
+    fn select_planes_needs_xo_chip() {
+        let mut chip8 = Chip8::new_for_testing(&[0xf3, 0x01]);
+        chip8.quirks.machine = Machine::SuperChip11;
+        assert_eq!(
+            chip8.emulate_cycle(),
+            Err(Chip8Error::UnknownOpcode { opcode: 0xf301, pc: 0x200 }),
+        );
+    }
+
+    fn xo_chip(rom: &[u8]) -> Chip8 {
+        let quirks = QuirkFlags { machine: Machine::XoChip, ..QuirkFlags::default() };
+        Chip8Builder::new().rom(rom.to_vec()).quirks(quirks).build().unwrap()
+    }
+
+    #[test]
+    fn xo_chip_memory() {
+        let mut chip8 = xo_chip(&[]);
+        assert_eq!(chip8.ram.len(), XO_CHIP_RAM_SIZE);
+        let max = XO_CHIP_RAM_SIZE - INSTRUCTIONS_START as usize;
+        assert_eq!(chip8.load_rom(vec![0; max]), Ok(()));
+    }
+
+    #[test]
+    fn set_long_index() {
+        // LD I, LONG 0xabcd; LD V0, [I]
+        let mut chip8 = xo_chip(&[0xf0, 0x00, 0xab, 0xcd, 0xf0, 0x65]);
+        chip8.ram[0xabcd] = 0x42;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.i, 0xabcd);
+        assert_eq!(chip8.pc, 0x204);
+
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.v[0], 0x42);
+    }
+
+    #[test]
+    fn skip_over_long_index() {
+        // SE V0, 0; LD I, LONG 0xabcd; LD V1, 1
+        let mut chip8 = xo_chip(&[0x30, 0x00, 0xf0, 0x00, 0xab, 0xcd, 0x61, 0x01]);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x206);
+    }
+
+    #[test]
+    fn pc_wraps_at_top_of_xo_chip_memory() {
+        // LD V0, 1 in the last two bytes of memory
+        let mut chip8 = xo_chip(&[]);
+        chip8.ram[0xfffe..].copy_from_slice(&[0x60, 0x01]);
+        chip8.pc = 0xfffe;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.v[0], 1);
+        assert_eq!(chip8.pc, 0);
+    }
+
+    #[test]
+    fn skip_at_top_of_xo_chip_memory() {
+        // SE V0, 0 skips the instruction at 0 as well as itself
+        let mut chip8 = xo_chip(&[]);
+        chip8.ram[0xfffe..].copy_from_slice(&[0x30, 0x00]);
+        chip8.pc = 0xfffe;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 2);
+
+        // and a skip over an LD I, LONG at the top of memory lands after its operand, at 0
+        let mut chip8 = xo_chip(&[]);
+        chip8.ram[0xfffa..].copy_from_slice(&[0x30, 0x00, 0xf0, 0x00, 0xab, 0xcd]);
+        chip8.pc = 0xfffa;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0);
+    }
+
+    #[test]
+    fn long_index_at_top_of_xo_chip_memory() {
+        // LD I, LONG 0xabcd in the last four bytes of memory
+        let mut chip8 = xo_chip(&[]);
+        chip8.ram[0xfffc..].copy_from_slice(&[0xf0, 0x00, 0xab, 0xcd]);
+        chip8.pc = 0xfffc;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.i, 0xabcd);
+        assert_eq!(chip8.pc, 0);
+
+        // and with only the instruction itself at the top, the operand is at 0
+        let mut chip8 = xo_chip(&[]);
+        chip8.ram[0xfffe..].copy_from_slice(&[0xf0, 0x00]);
+        chip8.ram[..2].copy_from_slice(&[0x12, 0x34]);
+        chip8.pc = 0xfffe;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.i, 0x1234);
+        assert_eq!(chip8.pc, 2);
+    }
+
+    #[test]
+    fn add_reg_to_i_past_xo_chip_memory() {
+        let mut chip8 = xo_chip(&[0xf1, 0x1e]);
+        chip8.quirks.i_overflow_vf = true;
+        chip8.i = 0xfff0;
+        chip8.v[1] = 0x20;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.i, 0x10);
+        assert_eq!(chip8.v[0xf], 1);
+    }
+
+    #[test]
+    fn load_audio_pattern() {
+        // LD AUDIO, [I]; LD PITCH, V0
+        let mut chip8 = xo_chip(&[0xf0, 0x02, 0xf0, 0x3a]);
+        chip8.i = 0x300;
+        chip8.ram[0x300..0x310].copy_from_slice(&[0xf0; 16]);
+        assert_eq!(chip8.waveform(), None);
+
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.waveform(), Some(([0xf0; 16], 4000.)));
+
+        chip8.v[0] = DEFAULT_PITCH + 48;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.waveform(), Some(([0xf0; 16], 8000.)));
+    }
+
+    #[test]
+    fn quirk_presets() {
+        let chip8 = QuirkPreset::Chip8.to_quirk_flags();
+        assert!(chip8.load_store_quirk && chip8.wrap_sprites);
+        assert!(!chip8.shift_quirk && !chip8.jump_quirk);
+
+        let super_chip = QuirkPreset::SuperChip.to_quirk_flags();
+        assert_eq!(super_chip.machine, Machine::SuperChip11);
+        assert!(super_chip.vf_reset && super_chip.shift_quirk && super_chip.jump_quirk);
+        assert!(!super_chip.load_store_quirk && !super_chip.wrap_sprites);
+    }
+
+    #[test]
+    fn build_for_machine() {
+        // SCR; HIGH
+        let rom = vec![0x00, 0xfb, 0x00, 0xff];
+        let mut chip8 = Chip8Builder::new()
+            .rom(rom.clone())
+            .machine(Machine::SuperChip10)
+            .build()
+            .unwrap();
+        assert!(chip8.quirks.shift_quirk && chip8.quirks.jump_quirk);
+        assert_eq!(
+            chip8.emulate_cycle(),
+            Err(Chip8Error::UnknownOpcode { opcode: 0x00fb, pc: 0x200 }),
+        );
+        chip8.pc = 0x202;
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.screen_size(), (HI_RES_WIDTH, HI_RES_HEIGHT));
+
+        let chip8 = Chip8Builder::new().rom(rom).machine(Machine::XoChip).build().unwrap();
+        assert_eq!(chip8.ram.len(), Machine::XoChip.max_ram());
+        assert_eq!(chip8.quirks.machine, Machine::XoChip);
+        assert_eq!(Machine::Chip48.default_quirks().machine, Machine::Chip48);
+    }
+
+    #[test]
+    fn quirk_flags_for_platforms() {
+        assert!(QuirkFlags::chip8().draw_wait);
+        assert_eq!(QuirkFlags::chip48().machine, Machine::Chip48);
+        assert!(QuirkFlags::chip48().vf_reset && !QuirkFlags::chip48().draw_wait);
+
+        let xo_chip = QuirkFlags::xo_chip();
+        assert_eq!(xo_chip.machine, Machine::XoChip);
+        assert!(xo_chip.load_store_quirk && xo_chip.wrap_sprites);
+        assert!(!xo_chip.vf_reset && !xo_chip.shift_quirk && !xo_chip.jump_quirk);
+        assert_eq!(QuirkPreset::XoChip.to_quirk_flags().machine, Machine::XoChip);
+    }
+
+    // Config::from_args with args, a global config file (given with --config) holding global,
+    // and a config file for the ROM holding rom, if there is one. name keeps the files apart from
+    // other tests'.
+    fn config_from_files(name: &str, global: &str, rom: Option<&str>, args: &[&str])
+        -> Result<Config, String>
+    {
+        let dir = std::env::temp_dir();
+        let global_path = dir.join(format!("chip8-{}-global.toml", name));
+        let rom_path = dir.join(format!("chip8-{}.ch8", name));
+        fs::write(&global_path, global).unwrap();
+        if let Some(text) = rom {
+            fs::write(rom_path.with_extension("toml"), text).unwrap();
+        }
+
+        let mut all_args = vec!["--config", global_path.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        all_args.push(rom_path.to_str().unwrap());
+        let config = Config::from_args(all_args.iter().map(|arg| arg.to_string()));
+
+        fs::remove_file(global_path).unwrap();
+        let _ = fs::remove_file(rom_path.with_extension("toml"));
+        config
+    }
+
+    #[test]
+    fn config_layers() {
+        let global = "speed = 500\nscale = 3\nfg_color = \"green\"\n";
+        let rom = "speed = 600\n";
+
+        let config = config_from_files("layers", global, None, &[]).unwrap();
+        assert_eq!((config.speed, config.scale), (500, 3));
+
+        // the ROM's config file beats the global one, and the command line beats them both
+        let config = config_from_files("layers", global, Some(rom), &[]).unwrap();
+        assert_eq!((config.speed, config.scale), (600, 3));
+        let config = config_from_files("layers", global, Some(rom), &["--speed", "700"]).unwrap();
+        assert_eq!((config.speed, config.scale), (700, 3));
+        assert_eq!(config.palette[1], frontend::Color::parse("green").unwrap());
+    }
+
+    #[test]
+    fn config_sections_merge() {
+        let global = "[quirks]\nmachine = \"chip48\"\nshift_quirk = true\n";
+        let rom = "[quirks]\nwrap_sprites = true\n";
+        let config = config_from_files("merge", global, Some(rom), &[]).unwrap();
+        assert_eq!(config.quirks.machine, Machine::Chip48);
+        assert!(config.quirks.shift_quirk && config.quirks.wrap_sprites);
+    }
+
+    #[test]
+    fn config_unknown_keys() {
+        let err = config_from_files("bad-global", "sped = 500\n", None, &[]).err().unwrap();
+        assert!(err.starts_with("Invalid config file "), "{}", err);
+        assert!(err.contains("chip8-bad-global-global.toml") && err.contains("sped"), "{}", err);
+
+        // and the mistake's reported as being in the ROM's file, not the global one
+        let rom = "[quirks]\nshift = true\n";
+        let err = config_from_files("bad-rom", "", Some(rom), &[]).err().unwrap();
+        assert!(err.contains("chip8-bad-rom.toml") && err.contains("shift"), "{}", err);
+    }
+
+    #[test]
+    fn quirk_preset_and_individual_quirks() {
+        // a preset comes first wherever it is on the command line, so it doesn't undo the others
+        let orders = [
+            ["--quirk", "chip8", "--quirk", "shift"],
+            ["--quirk", "shift", "--quirk", "chip8"],
+        ];
+        for args in orders {
+            let quirks = config_from_files("quirk-order", "", None, &args).unwrap().quirks;
+            assert_eq!(quirks.machine, Machine::Chip8);
+            assert!(quirks.shift_quirk && quirks.load_store_quirk && quirks.draw_wait);
+            assert!(!quirks.vf_reset && !quirks.jump_quirk);
+        }
+
+        // and --mode changes only the machine, not the preset's quirks
+        let args = ["--mode", "xochip", "--quirk", "chip48"];
+        let quirks = config_from_files("quirk-mode", "", None, &args).unwrap().quirks;
+        assert_eq!(quirks.machine, Machine::XoChip);
+        assert!(quirks.vf_reset && quirks.shift_quirk && quirks.jump_quirk);
+
+        let err = config_from_files("quirk-unknown", "", None, &["--quirk", "shif"]).err();
+        assert_eq!(err, Some("Unknown quirk shif".to_string()));
+    }
+
+    #[test]
+    fn draw_wait() {
+        // DRW V0, V0, 1; LD V1, 1
+        let rom = [0xd0, 0x01, 0x61, 0x01];
+        let mut chip8 = Chip8::new_for_testing(&rom);
+        chip8.quirks.draw_wait = true;
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        assert_eq!((chip8.pc, chip8.v[1]), (0x202, 0));
+
+        chip8.tick_timers(0);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.pc, 0x202);
+
+        chip8.tick_timers(1);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!((chip8.pc, chip8.v[1]), (0x204, 1));
+    }
+
+    #[test]
+    fn save_and_load_state() {
+        // LD V0, 5; CALL 0x300
+        let mut chip8 = Chip8::new_for_testing(&[0x60, 0x05, 0x23, 0x00]);
+        chip8.emulate_cycle().unwrap();
+        chip8.emulate_cycle().unwrap();
+        chip8.set_color(3, 4, 1);
+        chip8.ram[0x400] = 0x42;
+        chip8.delay_timer.start(30);
+        let state = chip8.save_state();
+
+        let mut loaded = Chip8::new_for_testing(&[]);
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.v[0], 5);
+        assert_eq!(loaded.pc, 0x300);
+        assert_eq!(loaded.stack(), [0x202]);
+        assert!(loaded.get_pixel(3, 4));
+        assert_eq!(loaded.ram[0x400], 0x42);
+        assert_eq!(loaded.delay_timer.get_value(), 30);
+        loaded.end_frame();
+        assert_eq!(loaded.dirty_rows(), u32::MAX as u64);
+        assert_eq!(loaded.save_state(), state);
+    }
+
+    #[test]
+    fn load_invalid_state() {
+        let mut chip8 = Chip8::new_for_testing(&[0x60, 0x05]);
+        let state = chip8.save_state();
+        chip8.emulate_cycle().unwrap();
+
+        assert_eq!(chip8.load_state(b"not a save state"), Err(Chip8Error::InvalidSaveState));
+        assert_eq!(chip8.load_state(&state[..state.len() - 1]), Err(Chip8Error::InvalidSaveState));
+        assert_eq!(xo_chip(&[]).load_state(&state), Err(Chip8Error::InvalidSaveState));
+        assert_eq!(chip8.v[0], 5);
+    }
+
+    #[test]
+    fn load_state_with_i_past_end_of_memory() {
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        chip8.i = RAM_SIZE as u16;
+        let state = chip8.save_state();
+        let mut loaded = Chip8::new_for_testing(&[]);
+        assert_eq!(loaded.load_state(&state), Err(Chip8Error::InvalidSaveState));
+        assert_eq!(loaded.i, 0);
+    }
+
+    #[test]
+    fn load_state_with_return_address_past_end_of_memory() {
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        chip8.stack[0] = RAM_SIZE as u16 - 1;
+        chip8.sp = 1;
+        let state = chip8.save_state();
+        let mut loaded = Chip8::new_for_testing(&[]);
+        assert_eq!(loaded.load_state(&state), Err(Chip8Error::InvalidSaveState));
+        assert_eq!(loaded.sp, 0);
+    }
+
+    #[test]
+    fn trace_limit() {
+        let path = std::env::temp_dir().join("chip8-trace-limit-test.txt");
+        let path = path.to_str().unwrap();
+        let mut tracer = Tracer::create(path, Some(2)).unwrap();
+
+        // LD V0, 5; LD V1, 6; LD V2, 7
+        let mut chip8 = Chip8::new_for_testing(&[0x60, 0x05, 0x61, 0x06, 0x62, 0x07]);
+        for _ in 0..3 {
+            tracer.record(&chip8).unwrap();
+            chip8.emulate_cycle().unwrap();
+        }
+
+        let trace = fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("0x0200  LD V0, 0x05       V0=00 V1=00"));
+        assert!(lines[1].starts_with("0x0202  LD V1, 0x06       V0=05 V1=00"));
+        assert!(lines[1].ends_with("VF=00  I=0000  DT=00"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn record_with_pc_past_end_of_memory() {
+        let path = std::env::temp_dir().join("chip8-trace-past-end-test.txt");
+        let path = path.to_str().unwrap();
+        let mut tracer = Tracer::create(path, None).unwrap();
+        let mut debugger = Debugger::new(true);
+        let mut call_graph = CallGraph::new();
+        let mut history = History::new();
+
+        // the last byte of memory is only half an instruction
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        chip8.pc = 0xfff;
+        tracer.record(&chip8).unwrap();
+        debugger.record(&chip8);
+        call_graph.record(&chip8);
+        history.record(&chip8);
+        assert_eq!(chip8.emulate_cycle(),
+                   Err(Chip8Error::MemoryOutOfBounds { addr: 0xfff, i: 0, pc: 0xfff }));
+
+        drop(tracer);
+        assert_eq!(fs::read_to_string(path).unwrap(), "");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn perf_counter() {
+        let mut perf = PerfCounter::new();
+        assert_eq!(perf.cycles_per_second(), 0.);
+
+        // 200 frames at 30 Hz, running 10 instructions each; only the last 100 count
+        for _ in 0..200 {
+            for _ in 0..10 {
+                perf.record_cycle();
+            }
+            perf.record_frame(time::Duration::from_secs(1) / 30);
+        }
+        assert!((perf.cycles_per_second() - 300.).abs() < 0.01);
+        assert!((perf.frame_rate() - 30.).abs() < 0.01);
+        assert!(perf.summary().contains("timers: 50.0% of 60 Hz"));
+    }
+
+    #[test]
+    fn call_graph() {
+        // 0x200: CALL 0x206; CALL 0x206; JP 0x204
+        // 0x206: CALL 0x20a; RET
+        // 0x20a: RET
+        let rom = [0x22, 0x06, 0x22, 0x06, 0x12, 0x04, 0x22, 0x0a, 0x00, 0xee, 0x00, 0xee];
+        let mut chip8 = Chip8::new_for_testing(&rom);
+        let mut call_graph = CallGraph::new();
+        for _ in 0..12 {
+            call_graph.record(&chip8);
+            chip8.emulate_cycle().unwrap();
+        }
+
+        let mut dot = Vec::new();
+        call_graph.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with("digraph calls {\n"));
+        assert!(dot.contains("\"0x0200\" [label=\"0x0200\\nentered 1 time\"];"));
+        assert!(dot.contains("\"0x0206\" [label=\"0x0206\\nentered 2 times\"];"));
+        assert!(dot.contains("\"0x0200\" -> \"0x0206\" [label=\"2\"];"));
+        assert!(dot.contains("\"0x0206\" -> \"0x020a\" [label=\"2\"];"));
+        assert!(!dot.contains("\"0x0200\" -> \"0x020a\""));
+    }
+
+    // Draws nothing, and never has any input
+    struct NullFrontend;
+
+    impl Frontend for NullFrontend {
+        fn render(&mut self, _display: &[u8], _dirty_rows: u64) {}
+        fn poll_events(&mut self, _keys: &mut [Key; 16]) -> Option<Command> {
+            None
+        }
+        fn clear(&mut self) {}
+        fn set_resolution(&mut self, _width: usize, _height: usize) {}
+    }
+
+    #[test]
+    fn call_graph_saved_when_program_fails() {
+        let path = std::env::temp_dir().join("chip8-call-graph-failure-test.dot");
+        let _ = fs::remove_file(&path);
+        let args = ["--callgraph", path.to_str().unwrap()];
+        let config = config_from_files("call-graph-failure", "", None, &args).unwrap();
+
+        // CALL 0x204; RET, which returns once too often; RET
+        let rom = vec![0x22, 0x04, 0x00, 0xee, 0x00, 0xee];
+        assert!(matches!(run(rom, Box::new(NullFrontend), &config),
+                         Err(Chip8Error::StackUnderflow { pc: 0x202 })));
+        let dot = fs::read_to_string(&path).unwrap();
+        assert!(dot.contains("\"0x0200\" -> \"0x0204\" [label=\"1\"];"), "{}", dot);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn call_graph_with_draw_wait() {
+        // 0x200: DRW V0, V0, 1; CALL 0x206; JP 0x204
+        // 0x206: RET
+        let rom = [0xd0, 0x01, 0x22, 0x06, 0x12, 0x04, 0x00, 0xee];
+        let mut chip8 = Chip8::new_for_testing(&rom);
+        chip8.quirks.draw_wait = true;
+        let mut call_graph = CallGraph::new();
+        for cycle in 0..6 {
+            // the CALL waits for the frame to end for two cycles, but is only made once
+            assert_eq!(chip8.is_waiting_for_frame(), cycle == 1 || cycle == 2);
+            call_graph.record(&chip8);
+            chip8.emulate_cycle().unwrap();
+            if cycle == 2 {
+                chip8.tick_timers(1);
+            }
+        }
+
+        let mut dot = Vec::new();
+        call_graph.write_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.contains("\"0x0206\" [label=\"0x0206\\nentered 1 time\"];"), "{}", dot);
+        assert!(dot.contains("\"0x0200\" -> \"0x0206\" [label=\"1\"];"), "{}", dot);
+    }
+
+    #[test]
+    fn screenshot() {
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        chip8.set_color(1, 0, 1);
+        chip8.end_frame();
+        let image = screenshot::render(&chip8, &frontend::DEFAULT_PALETTE, 2);
+        assert_eq!(image.dimensions(), (SCREEN_WIDTH as u32 * 2, SCREEN_HEIGHT as u32 * 2));
+        assert_eq!(image.get_pixel(1, 1).0, [0, 0, 0]);
+        assert_eq!(image.get_pixel(2, 1).0, [0xff, 0xff, 0xff]);
+        assert_eq!(image.get_pixel(3, 1).0, [0xff, 0xff, 0xff]);
+        assert_eq!(image.get_pixel(4, 1).0, [0, 0, 0]);
+    }
+
+    #[test]
+    fn record_gif() {
+        let path = std::env::temp_dir().join("chip8-record-test.gif");
+        let path = path.to_str().unwrap();
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        let mut recorder = GifRecorder::create(path, &frontend::DEFAULT_PALETTE).unwrap();
+
+        // low resolution pixels are doubled
+        chip8.set_color(1, 0, 1);
+        chip8.end_frame();
+        let picture = recording::picture(&chip8);
+        assert_eq!(picture.len(), HI_RES_PIXELS);
+        assert_eq!(&picture[0..5], &[0, 0, 1, 1, 0]);
+        assert_eq!(&picture[HI_RES_WIDTH..HI_RES_WIDTH + 5], &[0, 0, 1, 1, 0]);
+
+        // a second's worth of the same picture, then a different one
+        for _ in 0..FRAME_RATE {
+            recorder.record_frame(&chip8).unwrap();
+        }
+        chip8.set_color(1, 0, 0);
+        chip8.end_frame();
+        for _ in 0..3 {
+            recorder.record_frame(&chip8).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let file = fs::File::open(path).unwrap();
+        let mut decoder = gif::DecodeOptions::new().read_info(file).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (128, 64));
+        assert_eq!(decoder.read_next_frame().unwrap().unwrap().delay, 100);
+        assert_eq!(decoder.read_next_frame().unwrap().unwrap().delay, 5);
+        assert!(decoder.read_next_frame().unwrap().is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn record_and_replay_input() {
+        let path = std::env::temp_dir().join("chip8-input-test.bin");
+        let path = path.to_str().unwrap();
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        let mut recorder = InputRecorder::create(path).unwrap();
+
+        let old = chip8.keys;
+        chip8.cycles = 10;
+        chip8.set_key(5, true).unwrap();
+        recorder.record(&old, &chip8).unwrap();
+        let old = chip8.keys;
+        chip8.cycles = 20;
+        chip8.set_key(5, false).unwrap();
+        chip8.set_key(0xa, true).unwrap();
+        recorder.record(&old, &chip8).unwrap();
+        recorder.finish().unwrap();
+        assert_eq!(fs::metadata(path).unwrap().len(), 30);
+
+        let mut chip8 = Chip8::new_for_testing(&[]);
+        let mut replay = InputReplay::load(path).unwrap();
+        chip8.cycles = 9;
+        replay.apply(&mut chip8);
+        assert!(chip8.keys[5] == Key::Up);
+        chip8.cycles = 10;
+        replay.apply(&mut chip8);
+        assert!(chip8.keys[5] == Key::Down);
+        chip8.cycles = 25;
+        replay.apply(&mut chip8);
+        assert!(chip8.keys[5] == Key::Up && chip8.keys[0xa] == Key::Down);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn assemble_with_labels() {
+        let source = "
+            ; count V0 up to 10
+                    LD V0, 0
+            loop:   ADD V0, 0b1
+                    SNE V0, 10
+                    JP done
+                    JP loop
+            done:   LD I, sprite
+                    JP done
+            sprite: DB 0xf0, 0x90
+                    DW loop
+        ";
+        let rom = assembler::assemble(source).unwrap();
+        assert_eq!(rom, vec![0x60, 0x00, 0x70, 0x01, 0x40, 0x0a, 0x12, 0x0a, 0x12, 0x02,
+                             0xa2, 0x0e, 0x12, 0x0a, 0xf0, 0x90, 0x02, 0x02]);
+    }
+
+    #[test]
+    fn assemble_disassembly() {
+        // one of everything the disassembler knows, plus some data
+        let instrs: [u16; 56] = [
+            0x00c3, 0x00e0, 0x00ee, 0x00fb, 0x00fc, 0x00fd, 0x00fe, 0x00ff, 0x0123, 0x1234,
+            0x2345, 0x3456, 0x4567, 0x5670, 0x6789, 0x789a, 0x89a0, 0x89a1, 0x89a2, 0x89a3,
+            0x89a4, 0x89a5, 0x89a6, 0x89a7, 0x89ae, 0x9ab0, 0xabcd, 0xbcde, 0xcdef, 0xdef1,
+            0xe19e, 0xe2a1, 0xf000, 0xf201, 0xf002, 0xf307, 0xf40a, 0xf515, 0xf618, 0xf71e,
+            0xf829, 0xf930, 0xfa33, 0xfb3a, 0xfc55, 0xfd65, 0xf775, 0xf785, 0x5121, 0xffff,
+            0x8008, 0xe000, 0xf0ff, 0x9001, 0x0000, 0x0200,
+        ];
+        let rom: Vec<u8> = instrs.iter().flat_map(|instr| instr.to_be_bytes()).collect();
+        let listing = disasm::listing(&rom, INSTRUCTIONS_START, &disasm::Symbols::new());
+        assert_eq!(assembler::assemble(&listing).unwrap(), rom);
+
+        // a byte left over at the end
+        assert_eq!(assembler::assemble("DB 0x42").unwrap(), vec![0x42]);
+    }
+
+    #[test]
+    fn assemble_errors() {
+        let error = |source| assembler::assemble(source).unwrap_err();
+        let at = |line, columns, message: &str| assembler::AssembleError {
+            line,
+            columns,
+            message: message.to_string(),
+        };
+        assert_eq!(error("CLS\nFOO V1"), at(2, 0..3, "unknown instruction FOO"));
+        assert_eq!(error("LD V1, DT, 5"), at(1, 3..12, "invalid operands for LD: V1, DT, 5"));
+        assert_eq!(error("JP nowhere"), at(1, 3..10, "undefined label nowhere"));
+        assert_eq!(error("ld v0, 256"), at(1, 7..10, "256 is too large (at most 0xff)"));
+        assert_eq!(error("LD V0, 12ab"), at(1, 7..11, "invalid number 12ab"));
+        assert_eq!(error("a: CLS\n  a: RET"), at(2, 2..3, "label a is defined twice"));
+        assert_eq!(error("DT: CLS"), at(1, 0..2, "invalid label DT"));
+    }
+
+    #[test]
+    fn check_assembly() {
+        let (labels, errors) = assembler::check("FOO\nstart: JP there\n  LD V0, 300");
+        assert_eq!(labels, vec![assembler::Label {
+            name: "start".to_string(),
+            addr: 0x202,
+            line: 2,
+            columns: 0..5,
+        }]);
+        let lines: Vec<usize> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn disassembly_labels() {
+        // 0x200: CALL 0x208; JP 0x20a; (data)
+        // 0x206: JP 0x206; RET
+        // 0x20a: JP 0x200; LD I, 0x208
+        let rom = [0x22, 0x08, 0x12, 0x0a, 0x12, 0x34, 0x12, 0x06, 0x00, 0xee, 0x12, 0x00,
+                   0xa2, 0x08];
+        let mut symbols = disasm::Symbols::new();
+        symbols.insert(0x200, "start".to_string());
+        let lines: Vec<(Option<String>, String)> =
+            disasm::disassemble(&rom, INSTRUCTIONS_START, &symbols)
+                .into_iter()
+                .map(|(_, label, mnemonic)| (label, mnemonic))
+                .collect();
+        let label = |name: &str| Some(name.to_string());
+        assert_eq!(lines, vec![
+            (label("start"), "CALL SUB_0x208".to_string()),
+            (None, "JP LABEL_0x20a".to_string()),
+            (None, "JP 0x234".to_string()),
+            (label("LOOP_1"), "JP LOOP_1".to_string()),
+            (label("SUB_0x208"), "RET".to_string()),
+            (label("LABEL_0x20a"), "JP start".to_string()),
+            (None, "LD I, SUB_0x208".to_string()),
+        ]);
+
+        let listing = disasm::listing(&rom, INSTRUCTIONS_START, &symbols);
+        assert_eq!(assembler::assemble(&listing).unwrap(), rom);
+    }
+
+    #[test]
+    fn parse_symbols() {
+        let symbols = disasm::parse_symbols("0x220 draw ; the paddle\n\n2a0 score").unwrap();
+        assert_eq!(symbols.get(&0x220).map(String::as_str), Some("draw"));
+        assert_eq!(symbols.get(&0x2a0).map(String::as_str), Some("score"));
+        assert!(disasm::parse_symbols("0x220").is_err());
+        assert!(disasm::parse_symbols("0x220 two words").is_err());
+        assert!(disasm::parse_symbols("0x22g draw").is_err());
+        assert!(disasm::parse_symbols("0x220 DT").is_err());
+    }
 }
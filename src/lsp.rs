@@ -0,0 +1,265 @@
+// A language server for CHIP-8 assembly (the chip8-lsp binary, with the "lsp" feature), so that
+// editors can show the assembler's errors as you type, describe instructions on hover, jump to
+// where labels are defined and list them. It speaks the Language Server Protocol over stdin and
+// stdout. Positions count UTF-16 code units along the line, as LSP's do by default, so they're
+// converted to and from the byte offsets the assembler uses.
+
+use crate::assembler;
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::sync::Mutex;
+use tower_lsp::jsonrpc::Result;
+use tower_lsp::lsp_types::*;
+use tower_lsp::{Client, LanguageServer, LspService, Server};
+
+// what hovering over each mnemonic shows
+const DOCS: [(&str, &str); 29] = [
+    ("ADD", "`7xkk` ADD Vx, byte, `8xy4` ADD Vx, Vy (setting VF to the carry) and `Fx1E` \
+             ADD I, Vx add to a register."),
+    ("AND", "`8xy2` AND Vx, Vy sets Vx to Vx AND Vy."),
+    ("CALL", "`2nnn` CALL addr calls the subroutine at addr."),
+    ("CLS", "`00E0` CLS clears the display."),
+    ("DB", "DB puts bytes straight into the program."),
+    ("DRW", "`Dxyn` DRW Vx, Vy, n draws the n-byte sprite at I at (Vx, Vy), setting VF if any \
+             pixel was turned off."),
+    ("DW", "DW puts two-byte words straight into the program."),
+    ("EXIT", "`00FD` EXIT (SUPER-CHIP) stops the program."),
+    ("HIGH", "`00FF` HIGH (SUPER-CHIP) switches to the 128x64 display."),
+    ("JP", "`1nnn` JP addr jumps to addr, and `Bnnn` JP V0, addr to addr + V0."),
+    ("LD", "LD copies a value: `6xkk` LD Vx, byte, `8xy0` LD Vx, Vy, `Annn` LD I, addr, \
+            `Fx07` LD Vx, DT, `Fx0A` LD Vx, K (waiting for a key), `Fx15` LD DT, Vx, \
+            `Fx18` LD ST, Vx, `Fx29` LD F, Vx, `Fx30` LD HF, Vx, `Fx33` LD B, Vx, \
+            `Fx55` LD [I], Vx, `Fx65` LD Vx, [I], `Fx75` LD R, Vx, `Fx85` LD Vx, R, \
+            `F000` LD I, LONG, `F002` LD AUDIO, [I] and `Fx3A` LD PITCH, Vx."),
+    ("LOW", "`00FE` LOW (SUPER-CHIP) switches to the 64x32 display."),
+    ("OR", "`8xy1` OR Vx, Vy sets Vx to Vx OR Vy."),
+    ("PLANE", "`Fn01` PLANE n (XO-CHIP) selects the planes to draw on."),
+    ("RET", "`00EE` RET returns from a subroutine."),
+    ("RND", "`Cxkk` RND Vx, byte sets Vx to a random number AND byte."),
+    ("SCD", "`00Cn` SCD n (SUPER-CHIP) scrolls the display down n pixels."),
+    ("SCL", "`00FC` SCL (SUPER-CHIP) scrolls the display left 4 pixels."),
+    ("SCR", "`00FB` SCR (SUPER-CHIP) scrolls the display right 4 pixels."),
+    ("SE", "`3xkk` SE Vx, byte and `5xy0` SE Vx, Vy skip the next instruction if the two are \
+            equal."),
+    ("SHL", "`8xyE` SHL Vx, Vy shifts Vy (or Vx, with the shift quirk) left into Vx, setting \
+             VF to the bit shifted out."),
+    ("SHR", "`8xy6` SHR Vx, Vy shifts Vy (or Vx, with the shift quirk) right into Vx, setting \
+             VF to the bit shifted out."),
+    ("SKNP", "`ExA1` SKNP Vx skips the next instruction unless key Vx is down."),
+    ("SKP", "`Ex9E` SKP Vx skips the next instruction if key Vx is down."),
+    ("SNE", "`4xkk` SNE Vx, byte and `9xy0` SNE Vx, Vy skip the next instruction if the two \
+             are different."),
+    ("SUB", "`8xy5` SUB Vx, Vy sets Vx to Vx - Vy, and VF to 1 unless it borrowed."),
+    ("SUBN", "`8xy7` SUBN Vx, Vy sets Vx to Vy - Vx, and VF to 1 unless it borrowed."),
+    ("SYS", "`0nnn` SYS addr called machine code on the original interpreter, and is ignored."),
+    ("XOR", "`8xy3` XOR Vx, Vy sets Vx to Vx XOR Vy."),
+];
+
+pub(crate) struct Backend {
+    client: Client,
+
+    // the text of each open document
+    documents: Mutex<HashMap<Url, String>>,
+}
+
+impl Backend {
+    pub(crate) fn new(client: Client) -> Backend {
+        Backend {
+            client,
+            documents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn update(&self, uri: Url, text: String) {
+        let (_, errors) = assembler::check(&text);
+        let diagnostics = errors.into_iter()
+            .map(|error| Diagnostic {
+                range: range(&text, error.line, &error.columns),
+                severity: Some(DiagnosticSeverity::ERROR),
+                source: Some("chip8-asm".to_string()),
+                message: error.message,
+                ..Diagnostic::default()
+            })
+            .collect();
+        self.documents.lock().unwrap().insert(uri.clone(), text);
+        self.client.publish_diagnostics(uri, diagnostics, None).await;
+    }
+
+    // The word at position in the document, and the labels the document defines
+    fn word_at(&self, uri: &Url, position: Position) -> Option<(String, Vec<assembler::Label>)> {
+        let documents = self.documents.lock().unwrap();
+        let text = documents.get(uri)?;
+        let line = text.lines().nth(position.line as usize)?;
+        let word = word_at(line, byte_column(line, position.character))?;
+        Some((word.to_string(), assembler::check(text).0))
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for Backend {
+    async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
+        Ok(InitializeResult {
+            capabilities: ServerCapabilities {
+                text_document_sync: Some(TextDocumentSyncCapability::Kind(
+                    TextDocumentSyncKind::FULL,
+                )),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                definition_provider: Some(OneOf::Left(true)),
+                document_symbol_provider: Some(OneOf::Left(true)),
+                ..ServerCapabilities::default()
+            },
+            server_info: Some(ServerInfo { name: "chip8-lsp".to_string(), version: None }),
+        })
+    }
+
+    async fn shutdown(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        self.update(params.text_document.uri, params.text_document.text).await;
+    }
+
+    async fn did_change(&self, mut params: DidChangeTextDocumentParams) {
+        // we ask for the whole document each time, so the last change has all of it
+        if let Some(change) = params.content_changes.pop() {
+            self.update(params.text_document.uri, change.text).await;
+        }
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri;
+        self.documents.lock().unwrap().remove(&uri);
+        self.client.publish_diagnostics(uri, Vec::new(), None).await;
+    }
+
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let at = params.text_document_position_params;
+        let (word, labels) = match self.word_at(&at.text_document.uri, at.position) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let doc = DOCS.iter().find(|(name, _)| name.eq_ignore_ascii_case(&word));
+        let text = if let Some(label) = labels.iter().find(|label| label.name == word) {
+            format!("{} is at {:#06x}", label.name, label.addr)
+        } else if let Some((_, doc)) = doc {
+            doc.to_string()
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: text,
+            }),
+            range: None,
+        }))
+    }
+
+    async fn goto_definition(&self, params: GotoDefinitionParams)
+        -> Result<Option<GotoDefinitionResponse>>
+    {
+        let at = params.text_document_position_params;
+        let uri = at.text_document.uri;
+        let label = match self.word_at(&uri, at.position)
+            .and_then(|(word, labels)| labels.into_iter().find(|label| label.name == word))
+        {
+            Some(label) => label,
+            None => return Ok(None),
+        };
+        let range = match self.documents.lock().unwrap().get(&uri) {
+            Some(text) => range(text, label.line, &label.columns),
+            None => return Ok(None),
+        };
+        Ok(Some(GotoDefinitionResponse::Scalar(Location::new(uri, range))))
+    }
+
+    async fn document_symbol(&self, params: DocumentSymbolParams)
+        -> Result<Option<DocumentSymbolResponse>>
+    {
+        let documents = self.documents.lock().unwrap();
+        let text = match documents.get(&params.text_document.uri) {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+
+        let symbols = assembler::check(text).0.into_iter()
+            .map(|label| {
+                let range = range(text, label.line, &label.columns);
+                // DocumentSymbol has a deprecated field that we still have to fill in
+                #[allow(deprecated)]
+                DocumentSymbol {
+                    name: label.name,
+                    detail: Some(format!("{:#06x}", label.addr)),
+                    kind: SymbolKind::CONSTANT,
+                    tags: None,
+                    deprecated: None,
+                    range,
+                    selection_range: range,
+                    children: None,
+                }
+            })
+            .collect();
+        Ok(Some(DocumentSymbolResponse::Nested(symbols)))
+    }
+}
+
+// Serve requests on stdin and stdout until the editor closes the connection
+pub fn serve() -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(async {
+        let (service, socket) = LspService::new(Backend::new);
+        Server::new(tokio::io::stdin(), tokio::io::stdout(), socket).serve(service).await;
+    });
+    Ok(())
+}
+
+// An LSP range for the (byte) columns of a line of text, which counts from 1
+pub(crate) fn range(text: &str, line: usize, columns: &Range<usize>)
+    -> tower_lsp::lsp_types::Range
+{
+    let line_text = text.lines().nth(line - 1).unwrap_or("");
+    let line = line as u32 - 1;
+    tower_lsp::lsp_types::Range::new(
+        Position::new(line, utf16_column(line_text, columns.start)),
+        Position::new(line, utf16_column(line_text, columns.end)),
+    )
+}
+
+// The byte offset in line of an LSP position's character, which counts UTF-16 code units. One in
+// the middle of a character (or past the end of the line) gets the end of it.
+fn byte_column(line: &str, character: u32) -> usize {
+    let mut units = 0;
+    for (n, ch) in line.char_indices() {
+        if units >= character as usize {
+            return n;
+        }
+        units += ch.len_utf16();
+    }
+    line.len()
+}
+
+// The other way round: the UTF-16 code units in line before byte column
+fn utf16_column(line: &str, column: usize) -> u32 {
+    line.char_indices()
+        .take_while(|(n, _)| *n < column)
+        .map(|(_, ch)| ch.len_utf16() as u32)
+        .sum()
+}
+
+// The label, mnemonic or operand that (byte) column is in (or just after) in line
+fn word_at(line: &str, column: usize) -> Option<&str> {
+    let is_word = |ch: char| ch.is_ascii_alphanumeric() || "_[]".contains(ch);
+    let column = column.min(line.len());
+    let start = line[..column].char_indices()
+        .rfind(|(_, ch)| !is_word(*ch))
+        .map_or(0, |(n, ch)| n + ch.len_utf8());
+    let end = line[column..].find(|ch| !is_word(ch)).map_or(line.len(), |n| column + n);
+    if start < end {
+        Some(&line[start..end])
+    } else {
+        None
+    }
+}
@@ -1,20 +1,29 @@
+use chip8::config::{Config, USAGE};
+use chip8::{disasm, frontend};
 use std::env;
+use std::error::Error;
 use std::fs;
-use std::process;
+use std::io;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+// Errors come back from main, so they're printed (after "Error: ") and the exit code is 1
+fn main() -> Result<(), Box<dyn Error>> {
+    let config = Config::from_args(env::args().skip(1)).inspect_err(|_| println!("{}", USAGE))?;
 
-    if args.len() < 2 {
-        println!("Please provide a filename");
-        process::exit(1);
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level)
+        .with_writer(io::stderr)
+        .init();
+
+    let rom = fs::read(&config.rom_path).map_err(|err| format!("Couldn't open file: {}", err))?;
+
+    if config.disasm {
+        disasm::print_listing(&rom, &disasm::Symbols::new());
+        return Ok(());
     }
 
-    let rom = fs::read(&args[1]).unwrap_or_else(|err| {
-        println!("Couldn't open file: {}", err);
-        process::exit(1);
-    });
+    let frontend = frontend::create(&config)
+        .map_err(|err| format!("Couldn't start {:?} frontend: {}", config.frontend, err))?;
 
-    chip8::run(rom);
+    chip8::run(rom, frontend, &config).map_err(|err| format!("Emulation stopped: {}", err))?;
+    Ok(())
 }
-
@@ -0,0 +1,72 @@
+// Running performance figures for --perf: how many instructions we're really managing per
+// second, and how close to 60 Hz the frames (and so the timers, which count down once a frame)
+// are coming.
+
+use crate::FRAME_RATE;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// how many of the most recent frames the figures are averaged over
+const WINDOW: usize = 100;
+
+#[derive(Default)]
+pub struct PerfCounter {
+    // (how long it took, instructions executed) for each recent frame, oldest first
+    frames: VecDeque<(Duration, u32)>,
+
+    // instructions executed so far in the current frame
+    cycles: u32,
+
+    // frames recorded altogether
+    frame_count: u64,
+}
+
+impl PerfCounter {
+    pub fn new() -> PerfCounter {
+        PerfCounter::default()
+    }
+
+    pub fn record_cycle(&mut self) {
+        self.cycles += 1;
+    }
+
+    // Called at the end of each frame, with the time since the end of the last one
+    pub fn record_frame(&mut self, elapsed: Duration) {
+        if self.frames.len() == WINDOW {
+            self.frames.pop_front();
+        }
+        self.frames.push_back((elapsed, self.cycles));
+        self.cycles = 0;
+        self.frame_count += 1;
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn cycles_per_second(&self) -> f64 {
+        let cycles: u32 = self.frames.iter().map(|(_, cycles)| cycles).sum();
+        self.per_second(cycles as f64)
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        self.per_second(self.frames.len() as f64)
+    }
+
+    // A status line like "cycles/s: 500  fps: 60.0  timers: 100.0% of 60 Hz"
+    pub fn summary(&self) -> String {
+        let frame_rate = self.frame_rate();
+        format!("cycles/s: {:.0}  fps: {:.1}  timers: {:.1}% of {} Hz",
+                self.cycles_per_second(), frame_rate,
+                100. * frame_rate / FRAME_RATE as f64, FRAME_RATE)
+    }
+
+    fn per_second(&self, count: f64) -> f64 {
+        let total: Duration = self.frames.iter().map(|(elapsed, _)| *elapsed).sum();
+        if total.is_zero() {
+            0.
+        } else {
+            count / total.as_secs_f64()
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Records the display as an animated GIF (with --record), using the palette's four colors.
+//
+// GIF frame delays are in hundredths of a second, and most viewers won't show a frame for less
+// than two of them, so rather than every 60 Hz frame we keep every third: 20 a second, each
+// shown for 5/100ths. A picture that doesn't change is written once, with a longer delay. The
+// GIF is always the size of the high resolution display, with low resolution pixels doubled, so
+// that a program switching between the two doesn't change its size.
+
+use crate::frontend::Palette;
+use crate::{Chip8, Chip8Error, HI_RES_HEIGHT, HI_RES_PIXELS, HI_RES_WIDTH};
+use gif::{Encoder, EncodingError, Frame, Repeat};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const FRAMES_PER_PICTURE: u32 = 3;
+const PICTURE_DELAY: u16 = 5;
+
+pub struct GifRecorder {
+    path: String,
+    encoder: Encoder<BufWriter<File>>,
+
+    // 60 Hz frames since recording started
+    frames: u32,
+
+    // the latest picture, as palette indexes, and how long it has been showing; it's held back
+    // until we know how long that will be
+    pending: Option<(Vec<u8>, u16)>,
+}
+
+impl GifRecorder {
+    pub fn create(path: &str, palette: &Palette) -> Result<GifRecorder, Chip8Error> {
+        let file = File::create(path).map_err(|err| recording_failed(path, err.into()))?;
+        let colors: Vec<u8> = palette.iter()
+            .flat_map(|color| [color.r, color.g, color.b])
+            .collect();
+        let mut encoder = Encoder::new(BufWriter::new(file), HI_RES_WIDTH as u16,
+                                       HI_RES_HEIGHT as u16, &colors)
+            .map_err(|err| recording_failed(path, err))?;
+        encoder.set_repeat(Repeat::Infinite).map_err(|err| recording_failed(path, err))?;
+
+        Ok(GifRecorder {
+            path: path.to_string(),
+            encoder,
+            frames: 0,
+            pending: None,
+        })
+    }
+
+    // Called once per frame, after the display has been drawn
+    pub fn record_frame(&mut self, chip8: &Chip8) -> Result<(), Chip8Error> {
+        self.frames += 1;
+        if !self.frames.is_multiple_of(FRAMES_PER_PICTURE) {
+            return Ok(());
+        }
+
+        let picture = picture(chip8);
+        if let Some((pending, delay)) = &mut self.pending {
+            if *pending == picture && *delay <= u16::MAX - PICTURE_DELAY {
+                *delay += PICTURE_DELAY;
+                return Ok(());
+            }
+        }
+        self.write_pending()?;
+        self.pending = Some((picture, PICTURE_DELAY));
+        Ok(())
+    }
+
+    // Write out the last picture and the end of the GIF
+    pub fn finish(mut self) -> Result<(), Chip8Error> {
+        self.write_pending()?;
+        let path = self.path;
+        self.encoder.into_inner()
+            .and_then(|mut out| out.flush())
+            .map_err(|err| recording_failed(&path, err.into()))
+    }
+
+    fn write_pending(&mut self) -> Result<(), Chip8Error> {
+        if let Some((picture, delay)) = self.pending.take() {
+            let mut frame = Frame::from_indexed_pixels(HI_RES_WIDTH as u16, HI_RES_HEIGHT as u16,
+                                                       picture, None);
+            frame.delay = delay;
+            self.encoder.write_frame(&frame).map_err(|err| recording_failed(&self.path, err))?;
+        }
+        Ok(())
+    }
+}
+
+// The display at high resolution, as palette indexes, row by row
+pub fn picture(chip8: &Chip8) -> Vec<u8> {
+    let (width, _) = chip8.display_size();
+    let pixels = chip8.display_buffer();
+    let scale = HI_RES_WIDTH / width;
+    let mut picture = Vec::with_capacity(HI_RES_PIXELS);
+    for y in 0..HI_RES_HEIGHT {
+        for x in 0..HI_RES_WIDTH {
+            picture.push(pixels[y / scale * width + x / scale]);
+        }
+    }
+    picture
+}
+
+fn recording_failed(path: &str, err: EncodingError) -> Chip8Error {
+    Chip8Error::RecordingFailed { path: path.to_string(), reason: err.to_string() }
+}
@@ -0,0 +1,43 @@
+// The last few seconds of play, for --rewind-buffer: a snapshot of the Chip8 at the end of each
+// frame, so Backspace can step back through them a frame at a time.
+
+use crate::Chip8;
+use std::collections::VecDeque;
+
+// how many characters wide the fill level is in the status line
+const BAR_WIDTH: usize = 20;
+
+pub struct RewindBuffer {
+    // oldest first
+    snapshots: VecDeque<Chip8>,
+    capacity: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    // Called at the end of each frame; once the buffer is full, the oldest snapshot makes way
+    pub fn record(&mut self, chip8: &Chip8) {
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(chip8.clone());
+    }
+
+    // The most recent snapshot, if there's any left, taking it out so the next one is older
+    pub fn rewind(&mut self) -> Option<Chip8> {
+        self.snapshots.pop_back()
+    }
+
+    // How full the buffer is, like "rewind [##########----------]". It only changes every
+    // capacity / BAR_WIDTH frames, so windowed frontends don't fill stderr with it.
+    pub fn summary(&self) -> String {
+        let filled = BAR_WIDTH * self.snapshots.len() / self.capacity;
+        format!("rewind [{}{}]", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled))
+    }
+}
@@ -0,0 +1,33 @@
+// SUPER-CHIP games use the RPL flags (FX75/FX85) for things like high scores, so we keep them in
+// a file between runs: $XDG_DATA_HOME/chip8/rpl.bin, or ~/.local/share/chip8/rpl.bin.
+
+use crate::RPL_FLAG_COUNT;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+fn path() -> Option<PathBuf> {
+    let data_dir = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".local").join("share"),
+    };
+    Some(data_dir.join("chip8").join("rpl.bin"))
+}
+
+// The flags saved last time, or None if there aren't any (or they can't be read)
+pub fn load() -> Option<[u8; RPL_FLAG_COUNT]> {
+    let bytes = fs::read(path()?).ok()?;
+    let mut flags = [0; RPL_FLAG_COUNT];
+    let len = bytes.len().min(RPL_FLAG_COUNT);
+    flags[..len].copy_from_slice(&bytes[..len]);
+    Some(flags)
+}
+
+pub fn save(flags: &[u8; RPL_FLAG_COUNT]) -> io::Result<()> {
+    let path = path().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory"))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, flags)
+}
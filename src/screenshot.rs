@@ -0,0 +1,27 @@
+// Saves what's on the display as a PNG (F12 while running), in the colors of the palette, with
+// each CHIP-8 pixel drawn as a square scale pixels across.
+
+use crate::frontend::Palette;
+use crate::Chip8;
+use image::{ImageResult, Rgb, RgbImage};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Something like screenshot_1760438400.png, in the current directory
+pub fn default_path() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+    format!("screenshot_{}.png", seconds)
+}
+
+pub fn save(chip8: &Chip8, palette: &Palette, scale: u32, path: &str) -> ImageResult<()> {
+    render(chip8, palette, scale).save(path)
+}
+
+pub fn render(chip8: &Chip8, palette: &Palette, scale: u32) -> RgbImage {
+    let (width, height) = chip8.display_size();
+    let pixels = chip8.display_buffer();
+    RgbImage::from_fn(width as u32 * scale, height as u32 * scale, |x, y| {
+        let (x, y) = ((x / scale) as usize, (y / scale) as usize);
+        let color = palette[pixels[y * width + x] as usize];
+        Rgb([color.r, color.g, color.b])
+    })
+}
@@ -0,0 +1,156 @@
+// Save states: a snapshot of everything a program can change, which can be loaded back later to
+// carry on from the same point. The format is our own: a header, then each part of the state in
+// turn, with multi-byte numbers big-endian.
+
+use crate::audio::AUDIO_PATTERN_SIZE;
+use crate::{Chip8, Chip8Error, ALL_PLANES, HI_RES_PIXELS, STACK_SIZE};
+use alloc::vec::Vec;
+
+const MAGIC: &[u8] = b"CHIP8SAV";
+const VERSION: u8 = 1;
+
+impl Chip8 {
+    // RAM is saved along with the registers and the display, since programs keep their variables
+    // there (and can even rewrite themselves). Breakpoints, watchpoints, the quirks and the RPL
+    // flags aren't part of the program's state, so they're left out.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+
+        data.extend_from_slice(&(self.ram.len() as u32).to_be_bytes());
+        data.extend_from_slice(&self.ram);
+        data.extend_from_slice(&self.back_pixels);
+        data.push(self.hi_res as u8);
+        data.push(self.active_planes);
+        data.push(self.halted as u8);
+
+        data.extend_from_slice(&self.v);
+        data.extend_from_slice(&self.i.to_be_bytes());
+        data.extend_from_slice(&self.pc.to_be_bytes());
+        data.push(self.sp);
+        for addr in self.stack().iter() {
+            data.extend_from_slice(&addr.to_be_bytes());
+        }
+
+        data.push(self.delay_timer.get_value());
+        data.push(self.sound_timer.get_value());
+        data.push(self.pitch);
+        match self.audio_pattern {
+            Some(pattern) => {
+                data.push(1);
+                data.extend_from_slice(&pattern);
+            },
+            None => data.push(0),
+        }
+
+        data
+    }
+
+    // Go back to a state from save_state. Nothing changes unless the whole state is valid.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Chip8Error> {
+        let mut reader = Reader { data };
+        if reader.bytes(MAGIC.len())? != MAGIC || reader.byte()? != VERSION {
+            return Err(Chip8Error::InvalidSaveState);
+        }
+
+        // a save from a machine with a different amount of memory won't work here
+        let ram_size = u32::from_be_bytes(reader.array()?) as usize;
+        if ram_size != self.ram.len() {
+            return Err(Chip8Error::InvalidSaveState);
+        }
+        let ram = reader.bytes(ram_size)?;
+        let pixels = reader.array::<HI_RES_PIXELS>()?;
+        let hi_res = reader.flag()?;
+        let active_planes = reader.byte()?;
+        let halted = reader.flag()?;
+        if pixels.iter().chain([active_planes].iter()).any(|planes| planes & !ALL_PLANES != 0) {
+            return Err(Chip8Error::InvalidSaveState);
+        }
+
+        let v = reader.array()?;
+        let i = u16::from_be_bytes(reader.array()?);
+        let pc = u16::from_be_bytes(reader.array()?);
+        // I and the return addresses (below) have to be in memory, as check_invariants wants
+        if i as usize >= ram_size || pc as usize + 1 >= ram_size {
+            return Err(Chip8Error::InvalidSaveState);
+        }
+        let stack_depth = reader.byte()? as usize;
+        if stack_depth > STACK_SIZE {
+            return Err(Chip8Error::InvalidSaveState);
+        }
+        let mut stack = [0; STACK_SIZE];
+        for addr in stack[..stack_depth].iter_mut() {
+            *addr = u16::from_be_bytes(reader.array()?);
+            if *addr as usize + 2 > ram_size {
+                return Err(Chip8Error::InvalidSaveState);
+            }
+        }
+
+        let delay = reader.byte()?;
+        let sound = reader.byte()?;
+        let pitch = reader.byte()?;
+        let audio_pattern = if reader.flag()? {
+            Some(reader.array::<AUDIO_PATTERN_SIZE>()?)
+        } else {
+            None
+        };
+
+        if !reader.data.is_empty() {
+            return Err(Chip8Error::InvalidSaveState);
+        }
+
+        self.ram.copy_from_slice(ram);
+        self.back_pixels = pixels;
+        self.hi_res = hi_res;
+        self.active_planes = active_planes;
+        self.halted = halted;
+        self.v = v;
+        self.i = i;
+        self.pc = pc;
+        self.stack = stack;
+        self.sp = stack_depth as u8;
+        self.delay_timer.start(delay);
+        self.sound_timer.start(sound);
+        self.pitch = pitch;
+        self.audio_pattern = audio_pattern;
+        self.watch_hits.clear();
+
+        self.sync_rows();
+        self.redraw();
+        Ok(())
+    }
+}
+
+// Reads a save state from the front, failing if it runs out
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Chip8Error> {
+        if self.data.len() < len {
+            return Err(Chip8Error::InvalidSaveState);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Chip8Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    fn byte(&mut self) -> Result<u8, Chip8Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn flag(&mut self) -> Result<bool, Chip8Error> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Chip8Error::InvalidSaveState),
+        }
+    }
+}
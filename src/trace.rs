@@ -0,0 +1,65 @@
+// Writes a line to a file for every instruction executed (with --trace), showing the registers
+// as they were just before it ran, e.g.
+//
+//     0x0200  LD V0, 0x05       V0=00 V1=00 ... VF=00  I=0000  DT=00
+
+use crate::disasm::disassemble_instruction;
+use crate::{Chip8, Chip8Error};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+pub struct Tracer {
+    path: String,
+    out: BufWriter<File>,
+
+    // how many more lines we can write, if there's a limit
+    lines_left: Option<u64>,
+}
+
+impl Tracer {
+    pub fn create(path: &str, limit: Option<u64>) -> Result<Tracer, Chip8Error> {
+        let file = File::create(path).map_err(|err| trace_failed(path, err))?;
+        Ok(Tracer {
+            path: path.to_string(),
+            out: BufWriter::new(file),
+            lines_left: limit,
+        })
+    }
+
+    // Called just before the instruction at the current PC is executed
+    pub fn record(&mut self, chip8: &Chip8) -> Result<(), Chip8Error> {
+        self.write_line(chip8).map_err(|err| trace_failed(&self.path, err))
+    }
+
+    fn write_line(&mut self, chip8: &Chip8) -> io::Result<()> {
+        // there's no instruction to trace, and emulate_cycle will say so, or it isn't executed
+        // yet because it's waiting for the frame
+        let instr = match chip8.fetch() {
+            Some(instr) if !chip8.is_waiting_for_frame() => instr,
+            _ => return Ok(()),
+        };
+        match &mut self.lines_left {
+            Some(0) => return Ok(()),
+            Some(lines_left) => *lines_left -= 1,
+            None => {},
+        }
+
+        let registers: Vec<String> = chip8.v.iter()
+            .enumerate()
+            .map(|(n, val)| format!("V{:X}={:02X}", n, val))
+            .collect();
+        writeln!(self.out, "0x{:04X}  {:<16}  {}  I={:04X}  DT={:02X}",
+                 chip8.pc, disassemble_instruction(instr), registers.join(" "),
+                 chip8.i, chip8.delay_timer.get_value())?;
+
+        // so the end of the trace isn't left sitting in the buffer
+        if self.lines_left == Some(0) {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn trace_failed(path: &str, err: io::Error) -> Chip8Error {
+    Chip8Error::TraceFailed { path: path.to_string(), reason: err.to_string() }
+}
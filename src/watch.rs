@@ -0,0 +1,71 @@
+// Lets --watch reload the ROM whenever it changes on disk. With the "watch" feature enabled we
+// get told about changes by notify; without it, RomWatcher can never be created.
+
+#[cfg(feature = "watch")]
+mod imp {
+    use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+    use std::ffi::OsString;
+    use std::path::Path;
+    use std::sync::mpsc::{self, Receiver};
+
+    pub struct RomWatcher {
+        file_name: OsString,
+        events: Receiver<notify::Result<notify::Event>>,
+
+        // we stop hearing about changes once the watcher is dropped
+        _watcher: RecommendedWatcher,
+    }
+
+    impl RomWatcher {
+        pub fn new(path: &str) -> Option<RomWatcher> {
+            let path = Path::new(path);
+            let file_name = path.file_name()?.to_os_string();
+
+            // Editors and assemblers often replace the file rather than writing to it, so watch
+            // the directory it's in instead of the file itself
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+
+            let (sender, events) = mpsc::channel();
+            let mut watcher = notify::recommended_watcher(sender).ok()?;
+            watcher.watch(dir, RecursiveMode::NonRecursive).ok()?;
+
+            Some(RomWatcher {
+                file_name,
+                events,
+                _watcher: watcher,
+            })
+        }
+
+        // Whether the ROM has been written to since we last checked
+        pub fn changed(&self) -> bool {
+            let mut changed = false;
+            for event in self.events.try_iter().flatten() {
+                let writes = event.kind.is_create() || event.kind.is_modify();
+                let ours = event.paths.iter()
+                    .any(|path| path.file_name() == Some(self.file_name.as_os_str()));
+                changed |= writes && ours;
+            }
+            changed
+        }
+    }
+}
+
+#[cfg(not(feature = "watch"))]
+mod imp {
+    pub struct RomWatcher;
+
+    impl RomWatcher {
+        pub fn new(_path: &str) -> Option<RomWatcher> {
+            None
+        }
+
+        pub fn changed(&self) -> bool {
+            false
+        }
+    }
+}
+
+pub use imp::RomWatcher;
@@ -0,0 +1,118 @@
+// Run each ROM in tests/roms headlessly, with the same random numbers and key presses every time,
+// and compare the final display with its snapshot in tests/roms/<name>.snap. When a change to
+// what's drawn is intended, `cargo insta review` (or INSTA_UPDATE=always) updates the snapshots.
+
+use chip8::{Chip8, Chip8Builder, Machine, DEFAULT_SPEED};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CYCLES: usize = 1000;
+
+// the public test ROMs draw a lot, and some of them wait for the display after each sprite
+const SUITE_CYCLES: usize = 50_000;
+
+// The public test ROMs, as named in the bin folder of Timendus' chip8-test-suite (plus
+// BC_test.ch8), the machine each one's run on, and what to put at 0x1ff, which the later Timendus
+// tests read to pick a platform without waiting for a key
+const SUITE: [(&str, Machine, u8); 6] = [
+    ("1-chip8-logo", Machine::Chip8, 0),
+    ("2-ibm-logo", Machine::Chip8, 0),
+    ("3-corax+", Machine::Chip8, 0),
+    ("4-flags", Machine::Chip8, 0),
+    ("5-quirks", Machine::Chip8, 1),
+    ("BC_test", Machine::Chip8, 0),
+];
+
+// as run_headless does, a frame (and a tick of the timers) at the default speed
+const CYCLES_PER_FRAME: usize = DEFAULT_SPEED as usize / 60;
+
+// (cycle, key, down): press or release key just before that cycle
+type KeyScript = [(usize, u8, bool)];
+
+fn check_rom(name: &str, keys: &KeyScript) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms");
+    let rom = fs::read(dir.join(format!("{}.ch8", name))).unwrap();
+    let chip8 = Chip8Builder::new().rom(rom).random_seed(0).build().unwrap();
+    check_display(run(chip8, CYCLES, keys), &dir, name);
+}
+
+// Run chip8 for up to cycles instructions, pressing keys as it goes
+fn run(mut chip8: Chip8, cycles: usize, keys: &KeyScript) -> Chip8 {
+    for cycle in 0..cycles {
+        for (_, key, down) in keys.iter().filter(|(at, _, _)| *at == cycle) {
+            chip8.set_key(*key, *down).unwrap();
+        }
+        chip8.emulate_cycle().unwrap();
+        if chip8.is_halted() {
+            break;
+        }
+        if (cycle + 1) % CYCLES_PER_FRAME == 0 {
+            chip8.tick_timers(1);
+            chip8.end_frame();
+        }
+    }
+    chip8
+}
+
+// Compare what chip8 is showing with the snapshot called name in dir
+fn check_display(chip8: Chip8, dir: &Path, name: &str) {
+    // insta trims the blank rows off the end, so the size says how many there were
+    let (width, height) = chip8.display_size();
+    let snapshot = format!("{}x{}\n{}", width, height, chip8.get_display_string());
+    insta::with_settings!({snapshot_path => dir, prepend_module_to_snapshot => false}, {
+        insta::assert_snapshot!(name, snapshot);
+    });
+}
+
+#[test]
+fn font() {
+    check_rom("font", &[]);
+}
+
+#[test]
+fn arith() {
+    check_rom("arith", &[]);
+}
+
+#[test]
+fn calls() {
+    check_rom("calls", &[]);
+}
+
+// waits for a key, draws a random digit, and then waits for key 5 to be held and let go
+#[test]
+fn keys() {
+    check_rom("keys", &[(50, 0xa, true), (60, 0xa, false), (200, 5, true), (400, 5, false)]);
+}
+
+// The public test ROMs can't be included here, since they aren't ours to give away, so they're
+// only run when CHIP8_TEST_SUITE names a directory with them in (a copy of chip8-test-suite's bin
+// folder with BC_test.ch8 added, say). Their displays are compared with the snapshots in
+// tests/roms/suite. New snapshots are recorded with `cargo insta review`, after checking that
+// every test the ROM shows has passed.
+#[test]
+fn public_test_suites() {
+    let dir = match env::var_os("CHIP8_TEST_SUITE") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            eprintln!("CHIP8_TEST_SUITE isn't set, so the public test ROMs weren't run");
+            return;
+        },
+    };
+    let snapshots = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms/suite");
+    for (name, machine, platform) in SUITE {
+        let path = dir.join(format!("{}.ch8", name));
+        let rom = fs::read(&path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        let mut chip8 = Chip8Builder::new()
+            .rom(rom)
+            .machine(machine)
+            .random_seed(0)
+            .build()
+            .unwrap();
+        if platform != 0 {
+            chip8.poke(0x1ff, platform).unwrap();
+        }
+        check_display(run(chip8, SUITE_CYCLES, &[]), &snapshots, name);
+    }
+}